@@ -106,6 +106,29 @@ pub fn hash_kit_password(password: &str) -> String {
     kit_hash_format(PBKDF2_ITERATIONS, &salt, &hash)
 }
 
+/// Check a password against a hash generated by `hash_kit_password`.
+pub fn check_kit_password(password: &str, hash: &str) -> bool {
+    let parts: Vec<_> = hash.split('$').collect();
+    if parts.len() != 5 || parts[0] != "PBKDF2" || parts[1] != "sha256" {
+        return false;
+    }
+
+    let iterations: u32 = match parts[2].parse() {
+        Ok(iterations) if iterations > 0 => iterations,
+        _ => return false,
+    };
+    let expected_hash = match base64::decode(parts[4]) {
+        Ok(expected_hash) => expected_hash,
+        Err(_) => return false,
+    };
+
+    // Compare in constant time, not to leak how much of the hash matched.
+    crypto::util::fixed_time_eq(
+        &pbkdf2(password, parts[3].as_bytes(), iterations),
+        &expected_hash,
+    )
+}
+
 /// Perform pbkdf2.
 fn pbkdf2(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
     use crypto::{hmac::Hmac, sha2::Sha256};
@@ -167,6 +190,33 @@ mod test {
         )
     }
 
+    #[test]
+    pub fn check_kit_hash() {
+        let hash =
+            "PBKDF2$sha256$2000$Z416JHE8vSmaiamV5TRz$z3y6FvWAZtyQe6TV+O/oyhC3oqnF8KJdlB5Lphi+Lwg=";
+
+        assert!(super::check_kit_password(
+            "It all adds up to normality.",
+            hash
+        ));
+        assert!(!super::check_kit_password("It all adds up.", hash));
+        assert!(!super::check_kit_password(
+            "It all adds up to normality.",
+            "PBKDF2$sha256$0$Z416JHE8vSmaiamV5TRz$z3y6FvWAZtyQe6TV+O/oyhC3oqnF8KJdlB5Lphi+Lwg="
+        ));
+        assert!(!super::check_kit_password(
+            "It all adds up to normality.",
+            ""
+        ));
+    }
+
+    #[test]
+    pub fn kit_hash_round_trip() {
+        let password = "It all adds up to normality.";
+        let hash = super::hash_kit_password(password);
+        assert!(super::check_kit_password(password, &hash))
+    }
+
     #[test]
     pub fn check_v1_hash() {
         let v1_hash: super::V1Hash =
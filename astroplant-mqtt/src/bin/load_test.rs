@@ -0,0 +1,345 @@
+//! Load tests the ingestion of measurements. Spawns simulated kits that publish raw measurements at
+//! a configurable rate against an MQTT broker, and reports:
+//!
+//! - the throughput of the `MqttApiMessage` channel of an in-process MQTT API connector, the
+//!   largest backlog in the channel, the messages it dropped, and the latency from measurement to
+//!   channel;
+//! - optionally, the end-to-end latency from measurement to the `rawMeasurements` WebSocket
+//!   subscription of a running API.
+//!
+//! The simulated kits must exist with active configurations, otherwise the API quarantines their
+//! measurements and they never reach the WebSocket subscription.
+//!
+//! Configured through environment variables:
+//!
+//! - `MQTT_HOST` and `MQTT_PORT`: the broker to connect to;
+//! - `MQTT_USERNAME` and `MQTT_PASSWORD`: the credentials of the in-process MQTT API connector;
+//! - `LOAD_TEST_KITS`: the number of kits to simulate;
+//! - `LOAD_TEST_KIT_SERIAL_PREFIX`: the simulated kits' serials are this prefix, followed by
+//!   `1` up to and including the number of kits;
+//! - `LOAD_TEST_KIT_PASSWORD`: the password of all simulated kits;
+//! - `MEASUREMENT_INTERVAL_MILLIS`: the number of milliseconds between raw measurements of a kit;
+//! - `AGGREGATE_INTERVAL`: the number of seconds between aggregate measurements of a kit;
+//! - `LOAD_TEST_DURATION`: the number of seconds to run the load test for;
+//! - `WEBSOCKET_URL`: the WebSocket endpoint of the API, e.g. `ws://localhost:8080/`. If not set,
+//!   end-to-end latency is not measured.
+
+mod simulator;
+
+use astroplant_mqtt::{ConnectionOptions, DroppedMessages, MqttApiMessage, OverflowPolicies};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tungstenite::Message;
+
+static DEFAULT_MQTT_HOST: &str = "mqtt.ops";
+const DEFAULT_MQTT_PORT: u16 = 1883;
+static DEFAULT_MQTT_USERNAME: &str = "server";
+static DEFAULT_MQTT_PASSWORD: &str = "";
+static MQTT_CLIENT_ID: &str = "astroplant-load-test";
+const MQTT_KEEP_ALIVE: Duration = Duration::from_secs(30);
+const DEFAULT_KITS: usize = 10;
+static DEFAULT_KIT_SERIAL_PREFIX: &str = "k_load_";
+static DEFAULT_KIT_PASSWORD: &str = "abcdef";
+const DEFAULT_MEASUREMENT_INTERVAL_MILLIS: u64 = 1000;
+const DEFAULT_AGGREGATE_INTERVAL: u64 = 300;
+const DEFAULT_DURATION: u64 = 60;
+
+/// The interval at which statistics are reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is invalid.", name)),
+        Err(_) => default,
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Latencies in milliseconds.
+#[derive(Default)]
+struct Latencies(Vec<u64>);
+
+impl Latencies {
+    fn record(&mut self, measured_at: u64, received_at: u64) {
+        self.0.push(received_at.saturating_sub(measured_at));
+    }
+
+    fn extend(&mut self, other: &Latencies) {
+        self.0.extend_from_slice(&other.0);
+    }
+
+    fn summary(&mut self) -> String {
+        if self.0.is_empty() {
+            return "-".to_owned();
+        }
+
+        self.0.sort_unstable();
+        let percentile = |p: usize| self.0[(self.0.len() - 1) * p / 100];
+        format!(
+            "p50 {}ms, p95 {}ms, p99 {}ms, max {}ms",
+            percentile(50),
+            percentile(95),
+            percentile(99),
+            self.0[self.0.len() - 1]
+        )
+    }
+}
+
+/// The statistics collected during a report interval.
+#[derive(Default)]
+struct Window {
+    channel_messages: usize,
+    channel_raw_measurements: usize,
+    /// The largest number of messages waiting in the channel.
+    channel_backlog: usize,
+    channel_latencies: Latencies,
+    websocket_latencies: Latencies,
+}
+
+impl Window {
+    fn merge(&mut self, other: &Window) {
+        self.channel_messages += other.channel_messages;
+        self.channel_raw_measurements += other.channel_raw_measurements;
+        self.channel_backlog = self.channel_backlog.max(other.channel_backlog);
+        self.channel_latencies.extend(&other.channel_latencies);
+        self.websocket_latencies.extend(&other.websocket_latencies);
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    window: Mutex<Window>,
+    /// Set when the `MqttApiMessage` channel disconnected.
+    channel_disconnected: Mutex<bool>,
+    channel_dropped: Mutex<DroppedMessages>,
+}
+
+/// Consume the `MqttApiMessage` channel of an in-process MQTT API connector. Server RPC requests
+/// are dropped; they are answered by the API.
+fn consume_channel(
+    mqtt_host: String,
+    mqtt_port: u16,
+    kit_serial_prefix: String,
+    started_at: u64,
+    stats: Arc<Stats>,
+) {
+    let connection_options = ConnectionOptions {
+        host: mqtt_host,
+        port: mqtt_port,
+        client_id: MQTT_CLIENT_ID.to_owned(),
+        username: std::env::var("MQTT_USERNAME").unwrap_or(DEFAULT_MQTT_USERNAME.to_owned()),
+        password: std::env::var("MQTT_PASSWORD").unwrap_or(DEFAULT_MQTT_PASSWORD.to_owned()),
+        keep_alive: MQTT_KEEP_ALIVE,
+        tls: None,
+    };
+    let (receiver, _kits_rpc, _kits_notifier) =
+        astroplant_mqtt::run(connection_options, None, OverflowPolicies::default());
+
+    loop {
+        let backlog = receiver.len();
+        let message = match receiver.recv() {
+            Ok(message) => message,
+            Err(_) => break,
+        };
+        let received_at = now_millis();
+
+        *stats.channel_dropped.lock().unwrap() = receiver.dropped();
+        let mut window = stats.window.lock().unwrap();
+        window.channel_messages += 1;
+        window.channel_backlog = window.channel_backlog.max(backlog);
+        if let MqttApiMessage::RawMeasurement(measurement) = message {
+            if measurement.kit_serial.starts_with(&kit_serial_prefix)
+                && measurement.datetime >= started_at
+            {
+                window.channel_raw_measurements += 1;
+                window
+                    .channel_latencies
+                    .record(measurement.datetime, received_at);
+            }
+        }
+    }
+
+    *stats.channel_disconnected.lock().unwrap() = true;
+    eprintln!("the MqttApiMessage channel disconnected");
+}
+
+/// Subscribe to the raw measurements of the simulated kits over the API's WebSocket.
+fn consume_websocket(
+    websocket_url: String,
+    kit_serials: Vec<String>,
+    started_at: u64,
+    stats: Arc<Stats>,
+) {
+    let (mut socket, _) = match tungstenite::connect(websocket_url.as_str()) {
+        Ok(connection) => connection,
+        Err(err) => {
+            eprintln!("could not connect to {}: {:?}", websocket_url, err);
+            return;
+        }
+    };
+
+    for (id, kit_serial) in kit_serials.iter().enumerate() {
+        let subscribe = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "subscribe_rawMeasurements",
+            "params": { "kitSerial": kit_serial },
+            "id": id,
+        });
+        if let Err(err) = socket.write_message(Message::Text(subscribe.to_string())) {
+            eprintln!("could not subscribe to {}: {:?}", kit_serial, err);
+            return;
+        }
+    }
+
+    loop {
+        let text = match socket.read_message() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(_) => continue,
+        };
+        let received_at = now_millis();
+
+        let message: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message["method"] != "rawMeasurements" {
+            continue;
+        }
+
+        // Measurements buffered from before the load test started are sent on subscribing.
+        if let Some(datetime) = message["params"]["result"]["datetime"].as_u64() {
+            if datetime >= started_at {
+                stats
+                    .window
+                    .lock()
+                    .unwrap()
+                    .websocket_latencies
+                    .record(datetime, received_at);
+            }
+        }
+    }
+
+    eprintln!("the WebSocket connection closed");
+}
+
+fn main() {
+    let mqtt_host = std::env::var("MQTT_HOST").unwrap_or(DEFAULT_MQTT_HOST.to_owned());
+    let mqtt_port = env_or("MQTT_PORT", DEFAULT_MQTT_PORT);
+    let kits = env_or("LOAD_TEST_KITS", DEFAULT_KITS);
+    let kit_serial_prefix = std::env::var("LOAD_TEST_KIT_SERIAL_PREFIX")
+        .unwrap_or(DEFAULT_KIT_SERIAL_PREFIX.to_owned());
+    let kit_password =
+        std::env::var("LOAD_TEST_KIT_PASSWORD").unwrap_or(DEFAULT_KIT_PASSWORD.to_owned());
+    let measurement_interval = Duration::from_millis(env_or(
+        "MEASUREMENT_INTERVAL_MILLIS",
+        DEFAULT_MEASUREMENT_INTERVAL_MILLIS,
+    ));
+    let aggregate_interval =
+        Duration::from_secs(env_or("AGGREGATE_INTERVAL", DEFAULT_AGGREGATE_INTERVAL));
+    let duration = Duration::from_secs(env_or("LOAD_TEST_DURATION", DEFAULT_DURATION));
+
+    let started_at = now_millis();
+    let stats = Arc::new(Stats::default());
+    let kit_serials: Vec<String> = (1..=kits)
+        .map(|n| format!("{}{}", kit_serial_prefix, n))
+        .collect();
+
+    {
+        let mqtt_host = mqtt_host.clone();
+        let kit_serial_prefix = kit_serial_prefix.clone();
+        let stats = stats.clone();
+        std::thread::spawn(move || {
+            consume_channel(mqtt_host, mqtt_port, kit_serial_prefix, started_at, stats)
+        });
+    }
+
+    if let Ok(websocket_url) = std::env::var("WEBSOCKET_URL") {
+        let kit_serials = kit_serials.clone();
+        let stats = stats.clone();
+        std::thread::spawn(move || {
+            consume_websocket(websocket_url, kit_serials, started_at, stats)
+        });
+    }
+
+    let published_raw_measurements = Arc::new(AtomicUsize::new(0));
+    for kit_serial in kit_serials {
+        let options = simulator::Options {
+            mqtt_host: mqtt_host.clone(),
+            mqtt_port,
+            kit_serial,
+            password: kit_password.clone(),
+            measurement_interval,
+            aggregate_interval,
+            published_raw_measurements: published_raw_measurements.clone(),
+        };
+        std::thread::spawn(move || simulator::Kit::run(options));
+    }
+
+    let mut total = Window::default();
+    let mut published = 0;
+    let mut elapsed = Duration::from_secs(0);
+    while elapsed < duration {
+        std::thread::sleep(REPORT_INTERVAL);
+        elapsed += REPORT_INTERVAL;
+
+        let mut window = std::mem::take(&mut *stats.window.lock().unwrap());
+        let published_total = published_raw_measurements.load(Ordering::Relaxed);
+        println!(
+            "{}s: published {} raw measurements; channel: {} messages ({} raw measurements), \
+             backlog {}, latency {}; websocket: {} raw measurements, latency {}",
+            elapsed.as_secs(),
+            published_total - published,
+            window.channel_messages,
+            window.channel_raw_measurements,
+            window.channel_backlog,
+            window.channel_latencies.summary(),
+            window.websocket_latencies.0.len(),
+            window.websocket_latencies.summary(),
+        );
+        published = published_total;
+        total.merge(&window);
+    }
+
+    let seconds = duration.as_secs_f64();
+    println!("summary over {}s with {} kits:", duration.as_secs(), kits);
+    println!(
+        "  published: {} raw measurements ({:.1}/s)",
+        published,
+        published as f64 / seconds
+    );
+    println!(
+        "  channel: {} messages ({:.1}/s), {} raw measurements, largest backlog {}{}",
+        total.channel_messages,
+        total.channel_messages as f64 / seconds,
+        total.channel_raw_measurements,
+        total.channel_backlog,
+        if *stats.channel_disconnected.lock().unwrap() {
+            ", disconnected"
+        } else {
+            ""
+        },
+    );
+    println!("  channel latency: {}", total.channel_latencies.summary());
+    println!(
+        "  channel dropped: {:?}",
+        *stats.channel_dropped.lock().unwrap()
+    );
+    println!(
+        "  websocket: {} raw measurements, latency {}",
+        total.websocket_latencies.0.len(),
+        total.websocket_latencies.summary()
+    );
+
+    // The simulated kits run until the process exits.
+    std::process::exit(0);
+}
@@ -1,39 +1,67 @@
-use astroplant_mqtt::{KitRpc, ServerRpcRequest};
+//! Simulates one or more kits, for developing against a local MQTT broker without real hardware.
+//!
+//! Configured through environment variables:
+//!
+//! - `MQTT_HOST` and `MQTT_PORT`: the broker to connect to;
+//! - `SIMULATED_KITS`: comma-separated `serial:password` pairs of the kits to simulate;
+//! - `MEASUREMENT_INTERVAL`: the number of seconds between raw measurements;
+//! - `AGGREGATE_INTERVAL`: the number of seconds between aggregate measurements.
 
-fn main() {
-    let (receiver, kits_rpc) = astroplant_mqtt::run(
-        "mqtt.ops".to_owned(),
-        1883,
-        "server".to_owned(),
-        "abcdef".to_owned(),
-    );
-
-    std::thread::spawn(move || {
-        println!("Querying kit with serial 'k_develop'");
-        let kit_rpc: KitRpc = kits_rpc.kit_rpc("k_develop".to_owned());
-        println!(
-            "Version response: {:?}",
-            futures::executor::block_on(kit_rpc.version())
-        );
-        println!(
-            "Uptime response: {:?}",
-            futures::executor::block_on(kit_rpc.uptime())
-        );
-    });
-
-    while let Ok(res) = receiver.recv() {
-        println!("Received request: {:?}", res);
-        if let astroplant_mqtt::MqttApiMessage::ServerRpcRequest(rpc_request) = res {
-            match rpc_request {
-                ServerRpcRequest::Version { response } => {
-                    response
-                        .send("astroplant-mqtt-bin-tester".to_owned())
-                        .unwrap();
-                }
-                _ => {}
-            }
-        }
+mod simulator;
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+static DEFAULT_MQTT_HOST: &str = "mqtt.ops";
+const DEFAULT_MQTT_PORT: u16 = 1883;
+static DEFAULT_SIMULATED_KITS: &str = "k_develop:abcdef";
+const DEFAULT_MEASUREMENT_INTERVAL: u64 = 10;
+const DEFAULT_AGGREGATE_INTERVAL: u64 = 300;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is invalid.", name)),
+        Err(_) => default,
     }
+}
 
-    println!("Disconnected")
+fn main() {
+    let mqtt_host = std::env::var("MQTT_HOST").unwrap_or(DEFAULT_MQTT_HOST.to_owned());
+    let mqtt_port = env_or("MQTT_PORT", DEFAULT_MQTT_PORT);
+    let measurement_interval =
+        Duration::from_secs(env_or("MEASUREMENT_INTERVAL", DEFAULT_MEASUREMENT_INTERVAL));
+    let aggregate_interval =
+        Duration::from_secs(env_or("AGGREGATE_INTERVAL", DEFAULT_AGGREGATE_INTERVAL));
+    let kits = std::env::var("SIMULATED_KITS").unwrap_or(DEFAULT_SIMULATED_KITS.to_owned());
+    let published_raw_measurements = Arc::new(AtomicUsize::new(0));
+
+    let handles: Vec<_> = kits
+        .split(',')
+        .map(|kit| {
+            let mut credentials = kit.splitn(2, ':');
+            let kit_serial = credentials.next().unwrap_or_default().trim().to_owned();
+            let password = credentials
+                .next()
+                .expect("SIMULATED_KITS must be formatted as serial:password,serial:password")
+                .to_owned();
+
+            let options = simulator::Options {
+                mqtt_host: mqtt_host.clone(),
+                mqtt_port,
+                kit_serial,
+                password,
+                measurement_interval,
+                aggregate_interval,
+                published_raw_measurements: published_raw_measurements.clone(),
+            };
+            std::thread::spawn(move || simulator::Kit::run(options))
+        })
+        .collect();
+
+    for handle in handles {
+        let _ = handle.join();
+    }
 }
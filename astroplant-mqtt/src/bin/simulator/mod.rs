@@ -0,0 +1,586 @@
+//! A simulated kit. It fetches its active configuration from the server, publishes measurements
+//! for the quantity types its peripherals are expected to produce, and answers kit RPC requests.
+
+use astroplant_mqtt::astroplant_capnp;
+use capnp::serialize_packed;
+use crossbeam_channel::select;
+use rumqtt::{
+    LastWill, MqttClient, MqttOptions, Notification, QoS, ReconnectOptions, SecurityOptions,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The time after which server RPC requests that went unanswered are sent again.
+const SERVER_RPC_RETRY: Duration = Duration::from_secs(30);
+
+/// The aggregates published for every peripheral and quantity type.
+const AGGREGATE_TYPES: [&str; 3] = ["average", "minimum", "maximum"];
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    pub kit_serial: String,
+    pub password: String,
+    pub measurement_interval: Duration,
+    pub aggregate_interval: Duration,
+    /// Counts the raw measurements published. Can be shared between kits.
+    pub published_raw_measurements: Arc<AtomicUsize>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QuantityType {
+    id: i32,
+    physical_quantity: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    id: i32,
+    peripherals: Vec<PeripheralWithDefinition>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PeripheralWithDefinition {
+    peripheral: Peripheral,
+    definition: PeripheralDefinition,
+}
+
+#[derive(Deserialize, Debug)]
+struct Peripheral {
+    id: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PeripheralDefinition {
+    #[serde(default)]
+    expected_quantity_types: Vec<i32>,
+}
+
+/// The measured values of a peripheral's quantity type during the current aggregation window.
+struct Aggregate {
+    count: u32,
+    sum: f64,
+    minimum: f64,
+    maximum: f64,
+}
+
+impl Aggregate {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            minimum: std::f64::INFINITY,
+            maximum: std::f64::NEG_INFINITY,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.minimum = self.minimum.min(value);
+        self.maximum = self.maximum.max(value);
+    }
+
+    fn value(&self, aggregate_type: &str) -> f64 {
+        match aggregate_type {
+            "minimum" => self.minimum,
+            "maximum" => self.maximum,
+            _ => self.sum / f64::from(self.count),
+        }
+    }
+}
+
+/// A xorshift pseudo-random number generator, to add noise to measurements.
+struct Noise(u64);
+
+impl Noise {
+    fn new(seed: &str) -> Self {
+        // FNV-1a, such that kits with different serials produce different noise.
+        let mut state = 0xcbf2_9ce4_8422_2325u64;
+        for byte in seed.bytes() {
+            state ^= u64::from(byte);
+            state = state.wrapping_mul(0x0100_0000_01b3);
+        }
+        Self(state | 1)
+    }
+
+    /// Get a number in [-1, 1).
+    fn sample(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+/// Get a plausible base value and amplitude for a physical quantity.
+fn plausible_range(physical_quantity: Option<&str>) -> (f64, f64) {
+    let physical_quantity = physical_quantity.unwrap_or("").to_lowercase();
+    if physical_quantity.contains("temperature") {
+        (21.0, 3.0)
+    } else if physical_quantity.contains("humidity") {
+        (60.0, 10.0)
+    } else if physical_quantity.contains("concentration") || physical_quantity.contains("co2") {
+        (450.0, 50.0)
+    } else if physical_quantity.contains("light") || physical_quantity.contains("intensity") {
+        (5000.0, 4000.0)
+    } else if physical_quantity.contains("pressure") {
+        (1013.0, 10.0)
+    } else {
+        (50.0, 10.0)
+    }
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub struct Kit {
+    options: Options,
+    mqtt_client: MqttClient,
+    started: Instant,
+    noise: Noise,
+    next_rpc_id: u64,
+    quantity_types: Option<HashMap<i32, QuantityType>>,
+    quantity_types_requested: Option<Instant>,
+    /// The active configuration. `None` if it is not yet known, `Some(None)` if the kit has no
+    /// active configuration.
+    configuration: Option<Option<Configuration>>,
+    configuration_hash: Option<String>,
+    configuration_requested: Option<Instant>,
+    aggregates: HashMap<(i32, i32), Aggregate>,
+    aggregate_window_start: SystemTime,
+}
+
+impl Kit {
+    /// Connect to the MQTT broker as the kit, and run the kit until the connection closes.
+    pub fn run(options: Options) {
+        let mqtt_options = MqttOptions::new(
+            format!("astroplant-simulated-{}", options.kit_serial),
+            options.mqtt_host.clone(),
+            options.mqtt_port,
+        )
+        .set_reconnect_opts(ReconnectOptions::Always(10))
+        .set_security_opts(SecurityOptions::UsernamePassword(
+            options.kit_serial.clone(),
+            options.password.clone(),
+        ))
+        .set_last_will(LastWill {
+            topic: format!("kit/{}/status", options.kit_serial),
+            message: "offline".to_owned(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        });
+        let (mqtt_client, notifications) = match MqttClient::start(mqtt_options) {
+            Ok(connection) => connection,
+            Err(err) => {
+                eprintln!("{}: could not connect: {:?}", options.kit_serial, err);
+                return;
+            }
+        };
+
+        let mut kit = Kit {
+            noise: Noise::new(&options.kit_serial),
+            options,
+            mqtt_client,
+            started: Instant::now(),
+            next_rpc_id: 0,
+            quantity_types: None,
+            quantity_types_requested: None,
+            configuration: None,
+            configuration_hash: None,
+            configuration_requested: None,
+            aggregates: HashMap::new(),
+            aggregate_window_start: SystemTime::now(),
+        };
+        kit.connected();
+
+        let ticker = crossbeam_channel::tick(kit.options.measurement_interval);
+        loop {
+            select! {
+                recv(notifications) -> notification => match notification {
+                    Ok(Notification::Publish(publish)) => {
+                        kit.handle_publish(&publish.topic_name, &publish.payload)
+                    }
+                    Ok(Notification::Reconnection) => kit.connected(),
+                    Ok(_) => {}
+                    Err(_) => break,
+                },
+                recv(ticker) -> _ => kit.tick(),
+            }
+        }
+
+        println!("{}: disconnected", kit.options.kit_serial);
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("kit/{}/{}", self.options.kit_serial, suffix)
+    }
+
+    fn publish(&mut self, topic: String, retain: bool, payload: Vec<u8>) {
+        if let Err(err) = self
+            .mqtt_client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+        {
+            eprintln!("{}: could not publish: {:?}", self.options.kit_serial, err);
+        }
+    }
+
+    fn connected(&mut self) {
+        println!("{}: connected", self.options.kit_serial);
+
+        for suffix in &[
+            "server-rpc/response",
+            "kit-rpc/request",
+            "configuration/changed",
+        ] {
+            let topic = self.topic(suffix);
+            if let Err(err) = self.mqtt_client.subscribe(topic, QoS::AtLeastOnce) {
+                eprintln!(
+                    "{}: could not subscribe: {:?}",
+                    self.options.kit_serial, err
+                );
+            }
+        }
+
+        let topic = self.topic("status");
+        self.publish(topic, true, b"online".to_vec());
+
+        if self.quantity_types.is_none() {
+            self.request_quantity_types();
+        }
+        self.request_configuration();
+    }
+
+    fn tick(&mut self) {
+        let retry_due = |requested: Option<Instant>| {
+            requested.map_or(true, |requested| requested.elapsed() >= SERVER_RPC_RETRY)
+        };
+        if self.quantity_types.is_none() && retry_due(self.quantity_types_requested) {
+            self.request_quantity_types();
+        }
+        if self.configuration.is_none() && retry_due(self.configuration_requested) {
+            self.request_configuration();
+        }
+
+        self.measure();
+    }
+
+    fn server_rpc_request<F>(&mut self, build: F)
+    where
+        F: FnOnce(astroplant_capnp::server_rpc_request::Builder),
+    {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut request_builder =
+            message_builder.init_root::<astroplant_capnp::server_rpc_request::Builder>();
+        request_builder.set_id(self.next_rpc_id);
+        self.next_rpc_id += 1;
+        build(request_builder);
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        let topic = self.topic("server-rpc/request");
+        self.publish(topic, false, bytes);
+    }
+
+    fn request_quantity_types(&mut self) {
+        self.quantity_types_requested = Some(Instant::now());
+        self.server_rpc_request(|mut request_builder| request_builder.set_get_quantity_types(()));
+    }
+
+    fn request_configuration(&mut self) {
+        self.configuration_requested = Some(Instant::now());
+        let known_hash = match &self.configuration {
+            Some(_) => self.configuration_hash.clone(),
+            None => None,
+        };
+        self.server_rpc_request(|request_builder| {
+            let mut get_active_configuration = request_builder.init_get_active_configuration_v2();
+            if let Some(known_hash) = known_hash {
+                get_active_configuration.set_known_hash(&known_hash);
+            }
+        });
+    }
+
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) {
+        let result = match topic.splitn(3, '/').nth(2) {
+            Some("server-rpc/response") => self.handle_server_rpc_response(payload),
+            Some("kit-rpc/request") => self.handle_kit_rpc_request(payload),
+            Some("configuration/changed") => self.handle_configuration_changed(payload),
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            eprintln!(
+                "{}: could not handle message on {}: {:?}",
+                self.options.kit_serial, topic, err
+            );
+        }
+    }
+
+    fn handle_server_rpc_response(&mut self, mut payload: &[u8]) -> capnp::Result<()> {
+        use astroplant_capnp::server_rpc_response::Which;
+
+        let message_reader =
+            serialize_packed::read_message(&mut payload, capnp::message::ReaderOptions::default())?;
+        let response =
+            message_reader.get_root::<astroplant_capnp::server_rpc_response::Reader>()?;
+
+        match response.which()? {
+            Which::Error(error) => {
+                use astroplant_capnp::rpc_error::Which;
+
+                match error?.which()? {
+                    Which::RateLimit(millis) => println!(
+                        "{}: server RPC rate limited for {} ms",
+                        self.options.kit_serial, millis
+                    ),
+                    _ => println!("{}: server RPC error", self.options.kit_serial),
+                }
+            }
+            Which::GetQuantityTypes(quantity_types) => {
+                match serde_json::from_str::<Vec<QuantityType>>(quantity_types?) {
+                    Ok(quantity_types) => {
+                        self.quantity_types = Some(
+                            quantity_types
+                                .into_iter()
+                                .map(|quantity_type| (quantity_type.id, quantity_type))
+                                .collect(),
+                        );
+                    }
+                    Err(err) => eprintln!(
+                        "{}: invalid quantity types: {:?}",
+                        self.options.kit_serial, err
+                    ),
+                }
+            }
+            Which::GetActiveConfiguration(active_configuration) => {
+                use astroplant_capnp::active_configuration::Which;
+
+                let active_configuration = active_configuration?;
+                match active_configuration.which()? {
+                    Which::Configuration(configuration) => {
+                        match serde_json::from_str::<Configuration>(configuration?) {
+                            Ok(configuration) => {
+                                println!(
+                                    "{}: running configuration {} with {} peripheral(s)",
+                                    self.options.kit_serial,
+                                    configuration.id,
+                                    configuration.peripherals.len()
+                                );
+                                self.set_configuration(
+                                    Some(configuration),
+                                    Some(active_configuration.get_hash()?.to_owned()),
+                                );
+                            }
+                            Err(err) => eprintln!(
+                                "{}: invalid configuration: {:?}",
+                                self.options.kit_serial, err
+                            ),
+                        }
+                    }
+                    Which::None(()) => {
+                        println!("{}: no active configuration", self.options.kit_serial);
+                        self.set_configuration(None, None);
+                    }
+                    Which::Unchanged(()) => {}
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn set_configuration(&mut self, configuration: Option<Configuration>, hash: Option<String>) {
+        self.configuration = Some(configuration);
+        self.configuration_hash = hash;
+        self.aggregates.clear();
+        self.aggregate_window_start = SystemTime::now();
+    }
+
+    fn handle_configuration_changed(&mut self, mut payload: &[u8]) -> capnp::Result<()> {
+        use astroplant_capnp::active_configuration_hash::Which;
+
+        let message_reader =
+            serialize_packed::read_message(&mut payload, capnp::message::ReaderOptions::default())?;
+        let configuration_hash =
+            message_reader.get_root::<astroplant_capnp::active_configuration_hash::Reader>()?;
+
+        let hash = match configuration_hash.which()? {
+            Which::Configuration(configuration) => Some(configuration?.get_hash()?.to_owned()),
+            Which::None(()) => None,
+        };
+        if self.configuration.is_none() || hash != self.configuration_hash {
+            self.request_configuration();
+        }
+
+        Ok(())
+    }
+
+    fn handle_kit_rpc_request(&mut self, mut payload: &[u8]) -> capnp::Result<()> {
+        use astroplant_capnp::kit_rpc_request::Which;
+
+        let message_reader =
+            serialize_packed::read_message(&mut payload, capnp::message::ReaderOptions::default())?;
+        let request = message_reader.get_root::<astroplant_capnp::kit_rpc_request::Reader>()?;
+
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut response_builder =
+            message_builder.init_root::<astroplant_capnp::kit_rpc_response::Builder>();
+        response_builder.set_id(request.get_id());
+
+        match request.which() {
+            Ok(Which::Version(())) => {
+                response_builder.set_version(concat!(
+                    "astroplant-kit-simulator ",
+                    env!("CARGO_PKG_VERSION")
+                ));
+            }
+            Ok(Which::Uptime(())) => {
+                response_builder.set_uptime(self.started.elapsed().as_secs());
+            }
+            Ok(Which::PeripheralCommand(peripheral_command)) => {
+                let peripheral_command = peripheral_command?;
+                println!(
+                    "{}: peripheral {} received command {}",
+                    self.options.kit_serial,
+                    peripheral_command.get_peripheral()?,
+                    peripheral_command.get_command()?
+                );
+                response_builder.set_peripheral_command(());
+            }
+            Err(_) => {
+                response_builder.init_error().set_method_not_found(());
+            }
+        }
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        let topic = if request.has_reply_to() {
+            self.topic(&format!("kit-rpc/response/{}", request.get_reply_to()?))
+        } else {
+            self.topic("kit-rpc/response")
+        };
+        self.publish(topic, false, bytes);
+
+        Ok(())
+    }
+
+    /// Publish a raw measurement for every quantity type of every peripheral, and publish the
+    /// aggregate measurements when the aggregation window has passed.
+    fn measure(&mut self) {
+        let now = SystemTime::now();
+        let seconds = millis_since_epoch(now) as f64 / 1000.0;
+
+        let mut measurements = vec![];
+        if let Some(Some(configuration)) = &self.configuration {
+            for peripheral_with_definition in &configuration.peripherals {
+                let peripheral = &peripheral_with_definition.peripheral;
+                for &quantity_type in &peripheral_with_definition
+                    .definition
+                    .expected_quantity_types
+                {
+                    let physical_quantity = self
+                        .quantity_types
+                        .as_ref()
+                        .and_then(|quantity_types| quantity_types.get(&quantity_type))
+                        .map(|quantity_type| quantity_type.physical_quantity.as_str());
+                    let (base, amplitude) = plausible_range(physical_quantity);
+
+                    // A slow daily cycle, offset per peripheral and quantity type.
+                    let phase = f64::from(peripheral.id * 31 + quantity_type * 17);
+                    let cycle = (seconds / 86_400.0 * 2.0 * std::f64::consts::PI + phase).sin();
+                    measurements.push((peripheral.id, quantity_type, base, amplitude, cycle));
+                }
+            }
+        }
+
+        for (peripheral, quantity_type, base, amplitude, cycle) in measurements {
+            let value = base + amplitude * (0.9 * cycle + 0.1 * self.noise.sample());
+            self.publish_raw_measurement(now, peripheral, quantity_type, value);
+            self.aggregates
+                .entry((peripheral, quantity_type))
+                .or_insert_with(Aggregate::new)
+                .add(value);
+        }
+
+        let window_passed = now
+            .duration_since(self.aggregate_window_start)
+            .map(|elapsed| elapsed >= self.options.aggregate_interval)
+            .unwrap_or(true);
+        if window_passed {
+            let aggregates = std::mem::replace(&mut self.aggregates, HashMap::new());
+            for ((peripheral, quantity_type), aggregate) in aggregates {
+                for aggregate_type in &AGGREGATE_TYPES {
+                    self.publish_aggregate_measurement(
+                        now,
+                        peripheral,
+                        quantity_type,
+                        aggregate_type,
+                        aggregate.value(aggregate_type),
+                    );
+                }
+            }
+            self.aggregate_window_start = now;
+        }
+    }
+
+    fn publish_raw_measurement(
+        &mut self,
+        datetime: SystemTime,
+        peripheral: i32,
+        quantity_type: i32,
+        value: f64,
+    ) {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut measurement_builder =
+            message_builder.init_root::<astroplant_capnp::raw_measurement::Builder>();
+        measurement_builder.set_kit_serial(&self.options.kit_serial);
+        measurement_builder.set_datetime(millis_since_epoch(datetime));
+        measurement_builder.set_peripheral(peripheral);
+        measurement_builder.set_quantity_type(quantity_type);
+        measurement_builder.set_value(value);
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        let topic = self.topic("measurement/raw");
+        self.publish(topic, false, bytes);
+        self.options
+            .published_raw_measurements
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn publish_aggregate_measurement(
+        &mut self,
+        datetime_end: SystemTime,
+        peripheral: i32,
+        quantity_type: i32,
+        aggregate_type: &str,
+        value: f64,
+    ) {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut measurement_builder =
+            message_builder.init_root::<astroplant_capnp::aggregate_measurement::Builder>();
+        measurement_builder.set_kit_serial(&self.options.kit_serial);
+        measurement_builder.set_datetime_start(millis_since_epoch(self.aggregate_window_start));
+        measurement_builder.set_datetime_end(millis_since_epoch(datetime_end));
+        measurement_builder.set_peripheral(peripheral);
+        measurement_builder.set_quantity_type(quantity_type);
+        measurement_builder.set_aggregate_type(aggregate_type);
+        measurement_builder.set_value(value);
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        let topic = self.topic("measurement/aggregate");
+        self.publish(topic, false, bytes);
+    }
+}
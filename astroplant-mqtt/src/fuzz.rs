@@ -0,0 +1,72 @@
+//! Generates malformed payloads for fuzz-style tests of the message parsers.
+
+/// A xorshift pseudo-random number generator. It is seeded with a constant, such that test
+/// failures are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A random number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Payloads derived from a valid payload by truncating it and flipping bits, and payloads of
+/// random bytes.
+pub fn payloads(valid: &[u8]) -> Vec<Vec<u8>> {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut payloads = vec![];
+
+    for len in 0..valid.len() {
+        payloads.push(valid[..len].to_vec());
+    }
+
+    for _ in 0..2000 {
+        let mut payload = valid.to_vec();
+        for _ in 0..=rng.below(4) {
+            let index = rng.below(payload.len());
+            payload[index] ^= 1 << rng.below(8);
+        }
+        payloads.push(payload);
+    }
+
+    for _ in 0..2000 {
+        let len = rng.below(128);
+        payloads.push((0..len).map(|_| rng.next_u64() as u8).collect());
+    }
+
+    payloads
+}
+
+/// Pack unpacked Cap'n Proto words, to craft messages that cannot be built with a message
+/// builder. Does not compress runs of zero words or literal words.
+pub fn pack(words: &[u64]) -> Vec<u8> {
+    let mut packed = vec![];
+    for word in words {
+        let bytes = word.to_le_bytes();
+        let mut tag = 0u8;
+        for (index, byte) in bytes.iter().enumerate() {
+            if *byte != 0 {
+                tag |= 1 << index;
+            }
+        }
+
+        packed.push(tag);
+        match tag {
+            0x00 => packed.push(0),
+            0xff => {
+                packed.extend_from_slice(&bytes);
+                packed.push(0);
+            }
+            _ => packed.extend(bytes.iter().filter(|byte| **byte != 0)),
+        }
+    }
+    packed
+}
@@ -0,0 +1,79 @@
+use log::{trace, warn};
+
+use super::astroplant_capnp;
+use super::transport::Transport;
+
+use capnp::serialize_packed;
+use std::sync::Arc;
+
+/// The id and content hash of a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationHash {
+    pub id: i32,
+    /// The hex-encoded SHA-256 hash of the JSON-encoded configuration.
+    pub hash: String,
+}
+
+/// Build an `ActiveConfigurationHash` message.
+pub(crate) fn set_active_configuration_hash(
+    mut builder: astroplant_capnp::active_configuration_hash::Builder,
+    configuration_hash: Option<&ConfigurationHash>,
+) {
+    match configuration_hash {
+        Some(configuration_hash) => {
+            let mut configuration_builder = builder.init_configuration();
+            configuration_builder.set_id(configuration_hash.id);
+            configuration_builder.set_hash(&configuration_hash.hash);
+        }
+        None => builder.set_none(()),
+    }
+}
+
+/// A handle to send notifications to kits.
+#[derive(Clone)]
+pub struct KitsNotifier {
+    transport: Arc<dyn Transport>,
+}
+
+impl KitsNotifier {
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        Self { transport }
+    }
+
+    /// Notify the kit its active configuration has changed. `configuration_hash` is `None` if the
+    /// kit no longer has an active configuration.
+    ///
+    /// The notification is retained, such that kits that are offline receive it when they
+    /// reconnect.
+    pub fn configuration_changed(
+        &mut self,
+        kit_serial: &str,
+        configuration_hash: Option<&ConfigurationHash>,
+    ) {
+        trace!(
+            "notifying kit {} of configuration change: {:?}",
+            kit_serial,
+            configuration_hash
+        );
+
+        let mut message_builder = capnp::message::Builder::new_default();
+        set_active_configuration_hash(
+            message_builder.init_root::<astroplant_capnp::active_configuration_hash::Builder>(),
+            configuration_hash,
+        );
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+
+        if let Err(err) = self.transport.publish(
+            format!("kit/{}/configuration/changed", kit_serial),
+            true,
+            bytes,
+        ) {
+            warn!(
+                "error occurred when notifying kit {} of configuration change: {:?}",
+                kit_serial, err
+            );
+        }
+    }
+}
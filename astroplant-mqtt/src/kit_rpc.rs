@@ -1,137 +1,183 @@
 use log::{debug, trace, warn};
 
+use super::overflow::{Lane, OverflowPolicy};
+use super::transport::Transport;
 use super::{astroplant_capnp, Error};
 
 use capnp::serialize_packed;
 use futures::channel::oneshot;
-use futures::task::SpawnExt;
-use rumqtt::{MqttClient, QoS};
-use std::collections::{HashMap, VecDeque};
+use futures::task::{Context, Poll, SpawnExt};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 const KIT_RPC_RESPONSE_BUFFER: usize = super::MQTT_API_MESSAGE_BUFFER;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// An error the kit responded with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RpcError {
+    Other,
+    MethodNotFound,
+    #[serde(rename_all = "camelCase")]
+    RateLimit {
+        /// The time in milliseconds to wait before retrying.
+        wait_time_millis: u64,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub enum KitRpcResponseError {
     TimedOut,
-    RpcError,
+    /// The request was canceled before the kit responded, e.g. because the connection to the MQTT
+    /// broker went away.
+    Canceled,
+    RpcError(RpcError),
     MalformedResponse,
     InvalidResponse,
 }
 
 pub type KitRpcResponse<T> = Result<T, KitRpcResponseError>;
-pub type KitResponseReceiver<T> = oneshot::Receiver<KitRpcResponse<T>>;
+
+/// A future resolving to the kit's response, or to `KitRpcResponseError::TimedOut` when the
+/// request's timeout passes. Dropping the receiver cancels the request: a response arriving
+/// afterwards is ignored.
+pub struct KitResponseReceiver<T> {
+    kit_serial: String,
+    id: u64,
+    handle: Arc<Mutex<Handle>>,
+    receiver: oneshot::Receiver<KitRpcResponse<T>>,
+    deadline: futures_timer::Delay,
+}
+
+impl<T> Future for KitResponseReceiver<T> {
+    type Output = KitRpcResponse<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(response) = Pin::new(&mut self.receiver).poll(cx) {
+            return Poll::Ready(response.unwrap_or(Err(KitRpcResponseError::Canceled)));
+        }
+
+        match Pin::new(&mut self.deadline).poll(cx) {
+            Poll::Ready(_) => Poll::Ready(Err(KitRpcResponseError::TimedOut)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for KitResponseReceiver<T> {
+    fn drop(&mut self) {
+        if let Ok(mut handle) = self.handle.lock() {
+            let key = (std::mem::take(&mut self.kit_serial), self.id);
+            if handle.callbacks.remove(&key).is_some() {
+                trace!("removed kit RPC callback with id: {}", self.id);
+            }
+        }
+    }
+}
 
 enum KitRpcResponseCallback {
     Version(oneshot::Sender<KitRpcResponse<String>>),
-    Uptime(oneshot::Sender<KitRpcResponse<std::time::Duration>>),
+    Uptime(oneshot::Sender<KitRpcResponse<Duration>>),
+    PeripheralCommand(oneshot::Sender<KitRpcResponse<()>>),
 }
 
 impl KitRpcResponseCallback {
-    pub fn invoke(self, payload: Vec<u8>) -> Result<(), ()> {
+    pub fn invoke(
+        self,
+        rpc_response: astroplant_capnp::kit_rpc_response::Reader,
+    ) -> Result<(), ()> {
         use astroplant_capnp::kit_rpc_response::Which;
         use KitRpcResponseCallback::*;
 
-        let message_reader = serialize_packed::read_message(
-            &mut payload.as_ref(),
-            capnp::message::ReaderOptions::default(),
-        )
-        // `invoke` is only called when the message was successfully deserialized before,
-        // so this unwrap is safe.
-        .unwrap();
-
-        let rpc_response = message_reader
-            .get_root::<astroplant_capnp::kit_rpc_response::Reader>()
-            // `invoke` is only called when the message was successfully deserialized before,
-            // so this unwrap is safe.
-            .unwrap();
-
         let which_response = rpc_response.which();
 
         match self {
-            Version(callback) => {
-                if let Ok(Which::Version(Ok(version))) = which_response {
-                    callback.send(Ok(version.to_owned())).map_err(|_| ())
-                } else if let Ok(Which::Error(_)) = which_response {
-                    callback
-                        .send(Err(KitRpcResponseError::RpcError))
-                        .map_err(|_| ())
-                } else {
-                    callback
-                        .send(Err(KitRpcResponseError::InvalidResponse))
-                        .map_err(|_| ())
-                }
-            }
-            Uptime(callback) => {
-                if let Ok(Which::Uptime(uptime)) = which_response {
-                    callback
-                        .send(Ok(std::time::Duration::from_secs(uptime)))
-                        .map_err(|_| ())
-                } else if let Ok(Which::Error(_)) = which_response {
-                    callback
-                        .send(Err(KitRpcResponseError::RpcError))
-                        .map_err(|_| ())
-                } else {
-                    callback
-                        .send(Err(KitRpcResponseError::InvalidResponse))
-                        .map_err(|_| ())
-                }
-            }
+            Version(callback) => respond(callback, which_response, |which| match which {
+                Which::Version(Ok(version)) => Some(version.to_owned()),
+                _ => None,
+            }),
+            Uptime(callback) => respond(callback, which_response, |which| match which {
+                Which::Uptime(uptime) => Some(Duration::from_secs(uptime)),
+                _ => None,
+            }),
+            PeripheralCommand(callback) => respond(callback, which_response, |which| match which {
+                Which::PeripheralCommand(()) => Some(()),
+                _ => None,
+            }),
         }
     }
+}
 
-    pub fn time_out(self) {
-        use KitRpcResponseCallback::*;
+/// Convert the error a kit responded with.
+fn rpc_error(error: capnp::Result<astroplant_capnp::rpc_error::Reader>) -> KitRpcResponseError {
+    use astroplant_capnp::rpc_error::Which;
 
-        match self {
-            Version(callback) => {
-                let _ = callback.send(Err(KitRpcResponseError::TimedOut));
-            }
-            Uptime(callback) => {
-                let _ = callback.send(Err(KitRpcResponseError::TimedOut));
-            }
-        };
+    match error.map(|error| error.which()) {
+        Ok(Ok(Which::Other(()))) => KitRpcResponseError::RpcError(RpcError::Other),
+        Ok(Ok(Which::MethodNotFound(()))) => {
+            KitRpcResponseError::RpcError(RpcError::MethodNotFound)
+        }
+        Ok(Ok(Which::RateLimit(wait_time_millis))) => {
+            KitRpcResponseError::RpcError(RpcError::RateLimit { wait_time_millis })
+        }
+        _ => KitRpcResponseError::InvalidResponse,
     }
 }
 
+/// Send a response to a callback. `extract` gets the response value if the response is of the
+/// type the callback expects.
+fn respond<T, F>(
+    callback: oneshot::Sender<KitRpcResponse<T>>,
+    which_response: Result<astroplant_capnp::kit_rpc_response::WhichReader, capnp::NotInSchema>,
+    extract: F,
+) -> Result<(), ()>
+where
+    F: FnOnce(astroplant_capnp::kit_rpc_response::WhichReader) -> Option<T>,
+{
+    use astroplant_capnp::kit_rpc_response::Which;
+
+    let response = match which_response {
+        Ok(Which::Error(error)) => Err(rpc_error(error)),
+        Ok(which) => extract(which).ok_or(KitRpcResponseError::InvalidResponse),
+        Err(_) => Err(KitRpcResponseError::InvalidResponse),
+    };
+    callback.send(response).map_err(|_| ())
+}
+
 struct Handle {
-    mqtt_client: MqttClient,
+    transport: Arc<dyn Transport>,
+    /// The topic level kits are asked to publish their responses under, if any.
+    reply_to: Option<String>,
     next_id: u64,
-    callbacks: HashMap<u64, KitRpcResponseCallback>,
-    timeouts: VecDeque<(u64, std::time::Instant)>,
+    /// The callbacks awaiting responses, by the serial of the kit the request was sent to and the
+    /// request id. Responses are only accepted from that kit.
+    callbacks: HashMap<(String, u64), KitRpcResponseCallback>,
 }
 
 impl Handle {
     fn get_next_id(&mut self) -> u64 {
         let id = self.next_id;
-        self.next_id += 1;
+        self.next_id = self.next_id.wrapping_add(1);
         id
     }
 
-    /// Insert the response callback, and get the id to be used for request.
-    pub fn insert_callback(&mut self, callback: KitRpcResponseCallback) -> u64 {
+    /// Insert the response callback of a request to the kit, and get the id to be used for the
+    /// request.
+    pub fn insert_callback(&mut self, kit_serial: String, callback: KitRpcResponseCallback) -> u64 {
         let id = self.get_next_id();
-        self.callbacks.insert(id, callback);
-        self.timeouts.push_back((id, std::time::Instant::now()));
+        self.callbacks.insert((kit_serial, id), callback);
         trace!("created kit RPC callback with id: {}", id);
         id
     }
-
-    /// Expire old callbacks.
-    pub fn cleanup(&mut self) {
-        let now = std::time::Instant::now();
-        while let Some(&(idx, instant)) = self.timeouts.get(0) {
-            if now.duration_since(instant).as_secs() >= 60 {
-                self.timeouts.pop_front();
-                if let Some(callback) = self.callbacks.remove(&idx) {
-                    callback.time_out();
-                }
-            } else {
-                break;
-            }
-        }
-    }
 }
 
 struct KitRpcRequest {
@@ -145,11 +191,14 @@ struct KitRpcRequestBuilder {
 }
 
 impl KitRpcRequestBuilder {
-    pub fn new(kit_serial: String, id: u64) -> Self {
+    pub fn new(kit_serial: String, id: u64, reply_to: Option<&str>) -> Self {
         let mut message_builder = capnp::message::Builder::new_default();
         let mut request_builder =
             message_builder.init_root::<astroplant_capnp::kit_rpc_request::Builder>();
         request_builder.set_id(id);
+        if let Some(reply_to) = reply_to {
+            request_builder.set_reply_to(reply_to);
+        }
         Self {
             kit_serial,
             message_builder,
@@ -174,6 +223,17 @@ impl KitRpcRequestBuilder {
         self
     }
 
+    pub fn peripheral_command(mut self, peripheral: &str, command: &str) -> Self {
+        let request_builder = self
+            .message_builder
+            .get_root::<astroplant_capnp::kit_rpc_request::Builder>()
+            .expect("could not get root");
+        let mut peripheral_command_builder = request_builder.init_peripheral_command();
+        peripheral_command_builder.set_peripheral(peripheral);
+        peripheral_command_builder.set_command(command);
+        self
+    }
+
     pub fn create(self) -> KitRpcRequest {
         let mut bytes = Vec::new();
         serialize_packed::write_message(&mut bytes, &self.message_builder).unwrap();
@@ -193,43 +253,70 @@ pub struct KitRpc {
 }
 
 impl KitRpc {
-    fn send(rpc_request: KitRpcRequest, mqtt_client: &mut MqttClient) {
-        mqtt_client
-            .publish(
-                format!("kit/{}/kit-rpc/request", rpc_request.kit_serial),
-                QoS::AtLeastOnce,
-                false,
-                rpc_request.bytes,
-            )
-            .expect("could not publish kit RPC request to MQTT");
+    fn send(rpc_request: KitRpcRequest, transport: &dyn Transport) {
+        if let Err(err) = transport.publish(
+            format!("kit/{}/kit-rpc/request", rpc_request.kit_serial),
+            false,
+            rpc_request.bytes,
+        ) {
+            warn!("could not publish kit RPC request to MQTT: {:?}", err);
+        }
     }
 
-    pub fn version(&self) -> KitResponseReceiver<String> {
+    /// Send a request to the kit, and get a receiver for its response. The callback is created by
+    /// `callback` from the sender side of the response channel.
+    fn request<T, C, B>(&self, timeout: Duration, callback: C, build: B) -> KitResponseReceiver<T>
+    where
+        C: FnOnce(oneshot::Sender<KitRpcResponse<T>>) -> KitRpcResponseCallback,
+        B: FnOnce(KitRpcRequestBuilder) -> KitRpcRequestBuilder,
+    {
         let (sender, receiver) = oneshot::channel();
 
         let mut handle = self.handle.lock().unwrap();
-        let id = handle.insert_callback(KitRpcResponseCallback::Version(sender));
-
-        let request = KitRpcRequestBuilder::new(self.kit_serial.clone(), id)
-            .version()
-            .create();
-        Self::send(request, &mut handle.mqtt_client);
-
-        receiver
+        let id = handle.insert_callback(self.kit_serial.clone(), callback(sender));
+
+        let request = build(KitRpcRequestBuilder::new(
+            self.kit_serial.clone(),
+            id,
+            handle.reply_to.as_deref(),
+        ))
+        .create();
+        Self::send(request, &*handle.transport);
+
+        KitResponseReceiver {
+            kit_serial: self.kit_serial.clone(),
+            id,
+            handle: self.handle.clone(),
+            receiver,
+            deadline: futures_timer::Delay::new(timeout),
+        }
     }
 
-    pub fn uptime(&self) -> KitResponseReceiver<std::time::Duration> {
-        let (sender, receiver) = oneshot::channel();
-
-        let mut handle = self.handle.lock().unwrap();
-        let id = handle.insert_callback(KitRpcResponseCallback::Uptime(sender));
+    pub fn version(&self, timeout: Duration) -> KitResponseReceiver<String> {
+        self.request(timeout, KitRpcResponseCallback::Version, |builder| {
+            builder.version()
+        })
+    }
 
-        let request = KitRpcRequestBuilder::new(self.kit_serial.clone(), id)
-            .uptime()
-            .create();
-        Self::send(request, &mut handle.mqtt_client);
+    pub fn uptime(&self, timeout: Duration) -> KitResponseReceiver<Duration> {
+        self.request(timeout, KitRpcResponseCallback::Uptime, |builder| {
+            builder.uptime()
+        })
+    }
 
-        receiver
+    /// Send a command to one of the kit's peripherals. The command is relayed to the kit as JSON;
+    /// it is up to the caller to ensure the command conforms to the peripheral's command schema.
+    pub fn peripheral_command(
+        &self,
+        peripheral: String,
+        command: serde_json::Value,
+        timeout: Duration,
+    ) -> KitResponseReceiver<()> {
+        self.request(
+            timeout,
+            KitRpcResponseCallback::PeripheralCommand,
+            |builder| builder.peripheral_command(&peripheral, &command.to_string()),
+        )
     }
 }
 
@@ -240,13 +327,26 @@ pub struct KitsRpc {
 }
 
 impl KitsRpc {
-    pub fn new(mqtt_client: MqttClient) -> Self {
+    /// Create a handle to kit RPCs. If `reply_to` is set, kits are asked to publish their
+    /// responses on `kit/{kitSerial}/kit-rpc/response/{replyTo}`.
+    pub fn new(transport: Arc<dyn Transport>, reply_to: Option<String>) -> Self {
+        // Kits that do not support `replyTo` publish their responses to all instances sharing
+        // the load; distinct instances number their requests distinctly.
+        let first_id = match &reply_to {
+            Some(reply_to) => {
+                let mut hasher = DefaultHasher::new();
+                reply_to.hash(&mut hasher);
+                hasher.finish() & 0xffff_ffff_0000_0000
+            }
+            None => 0,
+        };
+
         Self {
             handle: Arc::new(Mutex::new(Handle {
-                mqtt_client,
-                next_id: 0,
+                transport,
+                reply_to,
+                next_id: first_id,
                 callbacks: HashMap::new(),
-                timeouts: VecDeque::new(),
             })),
         }
     }
@@ -259,17 +359,9 @@ impl KitsRpc {
     }
 }
 
-/// Intermittently cleans old (timed-out) kit RPC response callbacks.
-async fn cleanup(handle: Arc<Mutex<Handle>>) {
-    loop {
-        futures_timer::Delay::new(std::time::Duration::from_secs(30)).await;
-        trace!("Performing kit RPC response handle cleanup");
-        let mut handle = handle.lock().unwrap();
-        handle.cleanup();
-    }
-}
-
 /// Handles kit RPC responses. Deserializes the payload and invokes the kit RPC response callback.
+/// `kit_serial` is taken from the response's topic: a kit can only respond to requests sent to
+/// that kit.
 async fn handle_response(handle: Arc<Mutex<Handle>>, kit_serial: String, payload: Vec<u8>) {
     let message_reader = match serialize_packed::read_message(
         &mut payload.as_ref(),
@@ -295,29 +387,40 @@ async fn handle_response(handle: Arc<Mutex<Handle>>, kit_serial: String, payload
     let id = rpc_response.get_id();
     let mut handle = handle.lock().unwrap();
 
-    trace!("received kit RPC response for id: {}", id);
+    trace!(
+        "received kit RPC response of kit {} for id: {}",
+        kit_serial,
+        id
+    );
 
-    if let Some(callback) = handle.callbacks.remove(&id) {
-        callback
-            .invoke(payload)
-            .expect("kit RPC response callback went away");
+    if let Some(callback) = handle.callbacks.remove(&(kit_serial, id)) {
+        if callback.invoke(rpc_response).is_err() {
+            trace!("kit RPC response receiver for id {} went away", id);
+        }
+    } else {
+        trace!("kit RPC response for id {} was not awaited", id);
     }
 }
 
 pub struct KitsRpcRunner {
     pub kits_rpc: KitsRpc,
-    pub mqtt_message_handler: crossbeam_channel::Sender<(String, Vec<u8>)>,
+    pub mqtt_message_handler: Lane<(String, Vec<u8>)>,
 }
 
 pub fn kit_rpc_runner(
-    mqtt_client: MqttClient,
+    transport: Arc<dyn Transport>,
+    reply_to: Option<String>,
     thread_pool: futures::executor::ThreadPool,
+    overflow_policy: OverflowPolicy,
+    dropped: Arc<AtomicUsize>,
 ) -> KitsRpcRunner {
-    let kits_rpc = KitsRpc::new(mqtt_client);
-    let (sender, receiver) = crossbeam_channel::bounded(KIT_RPC_RESPONSE_BUFFER);
-    thread_pool
-        .spawn(cleanup(kits_rpc.handle.clone()))
-        .expect("Could not spawn kit RPC response handler cleanup");
+    let kits_rpc = KitsRpc::new(transport, reply_to);
+    let (lane, receiver) = Lane::new(
+        "kit RPC responses",
+        KIT_RPC_RESPONSE_BUFFER,
+        overflow_policy,
+        dropped,
+    );
 
     {
         let handle = kits_rpc.handle.clone();
@@ -342,6 +445,6 @@ pub fn kit_rpc_runner(
 
     KitsRpcRunner {
         kits_rpc,
-        mqtt_message_handler: sender,
+        mqtt_message_handler: lane,
     }
 }
@@ -3,28 +3,52 @@ use log::{debug, trace, warn};
 use capnp::serialize_packed;
 use futures::task::SpawnExt;
 use futures::FutureExt;
-use rumqtt::{MqttClient, MqttOptions, Notification, QoS, ReconnectOptions, SecurityOptions};
+use std::collections::HashMap;
 use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 mod server_rpc;
 pub use server_rpc::{ServerRpcRequest, ServerRpcResponder};
 
 mod kit_rpc;
-pub use kit_rpc::{KitRpc, KitRpcResponseError, KitsRpc};
+pub use kit_rpc::{KitResponseReceiver, KitRpc, KitRpcResponseError, KitsRpc, RpcError};
+
+mod kit_notification;
+pub use kit_notification::{ConfigurationHash, KitsNotifier};
+
+mod overflow;
+use overflow::{DropCounters, Lane};
+pub use overflow::{DroppedMessages, OverflowPolicies, OverflowPolicy};
+
+pub mod transport;
+pub use transport::{ConnectionOptions, TlsOptions};
+use transport::{Notification, Transport};
+
+pub use crossbeam_channel::{RecvError, RecvTimeoutError};
+
+#[cfg(test)]
+mod fuzz;
 
 const MQTT_API_MESSAGE_BUFFER: usize = 128;
 
+/// The minimum interval between two `MqttApiMessage::KitSeen` messages of the same kit.
+const KIT_SEEN_INTERVAL: Duration = Duration::from_secs(10);
+
 pub mod astroplant_capnp {
     include!(concat!(env!("OUT_DIR"), "/proto/astroplant_capnp.rs"));
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct RawMeasurement {
     pub kit_serial: String,
     pub datetime: u64,
     pub peripheral: i32,
     pub quantity_type: i32,
     pub value: f64,
+    /// Whether the kit buffered the measurement and published it in a batch, rather than
+    /// publishing it right after measuring it.
+    pub buffered: bool,
 }
 
 #[derive(Debug)]
@@ -38,27 +62,220 @@ pub struct AggregateMeasurement {
     pub value: f64,
 }
 
+/// Measurements the kit buffered and published in one message. A batch is handed over whole: it
+/// takes a single place in the measurements queue.
+#[derive(Debug)]
+pub struct MeasurementBatch {
+    /// The serial of the kit of the topic the batch was published on. The measurements' kit
+    /// serials are set to it.
+    pub kit_serial: String,
+    pub raw_measurements: Vec<RawMeasurement>,
+    pub aggregate_measurements: Vec<AggregateMeasurement>,
+}
+
+/// A kit's status, published by the kit on `kit/{kitSerial}/status`. Kits should publish a
+/// retained `online` status after connecting, and set a retained `offline` status as their last
+/// will.
+#[derive(Clone, Debug)]
+pub struct KitStatus {
+    pub kit_serial: String,
+    pub online: bool,
+}
+
+/// A message that could not be handled, because its topic is invalid or its payload is
+/// malformed.
+#[derive(Debug)]
+pub struct MalformedMessage {
+    pub topic: String,
+    /// The kit serial, if the topic is within a kit's topics.
+    pub kit_serial: Option<String>,
+    pub payload: Vec<u8>,
+    pub error: Error,
+}
+
+impl MalformedMessage {
+    fn new(msg: &transport::Publish, error: Error) -> Self {
+        Self {
+            topic: msg.topic.clone(),
+            kit_serial: kit_serial_of_topic(&msg.topic).map(str::to_owned),
+            payload: msg.payload.to_vec(),
+            error,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum MqttApiMessage {
     RawMeasurement(RawMeasurement),
     AggregateMeasurement(AggregateMeasurement),
+    MeasurementBatch(MeasurementBatch),
     ServerRpcRequest(ServerRpcRequest),
+    MalformedMessage(MalformedMessage),
+    KitStatus(KitStatus),
+    /// A kit published a message. Sent at most once per `KIT_SEEN_INTERVAL` per kit.
+    KitSeen(String),
+}
+
+/// Receives the messages of the MQTT API. Measurements, server RPC requests and other messages are
+/// queued separately. Server RPC requests are received first, such that they are not held up by a
+/// flood of measurements. Messages of different queues may be received out of order.
+pub struct MqttApiReceiver {
+    server_rpc_requests: crossbeam_channel::Receiver<MqttApiMessage>,
+    kit_events: crossbeam_channel::Receiver<MqttApiMessage>,
+    measurements: crossbeam_channel::Receiver<MqttApiMessage>,
+    dropped: Arc<DropCounters>,
+}
+
+impl MqttApiReceiver {
+    /// Receive a message, waiting at most `timeout` for one to arrive.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<MqttApiMessage, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut disconnected = true;
+            for receiver in &[
+                &self.server_rpc_requests,
+                &self.kit_events,
+                &self.measurements,
+            ] {
+                match receiver.try_recv() {
+                    Ok(message) => return Ok(message),
+                    Err(crossbeam_channel::TryRecvError::Empty) => disconnected = false,
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => {}
+                }
+            }
+            if disconnected {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            // Wait for a message to arrive, then receive by priority.
+            crossbeam_channel::select! {
+                recv(self.server_rpc_requests) -> message => if let Ok(message) = message {
+                    return Ok(message);
+                },
+                recv(self.kit_events) -> message => if let Ok(message) = message {
+                    return Ok(message);
+                },
+                recv(self.measurements) -> message => if let Ok(message) = message {
+                    return Ok(message);
+                },
+                default(deadline - now) => return Err(RecvTimeoutError::Timeout),
+            }
+        }
+    }
+
+    /// Receive a message, waiting for one to arrive.
+    pub fn recv(&self) -> Result<MqttApiMessage, RecvError> {
+        loop {
+            match self.recv_timeout(Duration::from_secs(60)) {
+                Ok(message) => return Ok(message),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(RecvError),
+            }
+        }
+    }
+
+    /// The number of messages waiting to be received.
+    pub fn len(&self) -> usize {
+        self.server_rpc_requests.len() + self.kit_events.len() + self.measurements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of messages dropped so far, because their queue overflowed or their consumer
+    /// went away.
+    pub fn dropped(&self) -> DroppedMessages {
+        self.dropped.get()
+    }
+}
+
+/// The queues messages are sent to.
+struct Lanes {
+    server_rpc_requests: Lane<MqttApiMessage>,
+    kit_events: Lane<MqttApiMessage>,
+    measurements: Lane<MqttApiMessage>,
+    kit_rpc_responses: Lane<(String, Vec<u8>)>,
+}
+
+impl Lanes {
+    fn send(&self, message: MqttApiMessage) {
+        match message {
+            MqttApiMessage::RawMeasurement(_)
+            | MqttApiMessage::AggregateMeasurement(_)
+            | MqttApiMessage::MeasurementBatch(_) => self.measurements.send(message),
+            MqttApiMessage::ServerRpcRequest(_) => self.server_rpc_requests.send(message),
+            MqttApiMessage::MalformedMessage(_)
+            | MqttApiMessage::KitStatus(_)
+            | MqttApiMessage::KitSeen(_) => self.kit_events.send(message),
+        }
+    }
+}
+
+/// Get the kit serial of a topic within `kit/{kitSerial}/`.
+fn kit_serial_of_topic(topic: &str) -> Option<&str> {
+    let mut topic_parts = topic.split('/');
+    match (topic_parts.next(), topic_parts.next()) {
+        (Some("kit"), Some(serial)) if !serial.is_empty() => Some(serial),
+        _ => None,
+    }
 }
 
 enum MqttMessage {
     Api(MqttApiMessage, Option<ServerRpcResponder<'static>>),
     KitRpcResponse(String, Vec<u8>),
+    /// A message published by this server itself, received through the `kit/#` subscription.
+    Own,
+}
+
+/// Share the handling of kit messages with other instances of the MQTT API, through MQTT shared
+/// subscriptions (`$share/{group}/{topicFilter}`). The broker delivers each measurement and each
+/// server RPC request to only one instance of the group. Kit statuses are delivered to all
+/// instances. Kits are asked to publish the responses to kit RPCs made by an instance on a topic
+/// only that instance subscribes to.
+#[derive(Debug, Clone)]
+pub struct SharedSubscription {
+    /// The name of the group of instances sharing the load.
+    pub group: String,
+    /// Must be unique within the group, and be a valid MQTT topic level.
+    pub instance_id: String,
 }
 
-fn establish_subscriptions(mqtt_client: &mut MqttClient) {
-    if let Err(err) = mqtt_client.subscribe("kit/#", QoS::AtLeastOnce) {
-        warn!("error occurred while subscribing {:?}", err);
+/// The topic filters to subscribe to.
+fn topic_filters(shared_subscription: Option<&SharedSubscription>) -> Vec<String> {
+    match shared_subscription {
+        None => vec!["kit/#".to_owned()],
+        Some(SharedSubscription { group, instance_id }) => vec![
+            format!("$share/{}/kit/+/measurement/#", group),
+            format!("$share/{}/kit/+/server-rpc/request", group),
+            "kit/+/status".to_owned(),
+            // Kits that do not support `replyTo` respond on the kit RPC response topic itself.
+            "kit/+/kit-rpc/response".to_owned(),
+            format!("kit/+/kit-rpc/response/{}", instance_id),
+        ],
+    }
+}
+
+fn establish_subscriptions(transport: &dyn Transport, topic_filters: &[String]) {
+    for topic_filter in topic_filters {
+        if let Err(err) = transport.subscribe(topic_filter) {
+            warn!(
+                "error occurred while subscribing to {}: {:?}",
+                topic_filter, err
+            );
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
     InvalidTopic,
+    InvalidPayload,
     Capnp(capnp::Error),
     // The response is the error to send over MQTT. This is hacky.
     ServerRpcError(server_rpc::ServerRpcResponse),
@@ -78,6 +295,7 @@ fn parse_raw_measurement(kit_serial: String, mut payload: &[u8]) -> Result<MqttA
         peripheral: raw_measurement.get_peripheral(),
         quantity_type: raw_measurement.get_quantity_type(),
         value: raw_measurement.get_value(),
+        buffered: false,
     };
 
     Ok(MqttApiMessage::RawMeasurement(measurement))
@@ -110,15 +328,77 @@ fn parse_aggregate_measurement(
     Ok(MqttApiMessage::AggregateMeasurement(measurement))
 }
 
+/// Parse a batch of measurements. If any of the measurements is malformed, the whole batch is.
+/// Batched measurements carry no kit serial: they are of the kit of the topic.
+fn parse_measurement_batch(
+    kit_serial: String,
+    mut payload: &[u8],
+) -> Result<MqttApiMessage, Error> {
+    let message_reader =
+        serialize_packed::read_message(&mut payload, capnp::message::ReaderOptions::default())
+            .map_err(Error::Capnp)?;
+    let batch = message_reader
+        .get_root::<astroplant_capnp::measurement_batch::Reader>()
+        .map_err(Error::Capnp)?;
+
+    let raw_measurements = batch
+        .get_raw_measurements()
+        .map_err(Error::Capnp)?
+        .iter()
+        .map(|reader| RawMeasurement {
+            kit_serial: kit_serial.clone(),
+            datetime: reader.get_datetime(),
+            peripheral: reader.get_peripheral(),
+            quantity_type: reader.get_quantity_type(),
+            value: reader.get_value(),
+            buffered: true,
+        })
+        .collect();
+    let aggregate_measurements = batch
+        .get_aggregate_measurements()
+        .map_err(Error::Capnp)?
+        .iter()
+        .map(|reader| {
+            Ok(AggregateMeasurement {
+                kit_serial: kit_serial.clone(),
+                datetime_start: reader.get_datetime_start(),
+                datetime_end: reader.get_datetime_end(),
+                peripheral: reader.get_peripheral(),
+                quantity_type: reader.get_quantity_type(),
+                aggregate_type: reader
+                    .get_aggregate_type()
+                    .map_err(Error::Capnp)?
+                    .to_owned(),
+                value: reader.get_value(),
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+
+    Ok(MqttApiMessage::MeasurementBatch(MeasurementBatch {
+        kit_serial,
+        raw_measurements,
+        aggregate_measurements,
+    }))
+}
+
+fn parse_kit_status(kit_serial: String, payload: &[u8]) -> Result<MqttApiMessage, Error> {
+    let online = match payload {
+        b"online" => true,
+        b"offline" => false,
+        _ => return Err(Error::InvalidPayload),
+    };
+
+    Ok(MqttApiMessage::KitStatus(KitStatus { kit_serial, online }))
+}
+
 fn proxy<'a>(
     rpc_bytes: ServerRpcResponder<'a>,
-    mut mqtt_client: MqttClient,
+    transport: Arc<dyn Transport>,
 ) -> impl Future<Output = ()> + 'a {
     rpc_bytes.map(move |response| {
         if let Some(server_rpc::ServerRpcResponse { kit_serial, bytes }) = response {
-            if let Err(err) = mqtt_client.publish(
+            if let Err(err) = transport.publish(
                 format!("kit/{}/server-rpc/response", kit_serial),
-                QoS::AtLeastOnce,
                 false,
                 bytes,
             ) {
@@ -132,18 +412,37 @@ fn proxy<'a>(
 
 struct Handler {
     server_rpc_handler: server_rpc::ServerRpcHandler,
+    kits_seen: HashMap<String, Instant>,
 }
 
 impl Handler {
     pub fn new() -> Self {
         Self {
             server_rpc_handler: server_rpc::ServerRpcHandler::new(),
+            kits_seen: HashMap::new(),
         }
     }
 
-    fn handle_mqtt_publish(&mut self, msg: rumqtt::Publish) -> Result<MqttMessage, Error> {
-        trace!("received an MQTT message on topic {}", msg.topic_name);
-        let mut topic_parts = msg.topic_name.split("/");
+    /// Record that a kit published a message. Returns whether a `MqttApiMessage::KitSeen` message
+    /// should be sent.
+    fn kit_seen(&mut self, kit_serial: &str) -> bool {
+        let now = Instant::now();
+        match self.kits_seen.get_mut(kit_serial) {
+            Some(last_sent) if now.duration_since(*last_sent) < KIT_SEEN_INTERVAL => false,
+            Some(last_sent) => {
+                *last_sent = now;
+                true
+            }
+            None => {
+                self.kits_seen.insert(kit_serial.to_owned(), now);
+                true
+            }
+        }
+    }
+
+    fn handle_mqtt_publish(&mut self, msg: &transport::Publish) -> Result<MqttMessage, Error> {
+        trace!("received an MQTT message on topic {}", msg.topic);
+        let mut topic_parts = msg.topic.split("/");
         if topic_parts.next() != Some("kit") {
             return Err(Error::InvalidTopic);
         }
@@ -163,9 +462,14 @@ impl Handler {
                     parse_aggregate_measurement(kit_serial, &msg.payload)?,
                     None,
                 )),
+                Some("batch") => Ok(MqttMessage::Api(
+                    parse_measurement_batch(kit_serial, &msg.payload)?,
+                    None,
+                )),
                 _ => Err(Error::InvalidTopic),
             },
             Some("server-rpc") => match topic_parts.next() {
+                Some("response") => Ok(MqttMessage::Own),
                 Some("request") => self
                     .server_rpc_handler
                     .handle_rpc_request(kit_serial, &msg.payload)
@@ -174,7 +478,19 @@ impl Handler {
                     }),
                 _ => Err(Error::InvalidTopic),
             },
+            Some("status") => match topic_parts.next() {
+                None => Ok(MqttMessage::Api(
+                    parse_kit_status(kit_serial, &msg.payload)?,
+                    None,
+                )),
+                _ => Err(Error::InvalidTopic),
+            },
+            Some("configuration") => match topic_parts.next() {
+                Some("changed") => Ok(MqttMessage::Own),
+                _ => Err(Error::InvalidTopic),
+            },
             Some("kit-rpc") => match topic_parts.next() {
+                Some("request") => Ok(MqttMessage::Own),
                 Some("response") => Ok(MqttMessage::KitRpcResponse(
                     kit_serial,
                     msg.payload.to_vec(),
@@ -188,68 +504,110 @@ impl Handler {
     fn runner(
         &mut self,
         thread_pool: futures::executor::ThreadPool,
-        mut mqtt_client: MqttClient,
-        notifications: crossbeam_channel::Receiver<Notification>,
-        kit_rpc_mqtt_message_handler: crossbeam_channel::Sender<(String, Vec<u8>)>,
-        mqtt_api_sender: crossbeam_channel::Sender<MqttApiMessage>,
+        transport: Arc<dyn Transport>,
+        notifications: impl IntoIterator<Item = Notification>,
+        topic_filters: Vec<String>,
+        lanes: Lanes,
     ) {
-        establish_subscriptions(&mut mqtt_client);
-
         // Receive incoming notifications.
         for notification in notifications {
             trace!("Received MQTT notification: {:?}", notification);
             match notification {
                 Notification::Reconnection => {
-                    establish_subscriptions(&mut mqtt_client);
+                    establish_subscriptions(&*transport, &topic_filters);
                 }
                 Notification::Publish(publish) => {
-                    match self.handle_mqtt_publish(publish) {
+                    let handled = self.handle_mqtt_publish(&publish);
+
+                    // Any message published by a kit is a sign of life, except for its status:
+                    // that may be its last will.
+                    let from_kit = match &handled {
+                        Ok(MqttMessage::Own) => false,
+                        Ok(MqttMessage::Api(MqttApiMessage::KitStatus(_), _)) => false,
+                        _ => true,
+                    };
+                    if let Some(kit_serial) = kit_serial_of_topic(&publish.topic) {
+                        if from_kit && self.kit_seen(kit_serial) {
+                            lanes.send(MqttApiMessage::KitSeen(kit_serial.to_owned()));
+                        }
+                    }
+
+                    match handled {
                         Ok(MqttMessage::Api(msg, responder)) => {
                             if let Some(responder) = responder {
                                 thread_pool
-                                    .spawn(proxy(responder, mqtt_client.clone()))
+                                    .spawn(proxy(responder, transport.clone()))
                                     .expect("Could not spawn on threadpool");
                             }
-                            if mqtt_api_sender.send(msg).is_err() {
-                                // Receiver not keeping up. Disconnect.
-                                break;
-                            }
+                            lanes.send(msg);
                         }
                         Ok(MqttMessage::KitRpcResponse(kit_serial, payload)) => {
-                            if kit_rpc_mqtt_message_handler
-                                .send((kit_serial, payload))
-                                .is_err()
-                            {
-                                // Kit RPC handler not keeping up. Disconnect.
-                                break;
-                            }
+                            lanes.kit_rpc_responses.send((kit_serial, payload));
                         }
+                        Ok(MqttMessage::Own) => {}
                         Err(Error::ServerRpcError(response)) => {
-                            let _ = mqtt_client.publish(
+                            let _ = transport.publish(
                                 format!("kit/{}/server-rpc/response", response.kit_serial),
-                                QoS::AtLeastOnce,
                                 false,
                                 response.bytes,
                             );
                         }
                         Err(err) => {
                             debug!("Error parsing MQTT message: {:?}", err);
+                            let message = MalformedMessage::new(&publish, err);
+                            lanes.send(MqttApiMessage::MalformedMessage(message));
                         }
                     }
                 }
-                _ => {}
             }
         }
     }
 }
 
+/// Run the MQTT API on a connection to an MQTT broker. If `shared_subscription` is set, the
+/// handling of kit messages is shared with the other instances in its group.
 pub fn run(
-    mqtt_host: String,
-    mqtt_port: u16,
-    mqtt_username: String,
-    mqtt_password: String,
-) -> (crossbeam_channel::Receiver<MqttApiMessage>, KitsRpc) {
-    let (mqtt_api_sender, mqtt_api_receiver) = crossbeam_channel::bounded(MQTT_API_MESSAGE_BUFFER);
+    connection_options: ConnectionOptions,
+    shared_subscription: Option<SharedSubscription>,
+    overflow_policies: OverflowPolicies,
+) -> (MqttApiReceiver, KitsRpc, KitsNotifier) {
+    let (transport, notifications) = transport::RumqttTransport::start(connection_options).unwrap();
+
+    run_with_transport(
+        Arc::new(transport),
+        notifications,
+        shared_subscription,
+        overflow_policies,
+    )
+}
+
+/// Run the MQTT API on any transport, such as a `transport::Loopback` connection.
+/// `notifications` is the notification stream of the transport.
+pub fn run_with_transport(
+    transport: Arc<dyn Transport>,
+    notifications: impl IntoIterator<Item = Notification> + Send + 'static,
+    shared_subscription: Option<SharedSubscription>,
+    overflow_policies: OverflowPolicies,
+) -> (MqttApiReceiver, KitsRpc, KitsNotifier) {
+    let dropped = Arc::new(DropCounters::default());
+    let (server_rpc_requests, server_rpc_requests_receiver) = Lane::new(
+        "server RPC requests",
+        MQTT_API_MESSAGE_BUFFER,
+        overflow_policies.server_rpc_requests,
+        dropped.server_rpc_requests.clone(),
+    );
+    let (kit_events, kit_events_receiver) = Lane::new(
+        "kit events",
+        MQTT_API_MESSAGE_BUFFER,
+        overflow_policies.kit_events,
+        dropped.kit_events.clone(),
+    );
+    let (measurements, measurements_receiver) = Lane::new(
+        "measurements",
+        MQTT_API_MESSAGE_BUFFER,
+        overflow_policies.measurements,
+        dropped.measurements.clone(),
+    );
 
     let thread_pool = futures::executor::ThreadPoolBuilder::new()
         .pool_size(1)
@@ -257,27 +615,472 @@ pub fn run(
         .create()
         .expect("Could not build thread pool");
 
-    let mqtt_options = MqttOptions::new("astroplant-api-connector", mqtt_host, mqtt_port)
-        .set_reconnect_opts(ReconnectOptions::Always(10))
-        .set_security_opts(SecurityOptions::UsernamePassword(
-            mqtt_username,
-            mqtt_password,
-        ));
-    let (mqtt_client, notifications) = MqttClient::start(mqtt_options).unwrap();
+    let kit_rpc_runner = kit_rpc::kit_rpc_runner(
+        transport.clone(),
+        shared_subscription
+            .as_ref()
+            .map(|shared_subscription| shared_subscription.instance_id.clone()),
+        thread_pool.clone(),
+        overflow_policies.kit_rpc_responses,
+        dropped.kit_rpc_responses.clone(),
+    );
+    let kits_notifier = KitsNotifier::new(transport.clone());
 
-    let kit_rpc_runner = kit_rpc::kit_rpc_runner(mqtt_client.clone(), thread_pool.clone());
+    // Subscribe before returning, such that no messages are missed.
+    let topic_filters = topic_filters(shared_subscription.as_ref());
+    establish_subscriptions(&*transport, &topic_filters);
 
     let mut handler = Handler::new();
-    let kit_rpc_mqtt_message_handler = kit_rpc_runner.mqtt_message_handler;
+    let lanes = Lanes {
+        server_rpc_requests,
+        kit_events,
+        measurements,
+        kit_rpc_responses: kit_rpc_runner.mqtt_message_handler,
+    };
     std::thread::spawn(move || {
-        handler.runner(
-            thread_pool,
-            mqtt_client,
+        handler.runner(thread_pool, transport, notifications, topic_filters, lanes);
+    });
+
+    let mqtt_api_receiver = MqttApiReceiver {
+        server_rpc_requests: server_rpc_requests_receiver,
+        kit_events: kit_events_receiver,
+        measurements: measurements_receiver,
+        dropped,
+    };
+    (mqtt_api_receiver, kit_rpc_runner.kits_rpc, kits_notifier)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn raw_measurement_payload() -> Vec<u8> {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut measurement_builder =
+            message_builder.init_root::<astroplant_capnp::raw_measurement::Builder>();
+        measurement_builder.set_kit_serial("k_test");
+        measurement_builder.set_datetime(1_588_680_000_000);
+        measurement_builder.set_peripheral(1);
+        measurement_builder.set_quantity_type(2);
+        measurement_builder.set_value(21.5);
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        bytes
+    }
+
+    fn aggregate_measurement_payload() -> Vec<u8> {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut measurement_builder =
+            message_builder.init_root::<astroplant_capnp::aggregate_measurement::Builder>();
+        measurement_builder.set_kit_serial("k_test");
+        measurement_builder.set_datetime_start(1_588_680_000_000);
+        measurement_builder.set_datetime_end(1_588_680_300_000);
+        measurement_builder.set_peripheral(1);
+        measurement_builder.set_quantity_type(2);
+        measurement_builder.set_aggregate_type("average");
+        measurement_builder.set_value(21.5);
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        bytes
+    }
+
+    /// A batch of `raw` raw measurements taken a second apart, and one aggregate measurement.
+    fn measurement_batch_payload(raw: u32) -> Vec<u8> {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut batch_builder =
+            message_builder.init_root::<astroplant_capnp::measurement_batch::Builder>();
+        {
+            let mut raw_measurements = batch_builder.reborrow().init_raw_measurements(raw);
+            for index in 0..raw {
+                let mut measurement_builder = raw_measurements.reborrow().get(index);
+                measurement_builder.set_datetime(1_588_680_000_000 + u64::from(index) * 1000);
+                measurement_builder.set_peripheral(1);
+                measurement_builder.set_quantity_type(2);
+                measurement_builder.set_value(21.5);
+            }
+        }
+        let mut measurement_builder = batch_builder.init_aggregate_measurements(1).get(0);
+        measurement_builder.set_datetime_start(1_588_680_000_000);
+        measurement_builder.set_datetime_end(1_588_680_300_000);
+        measurement_builder.set_peripheral(1);
+        measurement_builder.set_quantity_type(2);
+        measurement_builder.set_aggregate_type("average");
+        measurement_builder.set_value(21.5);
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn parse_raw_measurement_valid() {
+        match parse_raw_measurement("k_test".to_owned(), &raw_measurement_payload()) {
+            Ok(MqttApiMessage::RawMeasurement(measurement)) => {
+                assert_eq!(measurement.kit_serial, "k_test");
+                assert_eq!(measurement.datetime, 1_588_680_000_000);
+                assert_eq!(measurement.peripheral, 1);
+                assert_eq!(measurement.quantity_type, 2);
+                assert_eq!(measurement.value, 21.5);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_raw_measurement_malformed() {
+        assert!(parse_raw_measurement("k_test".to_owned(), &[]).is_err());
+        for payload in fuzz::payloads(&raw_measurement_payload()) {
+            let _ = parse_raw_measurement("k_test".to_owned(), &payload);
+        }
+    }
+
+    #[test]
+    fn parse_aggregate_measurement_valid() {
+        match parse_aggregate_measurement("k_test".to_owned(), &aggregate_measurement_payload()) {
+            Ok(MqttApiMessage::AggregateMeasurement(measurement)) => {
+                assert_eq!(measurement.kit_serial, "k_test");
+                assert_eq!(measurement.datetime_start, 1_588_680_000_000);
+                assert_eq!(measurement.datetime_end, 1_588_680_300_000);
+                assert_eq!(measurement.aggregate_type, "average");
+                assert_eq!(measurement.value, 21.5);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_aggregate_measurement_malformed() {
+        assert!(parse_aggregate_measurement("k_test".to_owned(), &[]).is_err());
+        for payload in fuzz::payloads(&aggregate_measurement_payload()) {
+            let _ = parse_aggregate_measurement("k_test".to_owned(), &payload);
+        }
+    }
+
+    #[test]
+    fn parse_measurement_batch_valid() {
+        let batch =
+            match parse_measurement_batch("k_test".to_owned(), &measurement_batch_payload(3)) {
+                Ok(MqttApiMessage::MeasurementBatch(batch)) => batch,
+                other => panic!("unexpected result: {:?}", other),
+            };
+        assert_eq!(batch.kit_serial, "k_test");
+        assert_eq!(batch.raw_measurements.len(), 3);
+        for (index, measurement) in batch.raw_measurements.iter().enumerate() {
+            assert_eq!(measurement.kit_serial, "k_test");
+            assert_eq!(
+                measurement.datetime,
+                1_588_680_000_000 + index as u64 * 1000
+            );
+            assert!(measurement.buffered);
+        }
+        assert_eq!(batch.aggregate_measurements.len(), 1);
+        assert_eq!(batch.aggregate_measurements[0].kit_serial, "k_test");
+        assert_eq!(batch.aggregate_measurements[0].aggregate_type, "average");
+    }
+
+    #[test]
+    fn parse_measurement_batch_malformed() {
+        assert!(parse_measurement_batch("k_test".to_owned(), &[]).is_err());
+        for payload in fuzz::payloads(&measurement_batch_payload(3)) {
+            let _ = parse_measurement_batch("k_test".to_owned(), &payload);
+        }
+    }
+
+    /// Receive the payload of the next message published on `topic`.
+    fn receive_on(
+        notifications: &crossbeam_channel::Receiver<Notification>,
+        topic: &str,
+    ) -> Arc<Vec<u8>> {
+        loop {
+            match notifications.recv_timeout(Duration::from_secs(5)) {
+                Ok(Notification::Publish(publish)) if publish.topic == topic => {
+                    return publish.payload
+                }
+                Ok(_) => {}
+                Err(err) => panic!("no message was published on {}: {:?}", topic, err),
+            }
+        }
+    }
+
+    fn run_on_loopback(loopback: &transport::Loopback) -> (MqttApiReceiver, KitsRpc, KitsNotifier) {
+        let (transport, notifications) = loopback.connect();
+        run_with_transport(
+            Arc::new(transport),
+            notifications,
+            None,
+            OverflowPolicies::default(),
+        )
+    }
+
+    fn run_shared_on_loopback(
+        loopback: &transport::Loopback,
+        instance_id: &str,
+    ) -> (MqttApiReceiver, KitsRpc, KitsNotifier) {
+        let (transport, notifications) = loopback.connect();
+        run_with_transport(
+            Arc::new(transport),
             notifications,
-            kit_rpc_mqtt_message_handler,
-            mqtt_api_sender,
+            Some(SharedSubscription {
+                group: "api".to_owned(),
+                instance_id: instance_id.to_owned(),
+            }),
+            OverflowPolicies::default(),
+        )
+    }
+
+    /// Answer the next version kit RPC request of `k_test`, publishing the response on
+    /// `kit/k_test/kit-rpc/response` followed by the request's `replyTo`, if any.
+    fn respond_to_version_request(
+        kit: transport::LoopbackTransport,
+        kit_notifications: crossbeam_channel::Receiver<Notification>,
+    ) {
+        respond_to_version_request_as(kit, kit_notifications, "k_test")
+    }
+
+    /// Answer the next version kit RPC request of `k_test`, publishing the response within the
+    /// topics of the kit with serial `responding_kit_serial`.
+    fn respond_to_version_request_as(
+        kit: transport::LoopbackTransport,
+        kit_notifications: crossbeam_channel::Receiver<Notification>,
+        responding_kit_serial: &str,
+    ) {
+        let payload = receive_on(&kit_notifications, "kit/k_test/kit-rpc/request");
+        let message_reader = serialize_packed::read_message(
+            &mut payload.as_slice(),
+            capnp::message::ReaderOptions::default(),
+        )
+        .unwrap();
+        let rpc_request = message_reader
+            .get_root::<astroplant_capnp::kit_rpc_request::Reader>()
+            .unwrap();
+        match rpc_request.which() {
+            Ok(astroplant_capnp::kit_rpc_request::Which::Version(())) => {}
+            _ => panic!("unexpected kit RPC request"),
+        }
+
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut response_builder =
+            message_builder.init_root::<astroplant_capnp::kit_rpc_response::Builder>();
+        response_builder.set_id(rpc_request.get_id());
+        response_builder.set_version("astroplant-kit 1.0.0");
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+
+        let topic = if rpc_request.has_reply_to() {
+            format!(
+                "kit/{}/kit-rpc/response/{}",
+                responding_kit_serial,
+                rpc_request.get_reply_to().unwrap()
+            )
+        } else {
+            format!("kit/{}/kit-rpc/response", responding_kit_serial)
+        };
+        kit.publish(topic, false, bytes).unwrap();
+    }
+
+    #[test]
+    fn server_rpc_round_trip() {
+        let loopback = transport::Loopback::new();
+        let (receiver, _kits_rpc, _kits_notifier) = run_on_loopback(&loopback);
+
+        let (kit, kit_notifications) = loopback.connect();
+        kit.subscribe("kit/k_test/server-rpc/response").unwrap();
+
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut request_builder =
+            message_builder.init_root::<astroplant_capnp::server_rpc_request::Builder>();
+        request_builder.set_id(7);
+        request_builder.set_version(());
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        kit.publish("kit/k_test/server-rpc/request".to_owned(), false, bytes)
+            .unwrap();
+
+        loop {
+            match receiver.recv_timeout(Duration::from_secs(5)) {
+                Ok(MqttApiMessage::ServerRpcRequest(ServerRpcRequest::Version { response })) => {
+                    response.send("1.0.0".to_owned()).unwrap();
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) => panic!("the server RPC request was not received: {:?}", err),
+            }
+        }
+
+        let payload = receive_on(&kit_notifications, "kit/k_test/server-rpc/response");
+        let message_reader = serialize_packed::read_message(
+            &mut payload.as_slice(),
+            capnp::message::ReaderOptions::default(),
+        )
+        .unwrap();
+        let rpc_response = message_reader
+            .get_root::<astroplant_capnp::server_rpc_response::Reader>()
+            .unwrap();
+        assert_eq!(rpc_response.get_id(), 7);
+        match rpc_response.which() {
+            Ok(astroplant_capnp::server_rpc_response::Which::Version(Ok(version))) => {
+                assert_eq!(version, "1.0.0")
+            }
+            _ => panic!("unexpected server RPC response"),
+        }
+    }
+
+    #[test]
+    fn kit_rpc_round_trip() {
+        let loopback = transport::Loopback::new();
+        let (_receiver, kits_rpc, _kits_notifier) = run_on_loopback(&loopback);
+
+        let (kit, kit_notifications) = loopback.connect();
+        kit.subscribe("kit/k_test/kit-rpc/request").unwrap();
+        let kit = std::thread::spawn(move || respond_to_version_request(kit, kit_notifications));
+
+        let version = futures::executor::block_on(
+            kits_rpc
+                .kit_rpc("k_test".to_owned())
+                .version(Duration::from_secs(5)),
         );
-    });
+        assert_eq!(version, Ok("astroplant-kit 1.0.0".to_owned()));
+        kit.join().unwrap();
+    }
+
+    #[test]
+    fn kit_rpc_responses_of_other_kits_are_ignored() {
+        let loopback = transport::Loopback::new();
+        let (_receiver, kits_rpc, _kits_notifier) = run_on_loopback(&loopback);
 
-    (mqtt_api_receiver, kit_rpc_runner.kits_rpc)
+        // Another kit learns the request id, and responds in its own topics.
+        let (kit, kit_notifications) = loopback.connect();
+        kit.subscribe("kit/k_test/kit-rpc/request").unwrap();
+        let kit = std::thread::spawn(move || {
+            respond_to_version_request_as(kit, kit_notifications, "k_other")
+        });
+
+        let version = futures::executor::block_on(
+            kits_rpc
+                .kit_rpc("k_test".to_owned())
+                .version(Duration::from_millis(500)),
+        );
+        assert_eq!(version, Err(KitRpcResponseError::TimedOut));
+        kit.join().unwrap();
+    }
+
+    #[test]
+    fn shared_measurements_are_handled_once() {
+        let loopback = transport::Loopback::new();
+        let instances = vec![
+            run_shared_on_loopback(&loopback, "a"),
+            run_shared_on_loopback(&loopback, "b"),
+        ];
+
+        let (kit, _) = loopback.connect();
+        for _ in 0..4 {
+            kit.publish(
+                "kit/k_test/measurement/raw".to_owned(),
+                false,
+                raw_measurement_payload(),
+            )
+            .unwrap();
+        }
+
+        let mut measurements = 0;
+        for (receiver, _, _) in &instances {
+            while let Ok(message) = receiver.recv_timeout(Duration::from_millis(200)) {
+                if let MqttApiMessage::RawMeasurement(_) = message {
+                    measurements += 1;
+                }
+            }
+        }
+        assert_eq!(measurements, 4);
+    }
+
+    #[test]
+    fn shared_kit_rpc_responses_reach_the_requesting_instance() {
+        let loopback = transport::Loopback::new();
+        let (_receiver_a, kits_rpc_a, _kits_notifier_a) = run_shared_on_loopback(&loopback, "a");
+        let (_receiver_b, _kits_rpc_b, _kits_notifier_b) = run_shared_on_loopback(&loopback, "b");
+
+        let (observer, observer_notifications) = loopback.connect();
+        observer.subscribe("kit/k_test/kit-rpc/response/#").unwrap();
+
+        let (kit, kit_notifications) = loopback.connect();
+        kit.subscribe("kit/k_test/kit-rpc/request").unwrap();
+        let kit = std::thread::spawn(move || respond_to_version_request(kit, kit_notifications));
+
+        let version = futures::executor::block_on(
+            kits_rpc_a
+                .kit_rpc("k_test".to_owned())
+                .version(Duration::from_secs(5)),
+        );
+        assert_eq!(version, Ok("astroplant-kit 1.0.0".to_owned()));
+        kit.join().unwrap();
+
+        receive_on(&observer_notifications, "kit/k_test/kit-rpc/response/a");
+    }
+
+    #[test]
+    fn shared_kit_rpc_responses_survive_restarts_of_other_instances() {
+        let loopback = transport::Loopback::new();
+        let instance_a = run_shared_on_loopback(&loopback, "a");
+        let (_receiver_b, kits_rpc_b, _kits_notifier_b) = run_shared_on_loopback(&loopback, "b");
+
+        let (observer, observer_notifications) = loopback.connect();
+        observer.subscribe("kit/k_test/kit-rpc/request").unwrap();
+
+        // The kit only responds once instance "a" has restarted.
+        let (kit, kit_notifications) = loopback.connect();
+        kit.subscribe("kit/k_test/kit-rpc/request").unwrap();
+        let (restarted_sender, restarted_receiver) = crossbeam_channel::bounded::<()>(1);
+        let kit = std::thread::spawn(move || {
+            restarted_receiver.recv().unwrap();
+            respond_to_version_request(kit, kit_notifications)
+        });
+
+        let version = std::thread::spawn(move || {
+            futures::executor::block_on(
+                kits_rpc_b
+                    .kit_rpc("k_test".to_owned())
+                    .version(Duration::from_secs(5)),
+            )
+        });
+
+        receive_on(&observer_notifications, "kit/k_test/kit-rpc/request");
+        drop(instance_a);
+        let _instance_a = run_shared_on_loopback(&loopback, "a");
+        restarted_sender.send(()).unwrap();
+
+        assert_eq!(
+            version.join().unwrap(),
+            Ok("astroplant-kit 1.0.0".to_owned())
+        );
+        kit.join().unwrap();
+    }
+
+    #[test]
+    fn measurement_batches_larger_than_the_queue_are_handed_over_whole() {
+        let loopback = transport::Loopback::new();
+        let (receiver, _kits_rpc, _kits_notifier) = run_on_loopback(&loopback);
+
+        let (kit, _) = loopback.connect();
+        kit.publish(
+            "kit/k_test/measurement/batch".to_owned(),
+            false,
+            measurement_batch_payload(1000),
+        )
+        .unwrap();
+
+        loop {
+            match receiver.recv_timeout(Duration::from_secs(5)) {
+                Ok(MqttApiMessage::MeasurementBatch(batch)) => {
+                    assert_eq!(batch.raw_measurements.len(), 1000);
+                    assert_eq!(batch.aggregate_measurements.len(), 1);
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) => panic!("the measurement batch was not received: {:?}", err),
+            }
+        }
+        assert_eq!(receiver.dropped().measurements, 0);
+    }
 }
@@ -0,0 +1,207 @@
+//! Bounded queues between the MQTT handler and the consumers of its messages, with a policy for
+//! what to do when a queue is full.
+
+use log::warn;
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What to do with a message when its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the queue has room. This stops the handling of all MQTT messages until then.
+    Block,
+    /// Drop the oldest message in the queue to make room.
+    DropOldest,
+    /// Drop the message.
+    DropNewest,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The overflow policy of each class of messages.
+#[derive(Debug, Clone, Copy)]
+pub struct OverflowPolicies {
+    /// Raw and aggregate measurements, and measurement batches.
+    pub measurements: OverflowPolicy,
+    pub server_rpc_requests: OverflowPolicy,
+    /// Kit statuses, kits being seen, and malformed messages.
+    pub kit_events: OverflowPolicy,
+    pub kit_rpc_responses: OverflowPolicy,
+}
+
+impl Default for OverflowPolicies {
+    /// Measurements are dropped rather than blocking, such that a flood of measurements does not
+    /// hold up server RPC requests.
+    fn default() -> Self {
+        Self {
+            measurements: OverflowPolicy::DropOldest,
+            server_rpc_requests: OverflowPolicy::Block,
+            kit_events: OverflowPolicy::Block,
+            kit_rpc_responses: OverflowPolicy::Block,
+        }
+    }
+}
+
+/// The number of messages dropped of each class of messages. Messages are also dropped when their
+/// consumer has gone away.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DroppedMessages {
+    pub measurements: usize,
+    pub server_rpc_requests: usize,
+    pub kit_events: usize,
+    pub kit_rpc_responses: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct DropCounters {
+    pub measurements: Arc<AtomicUsize>,
+    pub server_rpc_requests: Arc<AtomicUsize>,
+    pub kit_events: Arc<AtomicUsize>,
+    pub kit_rpc_responses: Arc<AtomicUsize>,
+}
+
+impl DropCounters {
+    pub fn get(&self) -> DroppedMessages {
+        DroppedMessages {
+            measurements: self.measurements.load(Ordering::Relaxed),
+            server_rpc_requests: self.server_rpc_requests.load(Ordering::Relaxed),
+            kit_events: self.kit_events.load(Ordering::Relaxed),
+            kit_rpc_responses: self.kit_rpc_responses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The sending side of a bounded queue with an overflow policy.
+pub(crate) struct Lane<T> {
+    name: &'static str,
+    policy: OverflowPolicy,
+    sender: Sender<T>,
+    /// Used to drop the oldest message when the queue is full. Only set for the `DropOldest`
+    /// policy, as holding a receiver means the queue is never disconnected.
+    evictor: Option<Receiver<T>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl<T> Lane<T> {
+    pub fn new(
+        name: &'static str,
+        capacity: usize,
+        policy: OverflowPolicy,
+        dropped: Arc<AtomicUsize>,
+    ) -> (Self, Receiver<T>) {
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let evictor = match policy {
+            OverflowPolicy::DropOldest => Some(receiver.clone()),
+            _ => None,
+        };
+        (
+            Self {
+                name,
+                policy,
+                sender,
+                evictor,
+                dropped,
+            },
+            receiver,
+        )
+    }
+
+    pub fn send(&self, mut message: T) {
+        match (self.policy, &self.evictor) {
+            (OverflowPolicy::DropOldest, Some(evictor)) => loop {
+                match self.sender.try_send(message) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(returned)) => {
+                        if evictor.try_recv().is_ok() {
+                            self.drop_one();
+                        }
+                        message = returned;
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        self.drop_one();
+                        break;
+                    }
+                }
+            },
+            (OverflowPolicy::DropNewest, _) => {
+                if self.sender.try_send(message).is_err() {
+                    self.drop_one();
+                }
+            }
+            _ => {
+                if self.sender.send(message).is_err() {
+                    self.drop_one();
+                }
+            }
+        }
+    }
+
+    fn drop_one(&self) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        // Keep the log readable during floods.
+        if dropped.is_power_of_two() {
+            warn!("dropped {} {} in total", dropped, self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lane(policy: OverflowPolicy) -> (Lane<u32>, Receiver<u32>, Arc<AtomicUsize>) {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (lane, receiver) = Lane::new("test messages", 2, policy, dropped.clone());
+        (lane, receiver, dropped)
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (lane, receiver, dropped) = lane(OverflowPolicy::DropOldest);
+        for message in 0..5 {
+            lane.send(message);
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (lane, receiver, dropped) = lane(OverflowPolicy::DropNewest);
+        for message in 0..5 {
+            lane.send(message);
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn block_drops_when_disconnected() {
+        let (lane, receiver, dropped) = lane(OverflowPolicy::Block);
+        lane.send(0);
+        drop(receiver);
+        lane.send(1);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!("drop-oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert_eq!("drop-newest".parse(), Ok(OverflowPolicy::DropNewest));
+        assert_eq!("block".parse(), Ok(OverflowPolicy::Block));
+        assert_eq!("drop".parse::<OverflowPolicy>(), Err(()));
+    }
+}
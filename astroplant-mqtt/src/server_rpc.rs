@@ -1,6 +1,6 @@
 use log::{debug, trace};
 
-use super::{astroplant_capnp, Error};
+use super::{astroplant_capnp, kit_notification, ConfigurationHash, Error};
 
 use capnp::serialize_packed;
 use futures::channel::oneshot;
@@ -12,13 +12,23 @@ pub enum ServerRpcRequest {
     Version {
         response: oneshot::Sender<String>,
     },
+    /// Get the kit's active configuration and its hash.
     GetActiveConfiguration {
         kit_serial: String,
-        response: oneshot::Sender<Option<serde_json::Value>>,
+        response: oneshot::Sender<Option<(serde_json::Value, ConfigurationHash)>>,
+    },
+    /// Get the id and hash of the kit's active configuration.
+    GetActiveConfigurationHash {
+        kit_serial: String,
+        response: oneshot::Sender<Option<ConfigurationHash>>,
     },
     GetQuantityTypes {
         response: oneshot::Sender<Vec<serde_json::Value>>,
     },
+    /// Get the server's time in milliseconds since the UNIX epoch.
+    GetServerTime {
+        response: oneshot::Sender<u64>,
+    },
 }
 
 #[derive(Debug)]
@@ -44,6 +54,15 @@ impl ServerRpcResponseBuilder {
         }
     }
 
+    pub fn set_error_other(mut self) -> Self {
+        let response_builder = self
+            .message_builder
+            .get_root::<astroplant_capnp::server_rpc_response::Builder>()
+            .expect("could not get root");
+        response_builder.init_error().set_other(());
+        self
+    }
+
     pub fn set_error_method_not_found(mut self) -> Self {
         let response_builder = self
             .message_builder
@@ -71,26 +90,51 @@ impl ServerRpcResponseBuilder {
         self
     }
 
-    pub fn set_active_configuration(mut self, configuration: Option<serde_json::Value>) -> Self {
+    /// Set the active configuration. If the kit already knows the configuration, i.e., the hash
+    /// it provided is that of the active configuration, the response is set to `unchanged`.
+    pub fn set_active_configuration(
+        mut self,
+        configuration: Option<(serde_json::Value, ConfigurationHash)>,
+        known_hash: Option<String>,
+    ) -> Self {
         let response_builder = self
             .message_builder
             .get_root::<astroplant_capnp::server_rpc_response::Builder>()
             .expect("could not get root");
+        let mut active_configuration_builder = response_builder.init_get_active_configuration();
         match configuration {
-            Some(configuration) => {
-                response_builder
-                    .init_get_active_configuration()
-                    .set_configuration(&configuration.to_string());
+            Some((_, configuration_hash))
+                if known_hash.as_ref() == Some(&configuration_hash.hash) =>
+            {
+                active_configuration_builder.set_unchanged(());
+                active_configuration_builder.set_hash(&configuration_hash.hash);
+            }
+            Some((configuration, configuration_hash)) => {
+                active_configuration_builder.set_configuration(&configuration.to_string());
+                active_configuration_builder.set_hash(&configuration_hash.hash);
             }
             None => {
-                response_builder
-                    .init_get_active_configuration()
-                    .set_none(());
+                active_configuration_builder.set_none(());
             }
         }
         self
     }
 
+    pub fn set_active_configuration_hash(
+        mut self,
+        configuration_hash: Option<ConfigurationHash>,
+    ) -> Self {
+        let response_builder = self
+            .message_builder
+            .get_root::<astroplant_capnp::server_rpc_response::Builder>()
+            .expect("could not get root");
+        kit_notification::set_active_configuration_hash(
+            response_builder.init_get_active_configuration_hash(),
+            configuration_hash.as_ref(),
+        );
+        self
+    }
+
     pub fn set_quantity_types(mut self, quantity_types: Vec<serde_json::Value>) -> Self {
         let mut response_builder = self
             .message_builder
@@ -100,6 +144,15 @@ impl ServerRpcResponseBuilder {
         self
     }
 
+    pub fn set_server_time(mut self, millis: u64) -> Self {
+        let mut response_builder = self
+            .message_builder
+            .get_root::<astroplant_capnp::server_rpc_response::Builder>()
+            .expect("could not get root");
+        response_builder.set_get_server_time(millis);
+        self
+    }
+
     pub fn create(self) -> ServerRpcResponse {
         let mut bytes = Vec::new();
         serialize_packed::write_message(&mut bytes, &self.message_builder).unwrap();
@@ -113,6 +166,31 @@ impl ServerRpcResponseBuilder {
 
 pub type ServerRpcResponder<'a> = BoxFuture<'a, Option<ServerRpcResponse>>;
 
+/// Create the request for a kit's active configuration, and its responder. `known_hash` is the
+/// hash of the configuration the kit is running, if it provided one.
+fn get_active_configuration(
+    kit_serial: String,
+    id: u64,
+    known_hash: Option<String>,
+) -> (ServerRpcRequest, Option<ServerRpcResponder<'static>>) {
+    let (sender, receiver) = oneshot::channel();
+    let request = ServerRpcRequest::GetActiveConfiguration {
+        kit_serial: kit_serial.clone(),
+        response: sender,
+    };
+
+    let receiver = receiver.map(move |configuration| match configuration {
+        Ok(configuration) => Some(
+            ServerRpcResponseBuilder::new(kit_serial, id)
+                .set_active_configuration(configuration, known_hash)
+                .create(),
+        ),
+        Err(_) => None,
+    });
+
+    (request, Some(receiver.boxed()))
+}
+
 pub struct ServerRpcHandler {
     rate_limiter: KeyedRateLimiter<String>,
 }
@@ -150,12 +228,24 @@ impl ServerRpcHandler {
     ) -> Result<(ServerRpcRequest, Option<ServerRpcResponder<'static>>), Error> {
         let message_reader =
             serialize_packed::read_message(&mut payload, capnp::message::ReaderOptions::default())
-                .unwrap();
+                .map_err(Error::Capnp)?;
         let rpc_request = message_reader
             .get_root::<astroplant_capnp::server_rpc_request::Reader>()
             .map_err(Error::Capnp)?;
         let id: u64 = rpc_request.get_id();
 
+        // Now the request id is known, malformed requests can be responded to.
+        let malformed = |err: capnp::Error| {
+            debug!(
+                "request id {} of kit {} is malformed: {:?}",
+                id, kit_serial, err
+            );
+            let response = ServerRpcResponseBuilder::new(kit_serial.clone(), id)
+                .set_error_other()
+                .create();
+            Error::ServerRpcError(response)
+        };
+
         self.check_rate_limit(kit_serial.clone(), id)?;
 
         match rpc_request.which().map_err(|_| {
@@ -184,16 +274,40 @@ impl ServerRpcHandler {
             astroplant_capnp::server_rpc_request::Which::GetActiveConfiguration(_) => {
                 trace!("received server RPC active configuration request");
 
+                Ok(get_active_configuration(kit_serial, id, None))
+            }
+            astroplant_capnp::server_rpc_request::Which::GetActiveConfigurationV2(
+                get_active_configuration_v2,
+            ) => {
+                trace!("received server RPC active configuration request");
+
+                let get_active_configuration_v2 = get_active_configuration_v2.map_err(malformed)?;
+                let known_hash = if get_active_configuration_v2.has_known_hash() {
+                    Some(
+                        get_active_configuration_v2
+                            .get_known_hash()
+                            .map_err(malformed)?
+                            .to_owned(),
+                    )
+                } else {
+                    None
+                };
+
+                Ok(get_active_configuration(kit_serial, id, known_hash))
+            }
+            astroplant_capnp::server_rpc_request::Which::GetActiveConfigurationHash(_) => {
+                trace!("received server RPC active configuration hash request");
+
                 let (sender, receiver) = oneshot::channel();
-                let request = ServerRpcRequest::GetActiveConfiguration {
+                let request = ServerRpcRequest::GetActiveConfigurationHash {
                     kit_serial: kit_serial.clone(),
                     response: sender,
                 };
 
-                let receiver = receiver.map(move |configuration| match configuration {
-                    Ok(configuration) => Some(
+                let receiver = receiver.map(move |configuration_hash| match configuration_hash {
+                    Ok(configuration_hash) => Some(
                         ServerRpcResponseBuilder::new(kit_serial, id)
-                            .set_active_configuration(configuration)
+                            .set_active_configuration_hash(configuration_hash)
                             .create(),
                     ),
                     Err(_) => None,
@@ -218,6 +332,145 @@ impl ServerRpcHandler {
 
                 Ok((request, Some(receiver.boxed())))
             }
+            astroplant_capnp::server_rpc_request::Which::GetServerTime(_) => {
+                trace!("received server RPC server time request");
+
+                let (sender, receiver) = oneshot::channel();
+                let request = ServerRpcRequest::GetServerTime { response: sender };
+
+                let receiver = receiver.map(move |millis| match millis {
+                    Ok(millis) => Some(
+                        ServerRpcResponseBuilder::new(kit_serial, id)
+                            .set_server_time(millis)
+                            .create(),
+                    ),
+                    Err(_) => None,
+                });
+
+                Ok((request, Some(receiver.boxed())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fuzz;
+
+    fn get_active_configuration_payload() -> Vec<u8> {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut request_builder =
+            message_builder.init_root::<astroplant_capnp::server_rpc_request::Builder>();
+        request_builder.set_id(42);
+        request_builder
+            .init_get_active_configuration_v2()
+            .set_known_hash("44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a");
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        bytes
+    }
+
+    /// Read the id and error of a server RPC error response.
+    fn error_response(response: &ServerRpcResponse) -> (u64, astroplant_capnp::rpc_error::Which) {
+        let message_reader = serialize_packed::read_message(
+            &mut response.bytes.as_slice(),
+            capnp::message::ReaderOptions::default(),
+        )
+        .unwrap();
+        let rpc_response = message_reader
+            .get_root::<astroplant_capnp::server_rpc_response::Reader>()
+            .unwrap();
+        match rpc_response.which() {
+            Ok(astroplant_capnp::server_rpc_response::Which::Error(error)) => {
+                (rpc_response.get_id(), error.unwrap().which().unwrap())
+            }
+            _ => panic!("not an error response"),
+        }
+    }
+
+    #[test]
+    fn valid_request() {
+        let mut handler = ServerRpcHandler::new();
+        match handler.handle_rpc_request("k_test".to_owned(), &get_active_configuration_payload()) {
+            Ok((ServerRpcRequest::GetActiveConfiguration { kit_serial, .. }, Some(_))) => {
+                assert_eq!(kit_serial, "k_test")
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(request, _)| request)),
+        }
+    }
+
+    #[test]
+    fn valid_request_of_kits_without_known_hash() {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut request_builder =
+            message_builder.init_root::<astroplant_capnp::server_rpc_request::Builder>();
+        request_builder.set_id(42);
+        request_builder.set_get_active_configuration(());
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+
+        let mut handler = ServerRpcHandler::new();
+        match handler.handle_rpc_request("k_test".to_owned(), &bytes) {
+            Ok((ServerRpcRequest::GetActiveConfiguration { kit_serial, .. }, Some(_))) => {
+                assert_eq!(kit_serial, "k_test")
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(request, _)| request)),
+        }
+    }
+
+    #[test]
+    fn unrecoverable_request() {
+        let mut handler = ServerRpcHandler::new();
+        match handler.handle_rpc_request("k_test".to_owned(), &[]) {
+            Err(Error::Capnp(_)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|(request, _)| request)),
+        }
+    }
+
+    #[test]
+    fn malformed_request_with_id() {
+        // A request with id 42 for the active configuration, of which the pointer to the
+        // `GetActiveConfiguration` struct is a list pointer.
+        let payload = fuzz::pack(&[
+            // Segment table: one segment of four words.
+            4 << 32,
+            // Root struct pointer: two data words and one pointer.
+            (2 << 32) | (1 << 48),
+            // Id.
+            42,
+            // Union discriminant: `getActiveConfigurationV2`.
+            5,
+            // An empty list of bytes.
+            1 | (2 << 32),
+        ]);
+
+        let mut handler = ServerRpcHandler::new();
+        match handler.handle_rpc_request("k_test".to_owned(), &payload) {
+            Err(Error::ServerRpcError(response)) => {
+                assert_eq!(response.kit_serial, "k_test");
+                match error_response(&response) {
+                    (42, astroplant_capnp::rpc_error::Which::Other(())) => {}
+                    (id, _) => panic!("unexpected error response to id {}", id),
+                }
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(request, _)| request)),
+        }
+    }
+
+    #[test]
+    fn fuzz_requests() {
+        for payload in fuzz::payloads(&get_active_configuration_payload()) {
+            // Fresh handlers are not rate limited.
+            let mut handler = ServerRpcHandler::new();
+            match handler.handle_rpc_request("k_test".to_owned(), &payload) {
+                Ok(_) | Err(Error::Capnp(_)) => {}
+                Err(Error::ServerRpcError(response)) => {
+                    error_response(&response);
+                }
+                Err(err) => panic!("unexpected error: {:?}", err),
+            }
         }
     }
 }
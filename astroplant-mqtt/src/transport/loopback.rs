@@ -0,0 +1,250 @@
+//! An in-process broker, to run the MQTT API without an MQTT broker, e.g. in tests.
+
+use super::{Notification, Publish, Transport, TransportError};
+
+use crossbeam_channel::Sender;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Whether a topic matches a topic filter, which may contain the `+` and `#` wildcards.
+fn topic_matches(topic_filter: &str, topic: &str) -> bool {
+    let mut filter_levels = topic_filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Split a shared subscription `$share/{group}/{topic_filter}` into its group and topic filter.
+fn shared_subscription(topic_filter: &str) -> Option<(&str, &str)> {
+    let mut levels = topic_filter.splitn(3, '/');
+    match (levels.next(), levels.next(), levels.next()) {
+        (Some("$share"), Some(group), Some(topic_filter)) => Some((group, topic_filter)),
+        _ => None,
+    }
+}
+
+struct Client {
+    id: usize,
+    subscriptions: Vec<String>,
+    notifications: Sender<Notification>,
+}
+
+#[derive(Default)]
+struct Broker {
+    next_client_id: usize,
+    clients: Vec<Client>,
+    retained: HashMap<String, Arc<Vec<u8>>>,
+    /// The number of messages delivered per shared subscription, to take turns delivering to the
+    /// clients of its group.
+    shared_deliveries: HashMap<String, usize>,
+}
+
+/// An in-process broker. Clients connected to it receive the messages published by all clients,
+/// including themselves, on the topics they subscribed to. Retained messages are kept and
+/// delivered on subscribing, like an MQTT broker does.
+///
+/// Shared subscriptions `$share/{group}/{topic_filter}` are supported: a message matching the
+/// topic filter is delivered to one of the clients in the group, taking turns.
+#[derive(Clone, Default)]
+pub struct Loopback {
+    broker: Arc<Mutex<Broker>>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect a client to the broker. Returns the client's transport and its notification
+    /// stream. The client is disconnected when its notification stream is dropped.
+    pub fn connect(&self) -> (LoopbackTransport, crossbeam_channel::Receiver<Notification>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut broker = self.broker.lock().unwrap();
+        let id = broker.next_client_id;
+        broker.next_client_id += 1;
+        broker.clients.push(Client {
+            id,
+            subscriptions: vec![],
+            notifications: sender,
+        });
+        (
+            LoopbackTransport {
+                broker: self.broker.clone(),
+                id,
+            },
+            receiver,
+        )
+    }
+}
+
+/// A client's connection to a `Loopback` broker.
+pub struct LoopbackTransport {
+    broker: Arc<Mutex<Broker>>,
+    id: usize,
+}
+
+impl Transport for LoopbackTransport {
+    fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) -> Result<(), TransportError> {
+        let payload = Arc::new(payload);
+        let mut broker = self.broker.lock().unwrap();
+
+        if retain {
+            if payload.is_empty() {
+                broker.retained.remove(&topic);
+            } else {
+                broker.retained.insert(topic.clone(), payload.clone());
+            }
+        }
+
+        let Broker {
+            clients,
+            shared_deliveries,
+            ..
+        } = &mut *broker;
+
+        // The clients to deliver to, by index. A client receives the message once for its
+        // non-shared subscriptions, and once for each shared subscription it is chosen for.
+        let mut recipients = vec![];
+        let mut shared: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (index, client) in clients.iter().enumerate() {
+            let mut subscribed = false;
+            for subscription in &client.subscriptions {
+                match shared_subscription(subscription) {
+                    Some((_, topic_filter)) => {
+                        if topic_matches(topic_filter, &topic) {
+                            shared.entry(subscription).or_default().push(index);
+                        }
+                    }
+                    None => subscribed |= topic_matches(subscription, &topic),
+                }
+            }
+            if subscribed {
+                recipients.push(index);
+            }
+        }
+        for (subscription, group) in shared {
+            let deliveries = shared_deliveries
+                .entry(subscription.to_owned())
+                .or_default();
+            recipients.push(group[*deliveries % group.len()]);
+            *deliveries += 1;
+        }
+
+        let mut disconnected = vec![];
+        for index in recipients {
+            let sent = clients[index]
+                .notifications
+                .send(Notification::Publish(Publish {
+                    topic: topic.clone(),
+                    payload: payload.clone(),
+                }));
+            if sent.is_err() {
+                disconnected.push(clients[index].id);
+            }
+        }
+
+        // Clients whose notification stream was dropped are disconnected.
+        clients.retain(|client| !disconnected.contains(&client.id));
+        Ok(())
+    }
+
+    fn subscribe(&self, topic_filter: &str) -> Result<(), TransportError> {
+        let mut broker = self.broker.lock().unwrap();
+        let Broker {
+            clients, retained, ..
+        } = &mut *broker;
+
+        let client = clients
+            .iter_mut()
+            .find(|client| client.id == self.id)
+            .ok_or(TransportError::Disconnected)?;
+        if !client
+            .subscriptions
+            .iter()
+            .any(|subscription| subscription == topic_filter)
+        {
+            client.subscriptions.push(topic_filter.to_owned());
+        }
+
+        // Retained messages are delivered on shared subscriptions as well, as the group may have
+        // had no clients when the message was published.
+        let unshared_filter = shared_subscription(topic_filter)
+            .map(|(_, topic_filter)| topic_filter)
+            .unwrap_or(topic_filter);
+        for (topic, payload) in retained.iter() {
+            if topic_matches(unshared_filter, topic) {
+                let _ = client.notifications.send(Notification::Publish(Publish {
+                    topic: topic.clone(),
+                    payload: payload.clone(),
+                }));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn topic_filters() {
+        assert!(topic_matches("kit/#", "kit/k_test/measurement/raw"));
+        assert!(topic_matches("kit/+/status", "kit/k_test/status"));
+        assert!(!topic_matches("kit/+/status", "kit/k_test/status/extra"));
+        assert!(!topic_matches("kit/+/status", "kit/k_test"));
+        assert!(!topic_matches("kit/k_other/#", "kit/k_test/status"));
+    }
+
+    #[test]
+    fn shared_subscriptions() {
+        let loopback = Loopback::new();
+        let (publisher, _) = loopback.connect();
+        let members: Vec<_> = (0..2)
+            .map(|_| {
+                let (member, notifications) = loopback.connect();
+                member.subscribe("$share/api/kit/+/status").unwrap();
+                (member, notifications)
+            })
+            .collect();
+        let (observer, observer_notifications) = loopback.connect();
+        observer.subscribe("kit/#").unwrap();
+
+        for _ in 0..4 {
+            publisher
+                .publish("kit/k_test/status".to_owned(), false, b"online".to_vec())
+                .unwrap();
+        }
+
+        for (_, notifications) in &members {
+            assert_eq!(notifications.try_iter().count(), 2);
+        }
+        assert_eq!(observer_notifications.try_iter().count(), 4);
+    }
+
+    #[test]
+    fn retained_messages() {
+        let loopback = Loopback::new();
+        let (publisher, _) = loopback.connect();
+        publisher
+            .publish("kit/k_test/status".to_owned(), true, b"online".to_vec())
+            .unwrap();
+
+        let (subscriber, notifications) = loopback.connect();
+        subscriber.subscribe("kit/#").unwrap();
+        match notifications.try_recv() {
+            Ok(Notification::Publish(publish)) => {
+                assert_eq!(publish.topic, "kit/k_test/status");
+                assert_eq!(*publish.payload, b"online");
+            }
+            other => panic!("unexpected notification: {:?}", other),
+        }
+    }
+}
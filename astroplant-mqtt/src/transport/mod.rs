@@ -0,0 +1,143 @@
+//! The connection to the MQTT broker. All messages are published and subscribed to with QoS 1
+//! ("at least once").
+
+use log::warn;
+
+use rumqtt::{MqttClient, QoS, ReconnectOptions, SecurityOptions};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod loopback;
+pub use loopback::{Loopback, LoopbackTransport};
+
+#[derive(Debug)]
+pub enum TransportError {
+    Rumqtt(rumqtt::ClientError),
+    /// The transport is no longer connected.
+    Disconnected,
+}
+
+/// A message published on a topic the client subscribed to.
+#[derive(Debug, Clone)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Arc<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Notification {
+    Publish(Publish),
+    /// The client reconnected to the broker, and must establish its subscriptions again.
+    Reconnection,
+}
+
+/// Publishes and subscribes to topics. The notifications of the subscriptions are received on the
+/// notification stream belonging to the transport.
+pub trait Transport: Send + Sync {
+    fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) -> Result<(), TransportError>;
+
+    fn subscribe(&self, topic_filter: &str) -> Result<(), TransportError>;
+}
+
+/// TLS configuration of a connection to an MQTT broker.
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// The PEM-encoded certificate of the certificate authority the broker's certificate is
+    /// verified with.
+    pub ca: Vec<u8>,
+    /// The PEM-encoded client certificate and its private key, if the broker authenticates
+    /// clients by certificate.
+    pub client_auth: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// The configuration of a connection to an MQTT broker.
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub host: String,
+    pub port: u16,
+    /// Must be unique among the clients connected to the broker.
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    /// The interval at which the connection is checked when no messages are exchanged. Must be at
+    /// least five seconds.
+    pub keep_alive: Duration,
+    /// Connect over TLS. If not set, the connection is over plain TCP.
+    pub tls: Option<TlsOptions>,
+}
+
+impl ConnectionOptions {
+    fn mqtt_options(self) -> rumqtt::MqttOptions {
+        let mut mqtt_options = rumqtt::MqttOptions::new(self.client_id, self.host, self.port)
+            .set_keep_alive(self.keep_alive.as_secs() as u16)
+            .set_reconnect_opts(ReconnectOptions::Always(10))
+            .set_security_opts(SecurityOptions::UsernamePassword(
+                self.username,
+                self.password,
+            ));
+
+        if let Some(tls) = self.tls {
+            mqtt_options = mqtt_options.set_ca(tls.ca);
+            if let Some((certificate, key)) = tls.client_auth {
+                mqtt_options = mqtt_options.set_client_auth(certificate, key);
+            }
+        }
+
+        mqtt_options
+    }
+}
+
+/// A transport through a connection to an MQTT broker.
+pub struct RumqttTransport {
+    mqtt_client: Mutex<MqttClient>,
+}
+
+impl RumqttTransport {
+    /// Connect to the broker. Returns the transport and its notification stream.
+    pub fn start(
+        options: ConnectionOptions,
+    ) -> Result<(Self, impl Iterator<Item = Notification>), rumqtt::ConnectError> {
+        let (mqtt_client, notifications) = MqttClient::start(options.mqtt_options())?;
+        let notifications =
+            notifications
+                .into_iter()
+                .filter_map(|notification| match notification {
+                    rumqtt::Notification::Publish(publish) => {
+                        Some(Notification::Publish(Publish {
+                            topic: publish.topic_name,
+                            payload: publish.payload,
+                        }))
+                    }
+                    rumqtt::Notification::Reconnection => Some(Notification::Reconnection),
+                    rumqtt::Notification::Disconnection => {
+                        warn!("disconnected from the MQTT broker");
+                        None
+                    }
+                    _ => None,
+                });
+        Ok((
+            Self {
+                mqtt_client: Mutex::new(mqtt_client),
+            },
+            notifications,
+        ))
+    }
+}
+
+impl Transport for RumqttTransport {
+    fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) -> Result<(), TransportError> {
+        self.mqtt_client
+            .lock()
+            .unwrap()
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+            .map_err(TransportError::Rumqtt)
+    }
+
+    fn subscribe(&self, topic_filter: &str) -> Result<(), TransportError> {
+        self.mqtt_client
+            .lock()
+            .unwrap()
+            .subscribe(topic_filter, QoS::AtLeastOnce)
+            .map_err(TransportError::Rumqtt)
+    }
+}
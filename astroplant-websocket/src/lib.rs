@@ -5,10 +5,11 @@ mod types;
 mod web_socket_session;
 
 use subscribers::Subscribers;
-pub use types::RawMeasurement;
+pub use types::{KitPresence, RawMeasurement};
 
+use futures::future::{BoxFuture, FutureExt as _, TryFutureExt as _};
 use jsonrpc_core::MetaIoHandler;
-use jsonrpc_core::{futures as futuresOne, Params, Value};
+use jsonrpc_core::{futures as futuresOne, Error, ErrorCode, Params, Value};
 use jsonrpc_pubsub::typed::{Sink, Subscriber};
 use jsonrpc_pubsub::{PubSubHandler, Session, SubscriptionId};
 use jsonrpc_server_utils::tokio;
@@ -22,12 +23,19 @@ use futuresOne::future::Future as FutureOne;
 
 type PeripheralQuantityType = (i32, i32);
 
+/// Decides whether a client may subscribe to a kit, given the client's access token, if any, and
+/// the kit's serial.
+pub type KitAuthorizer =
+    Arc<dyn Fn(Option<String>, String) -> BoxFuture<'static, bool> + Send + Sync>;
+
 #[derive(Clone)]
 struct WebSocketHandler {
     executor: tokio::runtime::TaskExecutor,
     raw_measurement_subscriptions: Arc<RwLock<HashMap<String, Subscribers<Sink<Value>>>>>,
     raw_measurement_buffer:
         Arc<RwLock<HashMap<String, HashMap<PeripheralQuantityType, RawMeasurement>>>>,
+    kit_presence_subscriptions: Arc<RwLock<HashMap<String, Subscribers<Sink<Value>>>>>,
+    kit_presence_buffer: Arc<RwLock<HashMap<String, KitPresence>>>,
 }
 
 impl WebSocketHandler {
@@ -36,6 +44,8 @@ impl WebSocketHandler {
             executor,
             raw_measurement_subscriptions: Arc::new(RwLock::new(HashMap::default())),
             raw_measurement_buffer: Arc::new(RwLock::new(HashMap::default())),
+            kit_presence_subscriptions: Arc::new(RwLock::new(HashMap::default())),
+            kit_presence_buffer: Arc::new(RwLock::new(HashMap::default())),
         }
     }
 
@@ -110,6 +120,69 @@ impl WebSocketHandler {
         });
         trace!("Raw measurement subscriber removed: {:?}", id);
     }
+
+    fn publish_kit_presence(&self, kit_presence: KitPresence) {
+        let subscriptions = self.kit_presence_subscriptions.read().unwrap();
+
+        let subscribers: Option<&Subscribers<Sink<Value>>> =
+            subscriptions.get(&kit_presence.kit_serial);
+        if let Some(subscribers) = subscribers {
+            let value = serde_json::to_value(kit_presence.clone()).unwrap();
+            for (id, subscriber) in subscribers.iter() {
+                let id = id.clone();
+                self.executor
+                    .spawn(
+                        subscriber
+                            .notify(Ok(value.clone()))
+                            .map(|_| ())
+                            .map_err(move |_| {
+                                debug!(
+                                    "subscriber {:?}: failed sending kit presence. Transport has gone away.",
+                                    id
+                                )
+                            }),
+                    );
+            }
+        }
+
+        let mut buffer = self.kit_presence_buffer.write().unwrap();
+        buffer.insert(kit_presence.kit_serial.clone(), kit_presence);
+    }
+
+    fn add_kit_presence_subscriber(&self, kit_serial: String, subscriber: Subscriber<Value>) {
+        let resend: Option<KitPresence> = self
+            .kit_presence_buffer
+            .read()
+            .unwrap()
+            .get(&kit_serial)
+            .cloned();
+
+        let mut subscriptions = self.kit_presence_subscriptions.write().unwrap();
+        let subscribers = subscriptions.entry(kit_serial).or_default();
+        let id = subscribers.add(subscriber);
+
+        let sink = id.and_then(|id| subscribers.get(&id));
+
+        // Resend the last known presence to new connection.
+        if let (Some(sink), Some(kit_presence)) = (sink, resend) {
+            self.executor.spawn(
+                sink.notify(Ok(serde_json::to_value(kit_presence).unwrap()))
+                    .map(|_| ())
+                    .map_err(|_| ()),
+            )
+        }
+    }
+
+    fn remove_kit_presence_subscriber(&self, id: SubscriptionId) {
+        let mut subscriptions = self.kit_presence_subscriptions.write().unwrap();
+
+        // O(n) with n the number of distinct kits subscribed to.
+        subscriptions.retain(|_, s| {
+            s.remove(&id);
+            !s.is_empty()
+        });
+        trace!("Kit presence subscriber removed: {:?}", id);
+    }
 }
 
 pub struct WebSocketPublisher {
@@ -122,13 +195,21 @@ impl WebSocketPublisher {
         self.web_socket_handler
             .publish_raw_measurement(kit_serial, raw_measurement);
     }
+
+    pub fn publish_kit_presence(&mut self, kit_presence: KitPresence) {
+        self.web_socket_handler.publish_kit_presence(kit_presence);
+    }
 }
 
 /// Runs a JSON-RPC server on top of a Warp WebSocket filter.
 /// An executor for handling messages in run in another thread.
 ///
+/// Subscriptions to kit presence are only accepted if `kit_presence_authorizer` allows them.
+///
 /// Returns a Warp filter and a handle to publish to subscriptions.
-pub fn run() -> (BoxedFilter<(impl warp::Reply,)>, WebSocketPublisher) {
+pub fn run(
+    kit_presence_authorizer: KitAuthorizer,
+) -> (BoxedFilter<(impl warp::Reply,)>, WebSocketPublisher) {
     let mut runtime = tokio::runtime::Builder::new().build().unwrap();
 
     let web_socket_handler = WebSocketHandler::new(runtime.executor());
@@ -165,6 +246,57 @@ pub fn run() -> (BoxedFilter<(impl warp::Reply,)>, WebSocketPublisher) {
             }
         }),
     );
+    io.add_subscription(
+        "kitPresence",
+        ("subscribe_kitPresence", {
+            let web_socket_handler = web_socket_handler.clone();
+            move |params: Params, _: Arc<Session>, subscriber: jsonrpc_pubsub::Subscriber| {
+                #[derive(Deserialize)]
+                #[serde(rename_all = "camelCase")]
+                struct SubParams {
+                    kit_serial: String,
+                    access_token: Option<String>,
+                }
+
+                match params.parse::<SubParams>() {
+                    Ok(sub_params) => {
+                        let executor = web_socket_handler.executor.clone();
+                        let web_socket_handler = web_socket_handler.clone();
+                        let authorized = kit_presence_authorizer(
+                            sub_params.access_token,
+                            sub_params.kit_serial.clone(),
+                        );
+                        let subscribe = authorized.unit_error().compat().map(move |authorized| {
+                            let subscriber = Subscriber::new(subscriber);
+                            if authorized {
+                                web_socket_handler
+                                    .add_kit_presence_subscriber(sub_params.kit_serial, subscriber);
+                            } else {
+                                trace!(
+                                    "Kit presence subscription to {} denied",
+                                    sub_params.kit_serial
+                                );
+                                let _ = subscriber.reject(Error {
+                                    code: ErrorCode::ServerError(403),
+                                    message: "Forbidden".to_owned(),
+                                    data: None,
+                                });
+                            }
+                        });
+                        executor.spawn(subscribe);
+                    }
+                    Err(_) => {}
+                }
+            }
+        }),
+        ("unsubscribe_kitPresence", {
+            let web_socket_handler = web_socket_handler.clone();
+            move |id: SubscriptionId, _| {
+                web_socket_handler.remove_kit_presence_subscriber(id);
+                futuresOne::future::ok(Value::Bool(true))
+            }
+        }),
+    );
     let io_handler: MetaIoHandler<Arc<Session>> = io.into();
 
     let num_sockets = Arc::new(Mutex::new(0usize));
@@ -10,3 +10,11 @@ pub struct RawMeasurement {
     pub value: f64,
 }
 
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KitPresence {
+    pub kit_serial: String,
+    pub online: bool,
+    pub last_seen: Option<u64>,
+    pub last_measurement_at: Option<u64>,
+}
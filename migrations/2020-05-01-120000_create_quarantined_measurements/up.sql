@@ -0,0 +1,19 @@
+CREATE TABLE quarantined_measurements (
+    id UUID PRIMARY KEY,
+    kit_serial VARCHAR NOT NULL,
+    kit_id INTEGER REFERENCES kits (id) ON DELETE CASCADE,
+    measurement_type VARCHAR NOT NULL,
+    peripheral_id INTEGER NOT NULL,
+    quantity_type_id INTEGER NOT NULL,
+    aggregate_type VARCHAR,
+    value DOUBLE PRECISION NOT NULL,
+    datetime TIMESTAMP WITH TIME ZONE,
+    datetime_end TIMESTAMP WITH TIME ZONE,
+    reason VARCHAR NOT NULL,
+    received_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX quarantined_measurements_kit_id_received_at_idx
+    ON quarantined_measurements (kit_id, received_at);
+CREATE INDEX quarantined_measurements_received_at_idx
+    ON quarantined_measurements (received_at);
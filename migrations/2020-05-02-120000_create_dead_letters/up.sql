@@ -0,0 +1,13 @@
+CREATE TABLE dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    kit_serial VARCHAR,
+    kit_id INTEGER REFERENCES kits (id) ON DELETE CASCADE,
+    topic VARCHAR NOT NULL,
+    payload BYTEA NOT NULL,
+    error_kind VARCHAR NOT NULL,
+    error_message VARCHAR,
+    received_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX dead_letters_kit_id_id_idx ON dead_letters (kit_id, id);
+CREATE INDEX dead_letters_received_at_idx ON dead_letters (received_at);
@@ -0,0 +1,4 @@
+ALTER TABLE kits
+    DROP COLUMN online,
+    DROP COLUMN last_seen,
+    DROP COLUMN last_measurement_at;
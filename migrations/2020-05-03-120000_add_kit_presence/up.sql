@@ -0,0 +1,4 @@
+ALTER TABLE kits
+    ADD COLUMN online BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN last_seen TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN last_measurement_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1 @@
+DROP TABLE queued_kit_rpc_requests;
@@ -0,0 +1,19 @@
+CREATE TABLE queued_kit_rpc_requests (
+    id BIGSERIAL PRIMARY KEY,
+    kit_id INTEGER NOT NULL REFERENCES kits (id) ON DELETE CASCADE,
+    user_id INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    method VARCHAR NOT NULL,
+    peripheral_id INTEGER REFERENCES peripherals (id) ON DELETE CASCADE,
+    command JSON,
+    status VARCHAR NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    response JSON,
+    error JSON,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    delivered_at TIMESTAMP WITH TIME ZONE,
+    completed_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX queued_kit_rpc_requests_kit_id_id_idx ON queued_kit_rpc_requests (kit_id, id);
+CREATE INDEX queued_kit_rpc_requests_pending_idx
+    ON queued_kit_rpc_requests (kit_id, id) WHERE status = 'pending';
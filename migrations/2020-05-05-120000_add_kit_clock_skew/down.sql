@@ -0,0 +1,2 @@
+ALTER TABLE kits
+    DROP COLUMN clock_skew_millis;
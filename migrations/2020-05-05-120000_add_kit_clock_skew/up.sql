@@ -0,0 +1,2 @@
+ALTER TABLE kits
+    ADD COLUMN clock_skew_millis BIGINT;
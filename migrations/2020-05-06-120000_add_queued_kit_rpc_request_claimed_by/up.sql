@@ -0,0 +1,2 @@
+ALTER TABLE queued_kit_rpc_requests
+    ADD COLUMN claimed_by VARCHAR;
@@ -13,6 +13,9 @@ pub enum KitAction {
     SetSuperMember,
     RpcVersion,
     RpcUptime,
+    RpcPeripheralCommand,
+    ViewRpcQueue,
+    ViewDeadLetters,
 }
 
 impl KitAction {
@@ -27,7 +30,7 @@ impl KitAction {
             View | SubscribeRealTimeMeasurements => {
                 kit.privacy_public_dashboard || kit_membership.is_some()
             }
-            EditDetails | EditConfiguration => kit_membership
+            EditDetails | EditConfiguration | RpcPeripheralCommand | ViewRpcQueue => kit_membership
                 .as_ref()
                 .map(|m| m.access_configure)
                 .unwrap_or(false),
@@ -35,7 +38,7 @@ impl KitAction {
                 .as_ref()
                 .map(|m| m.access_super)
                 .unwrap_or(false),
-            RpcVersion | RpcUptime => kit_membership
+            RpcVersion | RpcUptime | ViewDeadLetters => kit_membership
                 .as_ref()
                 .map(|m| m.access_super)
                 .unwrap_or(false),
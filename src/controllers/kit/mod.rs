@@ -13,6 +13,8 @@ pub fn router(pg: BoxedFilter<(crate::PgPooled,)>) -> BoxedFilter<(Response,)> {
     (warp::get().and(kit_by_serial(pg.clone().boxed())))
         .or(warp::post().and(reset_password(pg.clone().boxed())))
         .unify()
+        .or(warp::get().and(dead_letters(pg.clone().boxed())))
+        .unify()
         .or(warp::path::end()
             .and(warp::get())
             .and(kits(pg.clone().boxed())))
@@ -110,6 +112,60 @@ pub fn reset_password(
         })
 }
 
+/// Handles the `GET /kits/{kitSerial}/dead-letters?before={deadLetterId}` route.
+///
+/// Lists the MQTT messages of the kit that could not be handled, from newest to oldest.
+pub fn dead_letters(
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    #[derive(Deserialize)]
+    struct Page {
+        before: Option<i64>,
+    }
+
+    path!(String / "dead-letters")
+        .and(authentication::option_by_token())
+        .and(pg.clone())
+        .and_then(
+            |kit_serial: String, user_id: Option<models::UserId>, conn: PgPooled| {
+                helpers::fut_permission_or_forbidden(
+                    conn,
+                    user_id,
+                    kit_serial,
+                    crate::authorization::KitAction::ViewDeadLetters,
+                )
+                .map_ok(|(_, _, kit)| kit)
+            },
+        )
+        .and(warp::query::query::<Page>())
+        .and(pg)
+        .and_then(|kit: models::Kit, page: Page, conn: PgPooled| {
+            async move {
+                let kit_serial = kit.serial.clone();
+                let dead_letters = helpers::threadpool_diesel_ok(move || {
+                    models::DeadLetter::page_of_kit(&conn, &kit, page.before, 100)
+                })
+                .await?;
+
+                let next_page_uri = dead_letters.last().map(|last| {
+                    format!("/kits/{}/dead-letters?before={}", kit_serial, last.id)
+                });
+                let mut response_builder = ResponseBuilder::ok();
+                if let Some(next_page_uri) = next_page_uri {
+                    response_builder = response_builder.next_page_uri(next_page_uri);
+                }
+                Ok::<_, Rejection>(
+                    response_builder.body(
+                        dead_letters
+                            .into_iter()
+                            .map(views::DeadLetter::from)
+                            .collect::<Vec<_>>(),
+                    ),
+                )
+            }
+        })
+}
+
 /// Handles the `POST /kits` route.
 pub fn create_kit(
     pg: BoxedFilter<(crate::PgPooled,)>,
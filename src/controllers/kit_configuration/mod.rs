@@ -4,19 +4,23 @@ use futures::FutureExt;
 use serde::Deserialize;
 use warp::{filters::BoxedFilter, path, Filter, Rejection};
 
+use crate::mqtt::KitsNotifier;
 use crate::response::{Response, ResponseBuilder};
 use crate::utils::deserialize_some;
 use crate::PgPooled;
 use crate::{helpers, models, problem, views};
 
-pub fn router(pg: BoxedFilter<(crate::PgPooled,)>) -> BoxedFilter<(Response,)> {
+pub fn router(
+    kits_notifier: KitsNotifier,
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> BoxedFilter<(Response,)> {
     //impl Filter<Extract = (Response,), Error = Rejection> + Clone {
     trace!("Setting up configurations router.");
 
     configurations_by_kit_serial(pg.clone())
         .or(create_configuration(pg.clone()))
         .unify()
-        .or(patch_configuration(pg.clone()))
+        .or(patch_configuration(kits_notifier, pg.clone()))
         .unify()
         .or(peripheral::router(pg.clone()))
         .unify()
@@ -164,8 +168,10 @@ fn create_configuration(
 
 /// Handles the `PATCH /kit-configurations/{kitConfigurationId}?kitSerial={kitSerial}` route.
 ///
-/// If the configuration is set active, all other configurations of the kit are deactivated.
+/// If the configuration is set active, all other configurations of the kit are deactivated. If the
+/// kit's active configuration changes, the kit is notified over MQTT.
 fn patch_configuration(
+    kits_notifier: KitsNotifier,
     pg: BoxedFilter<(crate::PgPooled,)>,
 ) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
     use diesel::Connection;
@@ -190,13 +196,14 @@ fn patch_configuration(
         .and(crate::helpers::deserialize())
         .and(pg)
         .and_then(
-            |_user,
-             _kit_membership,
-             kit: models::Kit,
-             configuration: models::KitConfiguration,
-             configuration_patch: KitConfigurationPatch,
-             conn: PgPooled| {
-                async {
+            move |_user,
+                  _kit_membership,
+                  kit: models::Kit,
+                  configuration: models::KitConfiguration,
+                  configuration_patch: KitConfigurationPatch,
+                  conn: PgPooled| {
+                let mut kits_notifier = kits_notifier.clone();
+                async move {
                     if !configuration.never_used {
                         if configuration_patch.rules_supervisor_module_name.is_some()
                             || configuration_patch.rules_supervisor_class_name.is_some()
@@ -226,17 +233,28 @@ fn patch_configuration(
                     };
 
                     helpers::threadpool_diesel_ok(move || {
-                        conn.transaction(|| {
+                        let patched_configuration = conn.transaction(|| {
                             if let Some(active) = patch.active {
                                 if active != configuration.active {
                                     models::KitConfiguration::deactivate_all_of_kit(&conn, &kit)?;
                                 }
                             }
-                            let patched_configuration = patch.update(&conn)?;
+                            patch.update(&conn)
+                        })?;
 
-                            Ok(ResponseBuilder::ok()
-                                .body(views::KitConfiguration::from(patched_configuration)))
-                        })
+                        if configuration.active || patched_configuration.active {
+                            let active_configuration =
+                                crate::mqtt::ActiveConfiguration::of_kit(&conn, &kit)?;
+                            kits_notifier.configuration_changed(
+                                &kit.serial,
+                                active_configuration
+                                    .as_ref()
+                                    .map(|active_configuration| &active_configuration.hash),
+                            );
+                        }
+
+                        Ok(ResponseBuilder::ok()
+                            .body(views::KitConfiguration::from(patched_configuration)))
                     })
                     .await
                 }
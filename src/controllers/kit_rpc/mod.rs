@@ -1,11 +1,35 @@
-use astroplant_mqtt::KitsRpc;
-use futures::future::TryFutureExt;
+mod queue;
+
+use astroplant_mqtt::{KitResponseReceiver, KitsRpc};
+use diesel::pg::PgConnection;
+use diesel::QueryResult;
+use futures::future::{FutureExt, TryFutureExt};
+use std::time::{Duration, Instant};
 use warp::{filters::BoxedFilter, path, Filter, Rejection};
 
 use crate::response::{Response, ResponseBuilder};
 use crate::PgPooled;
 use crate::{authentication, helpers, models, problem};
 
+/// The time kits are given to respond to RPC requests made through the REST API.
+const KIT_RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send a request to a kit and await its response. Errors are turned into a problem including the
+/// time spent waiting for the response.
+async fn kit_rpc_response<T, F>(request: F) -> Result<T, Rejection>
+where
+    F: FnOnce(Duration) -> KitResponseReceiver<T>,
+{
+    let start = Instant::now();
+    request(KIT_RPC_TIMEOUT).await.map_err(|err| {
+        warp::reject::custom(problem::KitRpcProblem::kit_rpc_response_error_into_problem(
+            err,
+            start.elapsed(),
+            KIT_RPC_TIMEOUT,
+        ))
+    })
+}
+
 pub fn router(kits_rpc: KitsRpc, pg: BoxedFilter<(crate::PgPooled,)>) -> BoxedFilter<(Response,)> {
     //impl Filter<Extract = (Response,), Error = Rejection> + Clone {
     trace!("Setting up kit rpc router.");
@@ -13,6 +37,10 @@ pub fn router(kits_rpc: KitsRpc, pg: BoxedFilter<(crate::PgPooled,)>) -> BoxedFi
     version(kits_rpc.clone(), pg.clone().boxed())
         .or(uptime(kits_rpc.clone(), pg.clone().boxed()))
         .unify()
+        .or(peripheral_command(kits_rpc.clone(), pg.clone().boxed()))
+        .unify()
+        .or(queue::router(pg.clone().boxed()))
+        .unify()
         .boxed()
 }
 
@@ -39,11 +67,7 @@ pub fn version(
             let kits_rpc = kits_rpc.clone();
             async move {
                 let rpc = kits_rpc.kit_rpc(kit.serial);
-                let version = rpc.version().await.unwrap().map_err(|err| {
-                    warp::reject::custom(
-                        problem::KitRpcProblem::kit_rpc_response_error_into_problem(err),
-                    )
-                })?;
+                let version = kit_rpc_response(|timeout| rpc.version(timeout)).await?;
                 Ok::<_, Rejection>(ResponseBuilder::ok().body(version))
             }
         })
@@ -72,12 +96,139 @@ pub fn uptime(
             let kits_rpc = kits_rpc.clone();
             async move {
                 let rpc = kits_rpc.kit_rpc(kit.serial);
-                let uptime = rpc.uptime().await.unwrap().map_err(|err| {
-                    warp::reject::custom(
-                        problem::KitRpcProblem::kit_rpc_response_error_into_problem(err),
-                    )
-                })?;
+                let uptime = kit_rpc_response(|timeout| rpc.uptime(timeout)).await?;
                 Ok::<_, Rejection>(ResponseBuilder::ok().body(uptime.as_secs()))
             }
         })
 }
+
+/// Validate a command against the command schema of a peripheral's definition.
+fn check_command(
+    command: &serde_json::Value,
+    peripheral_definition: &models::PeripheralDefinition,
+) -> Result<(), problem::Problem> {
+    let command_schema = match &peripheral_definition.command_schema {
+        Some(command_schema) => command_schema,
+        None => {
+            return Err(problem::InvalidParameterReason::Other
+                .singleton("peripheralId")
+                .into_problem())
+        }
+    };
+
+    let mut scope = valico::json_schema::Scope::new();
+    let schema = match scope.compile_and_return(command_schema.clone(), false) {
+        Ok(schema) => schema,
+        Err(_) => {
+            error!(
+                "peripheral definition with id {} has an invalid command schema",
+                peripheral_definition.id
+            );
+            return Err(problem::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if !schema.validate(command).is_strictly_valid() {
+        return Err(problem::InvalidParameterReason::Other
+            .singleton("command")
+            .into_problem());
+    }
+
+    Ok(())
+}
+
+/// Check whether a command can be sent to a peripheral of the kit, and get the peripheral. The
+/// peripheral must be part of the kit's active configuration, and the command must conform to
+/// the command schema of the peripheral's definition.
+fn check_peripheral_command(
+    conn: &PgConnection,
+    kit: &models::Kit,
+    peripheral_id: i32,
+    command: &serde_json::Value,
+) -> QueryResult<Result<models::Peripheral, problem::Problem>> {
+    let peripheral = match models::Peripheral::by_id(conn, models::PeripheralId(peripheral_id))? {
+        Some(peripheral) if peripheral.kit_id == kit.id => peripheral,
+        _ => {
+            return Ok(Err(problem::InvalidParameterReason::NotFound
+                .singleton("peripheralId")
+                .into_problem()))
+        }
+    };
+
+    // The kit only knows about the peripherals of its active configuration.
+    let active_configuration = models::KitConfiguration::active_configuration_of_kit(conn, kit)?;
+    if active_configuration.map(|configuration| configuration.id)
+        != Some(peripheral.kit_configuration_id)
+    {
+        return Ok(Err(problem::InvalidParameterReason::Other
+            .singleton("peripheralId")
+            .into_problem()));
+    }
+
+    let definition =
+        models::PeripheralDefinition::by_id(conn, peripheral.peripheral_definition_id)?;
+    if let Err(problem) = check_command(command, &definition) {
+        return Ok(Err(problem));
+    }
+
+    Ok(Ok(peripheral))
+}
+
+/// Handles the `POST /kit-rpc/{kitSerial}/peripherals/{peripheralId}/command` route.
+pub fn peripheral_command(
+    kits_rpc: KitsRpc,
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::post()
+        .and(path!(String / "peripherals" / i32 / "command"))
+        .and(authentication::option_by_token())
+        .and(pg.clone())
+        .and_then(
+            |kit_serial: String,
+             peripheral_id: i32,
+             user_id: Option<models::UserId>,
+             conn: PgPooled| {
+                helpers::fut_permission_or_forbidden(
+                    conn,
+                    user_id,
+                    kit_serial,
+                    crate::authorization::KitAction::RpcPeripheralCommand,
+                )
+                .map_ok(move |(_, _, kit)| (kit, peripheral_id))
+            },
+        )
+        .untuple_one()
+        .and(helpers::deserialize())
+        .and(pg)
+        .and_then(
+            |kit: models::Kit, peripheral_id: i32, command: serde_json::Value, conn: PgPooled| {
+                helpers::threadpool_diesel_ok(move || {
+                    let peripheral =
+                        match check_peripheral_command(&conn, &kit, peripheral_id, &command)? {
+                            Ok(peripheral) => peripheral,
+                            Err(problem) => return Ok(Err(warp::reject::custom(problem))),
+                        };
+
+                    Ok(Ok((kit, peripheral, command)))
+                })
+                .map(helpers::flatten_result)
+            },
+        )
+        .and_then(
+            move |(kit, peripheral, command): (
+                models::Kit,
+                models::Peripheral,
+                serde_json::Value,
+            )| {
+                let kits_rpc = kits_rpc.clone();
+                async move {
+                    let rpc = kits_rpc.kit_rpc(kit.serial);
+                    kit_rpc_response(|timeout| {
+                        rpc.peripheral_command(peripheral.name, command, timeout)
+                    })
+                    .await?;
+                    Ok::<_, Rejection>(ResponseBuilder::ok().empty())
+                }
+            },
+        )
+}
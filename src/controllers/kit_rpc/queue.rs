@@ -0,0 +1,207 @@
+use futures::future::{FutureExt, TryFutureExt};
+use serde::Deserialize;
+use warp::{filters::BoxedFilter, path, Filter, Rejection};
+
+use crate::response::{Response, ResponseBuilder};
+use crate::PgPooled;
+use crate::{authentication, helpers, models, problem, views};
+
+/// The number of queued requests per page.
+const PAGE_SIZE: i64 = 100;
+
+pub fn router(pg: BoxedFilter<(crate::PgPooled,)>) -> BoxedFilter<(Response,)> {
+    //impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    trace!("Setting up kit rpc queue router.");
+
+    (warp::get().and(list_queue(pg.clone())))
+        .or(warp::post().and(enqueue(pg.clone())))
+        .unify()
+        .or(warp::get().and(queued_request(pg.clone())))
+        .unify()
+        .boxed()
+}
+
+/// A request to queue.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "method", rename_all = "camelCase")]
+enum QueueRequest {
+    Version,
+    Uptime,
+    #[serde(rename_all = "camelCase")]
+    PeripheralCommand {
+        peripheral_id: i32,
+        command: serde_json::Value,
+    },
+}
+
+impl QueueRequest {
+    fn action(&self) -> crate::authorization::KitAction {
+        use crate::authorization::KitAction;
+
+        match self {
+            QueueRequest::Version => KitAction::RpcVersion,
+            QueueRequest::Uptime => KitAction::RpcUptime,
+            QueueRequest::PeripheralCommand { .. } => KitAction::RpcPeripheralCommand,
+        }
+    }
+}
+
+/// Handles the `GET /kit-rpc/{kitSerial}/queue?before={queuedRequestId}` route.
+///
+/// Lists the kit's queued RPC requests, from newest to oldest.
+pub fn list_queue(
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    #[derive(Deserialize)]
+    struct Page {
+        before: Option<i64>,
+    }
+
+    path!(String / "queue")
+        .and(authentication::option_by_token())
+        .and(pg.clone())
+        .and_then(
+            |kit_serial: String, user_id: Option<models::UserId>, conn: PgPooled| {
+                helpers::fut_permission_or_forbidden(
+                    conn,
+                    user_id,
+                    kit_serial,
+                    crate::authorization::KitAction::ViewRpcQueue,
+                )
+                .map_ok(|(_, _, kit)| kit)
+            },
+        )
+        .and(warp::query::query::<Page>())
+        .and(pg)
+        .and_then(|kit: models::Kit, page: Page, conn: PgPooled| async move {
+            let kit_serial = kit.serial.clone();
+            let queued_requests = helpers::threadpool_diesel_ok(move || {
+                models::QueuedKitRpcRequest::page_of_kit(&conn, &kit, page.before, PAGE_SIZE)
+            })
+            .await?;
+
+            let next_page_uri = queued_requests
+                .last()
+                .map(|last| format!("/kit-rpc/{}/queue?before={}", kit_serial, last.id));
+            let mut response_builder = ResponseBuilder::ok();
+            if let Some(next_page_uri) = next_page_uri {
+                response_builder = response_builder.next_page_uri(next_page_uri);
+            }
+            Ok::<_, Rejection>(
+                response_builder.body(
+                    queued_requests
+                        .into_iter()
+                        .map(views::QueuedKitRpcRequest::from)
+                        .collect::<Vec<_>>(),
+                ),
+            )
+        })
+}
+
+/// Handles the `POST /kit-rpc/{kitSerial}/queue` route.
+///
+/// Queues a request, to be delivered when the kit next shows activity.
+pub fn enqueue(
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    path!(String / "queue")
+        .and(authentication::option_by_token())
+        .and(helpers::deserialize())
+        .and(pg.clone())
+        .and_then(
+            |kit_serial: String,
+             user_id: Option<models::UserId>,
+             request: QueueRequest,
+             conn: PgPooled| {
+                helpers::fut_permission_or_forbidden(conn, user_id, kit_serial, request.action())
+                    .map_ok(move |(_, _, kit)| (kit, user_id, request))
+            },
+        )
+        .untuple_one()
+        .and(pg)
+        .and_then(
+            |kit: models::Kit,
+             user_id: Option<models::UserId>,
+             request: QueueRequest,
+             conn: PgPooled| {
+                helpers::threadpool_diesel_ok(move || {
+                    use models::QueuedKitRpcMethod;
+
+                    let (method, peripheral_id, command) = match request {
+                        QueueRequest::Version => (QueuedKitRpcMethod::Version, None, None),
+                        QueueRequest::Uptime => (QueuedKitRpcMethod::Uptime, None, None),
+                        QueueRequest::PeripheralCommand {
+                            peripheral_id,
+                            command,
+                        } => {
+                            if let Err(problem) = super::check_peripheral_command(
+                                &conn,
+                                &kit,
+                                peripheral_id,
+                                &command,
+                            )? {
+                                return Ok(Err(warp::reject::custom(problem)));
+                            }
+                            (
+                                QueuedKitRpcMethod::PeripheralCommand,
+                                Some(peripheral_id),
+                                Some(command),
+                            )
+                        }
+                    };
+
+                    let queued_request = models::NewQueuedKitRpcRequest::new(
+                        kit.get_id(),
+                        user_id.map(|user_id| user_id.0),
+                        method,
+                        peripheral_id,
+                        command,
+                    )
+                    .create(&conn)?;
+
+                    Ok(Ok(ResponseBuilder::created()
+                        .body(views::QueuedKitRpcRequest::from(queued_request))))
+                })
+                .map(helpers::flatten_result)
+            },
+        )
+}
+
+/// Handles the `GET /kit-rpc/{kitSerial}/queue/{queuedRequestId}` route.
+pub fn queued_request(
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    path!(String / "queue" / i64)
+        .and(authentication::option_by_token())
+        .and(pg.clone())
+        .and_then(
+            |kit_serial: String,
+             queued_request_id: i64,
+             user_id: Option<models::UserId>,
+             conn: PgPooled| {
+                helpers::fut_permission_or_forbidden(
+                    conn,
+                    user_id,
+                    kit_serial,
+                    crate::authorization::KitAction::ViewRpcQueue,
+                )
+                .map_ok(move |(_, _, kit)| (kit, queued_request_id))
+            },
+        )
+        .untuple_one()
+        .and(pg)
+        .and_then(
+            |kit: models::Kit, queued_request_id: i64, conn: PgPooled| async move {
+                let queued_request = helpers::threadpool_diesel_ok(move || {
+                    models::QueuedKitRpcRequest::by_id_of_kit(&conn, &kit, queued_request_id)
+                })
+                .await?;
+
+                match queued_request {
+                    Some(queued_request) => Ok(ResponseBuilder::ok()
+                        .body(views::QueuedKitRpcRequest::from(queued_request))),
+                    None => Err(warp::reject::custom(problem::NOT_FOUND)),
+                }
+            },
+        )
+}
@@ -0,0 +1,81 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// A position in a series of measurements ordered by datetime and id, used for keyset pagination.
+///
+/// Formatted as `{microseconds since the UNIX epoch}.{uuid}`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cursor {
+    pub datetime: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl Cursor {
+    pub fn new(datetime: DateTime<Utc>, id: Uuid) -> Self {
+        Self { datetime, id }
+    }
+
+    pub fn into_tuple(self) -> (DateTime<Utc>, Uuid) {
+        (self.datetime, self.id)
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let micros = self.datetime.timestamp() * 1_000_000
+            + i64::from(self.datetime.timestamp_subsec_micros());
+        write!(f, "{}.{}", micros, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '.');
+        let micros: i64 = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let id = Uuid::parse_str(parts.next().ok_or(())?).map_err(|_| ())?;
+
+        let datetime = Utc
+            .timestamp_opt(
+                micros.div_euclid(1_000_000),
+                micros.rem_euclid(1_000_000) as u32 * 1_000,
+            )
+            .single()
+            .ok_or(())?;
+
+        Ok(Self { datetime, id })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cursor;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn round_trip() {
+        let cursor = Cursor::new(
+            Utc.ymd(2020, 4, 12).and_hms_micro(13, 37, 0, 123_456),
+            "b0e1e7a4-3c4d-4c7a-9a43-2b0d5d0c11f2".parse().unwrap(),
+        );
+        let formatted = cursor.to_string();
+        assert_eq!(
+            formatted,
+            "1586698620123456.b0e1e7a4-3c4d-4c7a-9a43-2b0d5d0c11f2"
+        );
+        assert_eq!(formatted.parse::<Cursor>(), Ok(cursor));
+    }
+
+    #[test]
+    fn malformed() {
+        assert!("".parse::<Cursor>().is_err());
+        assert!("1586698620123456".parse::<Cursor>().is_err());
+        assert!("abc.b0e1e7a4-3c4d-4c7a-9a43-2b0d5d0c11f2"
+            .parse::<Cursor>()
+            .is_err());
+        assert!("1586698620123456.not-a-uuid".parse::<Cursor>().is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! Streams a kit's measurements as CSV or newline-delimited JSON.
+//!
+//! Measurements are fetched from the database page by page, keyed by a cursor, and each page is
+//! encoded and sent to the client before the next is fetched. A database connection is only held
+//! while fetching a page, such that slow clients do not exhaust the connection pool.
+
+use super::cursor::Cursor;
+use crate::{helpers, models, PgPool};
+
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::QueryResult;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// The number of measurements fetched from the database at a time.
+pub const EXPORT_PAGE_SIZE: i64 = 5000;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// The peripheral names and quantity types measurements are joined with.
+pub struct Lookup {
+    peripheral_names: HashMap<i32, String>,
+    quantity_types: HashMap<i32, models::QuantityType>,
+}
+
+impl Lookup {
+    pub fn fetch(conn: &PgConnection, kit_id: models::KitId) -> QueryResult<Self> {
+        let peripheral_names = models::Peripheral::peripherals_of_kit_id(conn, kit_id)?
+            .into_iter()
+            .map(|peripheral| (peripheral.id, peripheral.name))
+            .collect();
+        let quantity_types = models::QuantityType::all(conn)?
+            .into_iter()
+            .map(|quantity_type| (quantity_type.id, quantity_type))
+            .collect();
+
+        Ok(Self {
+            peripheral_names,
+            quantity_types,
+        })
+    }
+
+    fn peripheral_name(&self, peripheral_id: i32) -> String {
+        self.peripheral_names
+            .get(&peripheral_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get the physical quantity and unit of a quantity type.
+    fn quantity(&self, quantity_type_id: i32) -> (String, String) {
+        match self.quantity_types.get(&quantity_type_id) {
+            Some(quantity_type) => (
+                quantity_type.physical_quantity.clone(),
+                quantity_type.physical_unit.clone(),
+            ),
+            None => (String::new(), String::new()),
+        }
+    }
+}
+
+/// A measurement that can be exported.
+pub trait Exportable: Send + 'static {
+    type Row: Serialize;
+
+    /// The cursor pointing at this measurement.
+    fn cursor(&self) -> Cursor;
+
+    fn into_row(self, lookup: &Lookup) -> Self::Row;
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RawMeasurementRow {
+    id: Uuid,
+    kit_configuration_id: i32,
+    peripheral_id: i32,
+    peripheral_name: String,
+    quantity_type_id: i32,
+    physical_quantity: String,
+    physical_unit: String,
+    value: f64,
+    datetime: DateTime<Utc>,
+}
+
+impl Exportable for models::RawMeasurement {
+    type Row = RawMeasurementRow;
+
+    fn cursor(&self) -> Cursor {
+        Cursor::new(self.datetime, self.id)
+    }
+
+    fn into_row(self, lookup: &Lookup) -> Self::Row {
+        let (physical_quantity, physical_unit) = lookup.quantity(self.quantity_type_id);
+        RawMeasurementRow {
+            id: self.id,
+            kit_configuration_id: self.kit_configuration_id,
+            peripheral_id: self.peripheral_id,
+            peripheral_name: lookup.peripheral_name(self.peripheral_id),
+            quantity_type_id: self.quantity_type_id,
+            physical_quantity,
+            physical_unit,
+            value: self.value,
+            datetime: self.datetime,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AggregateMeasurementRow {
+    id: Uuid,
+    kit_configuration_id: i32,
+    peripheral_id: i32,
+    peripheral_name: String,
+    quantity_type_id: i32,
+    physical_quantity: String,
+    physical_unit: String,
+    aggregate_type: String,
+    value: f64,
+    datetime_start: DateTime<Utc>,
+    datetime_end: DateTime<Utc>,
+}
+
+impl Exportable for models::AggregateMeasurement {
+    type Row = AggregateMeasurementRow;
+
+    fn cursor(&self) -> Cursor {
+        Cursor::new(self.datetime_start, self.id)
+    }
+
+    fn into_row(self, lookup: &Lookup) -> Self::Row {
+        let (physical_quantity, physical_unit) = lookup.quantity(self.quantity_type_id);
+        AggregateMeasurementRow {
+            id: self.id,
+            kit_configuration_id: self.kit_configuration_id,
+            peripheral_id: self.peripheral_id,
+            peripheral_name: lookup.peripheral_name(self.peripheral_id),
+            quantity_type_id: self.quantity_type_id,
+            physical_quantity,
+            physical_unit,
+            aggregate_type: self.aggregate_type,
+            value: self.value,
+            datetime_start: self.datetime_start,
+            datetime_end: self.datetime_end,
+        }
+    }
+}
+
+/// Encode rows in the given format. For CSV, a header record is written if `header` is true.
+fn encode<R: Serialize>(
+    format: ExportFormat,
+    rows: &[R],
+    header: bool,
+) -> Result<Vec<u8>, BoxError> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(header)
+                .from_writer(vec![]);
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            Ok(writer.into_inner().map_err(|err| err.into_error())?)
+        }
+        ExportFormat::Ndjson => {
+            let mut buffer = vec![];
+            for row in rows {
+                serde_json::to_writer(&mut buffer, row)?;
+                buffer.push(b'\n');
+            }
+            Ok(buffer)
+        }
+    }
+}
+
+/// Create a response body streaming the measurements returned by `fetch`.
+///
+/// `fetch` is called with a connection from `pg_pool` and the cursor of the last measurement sent,
+/// and should return the next page of at most `EXPORT_PAGE_SIZE` measurements. The connection is
+/// returned to the pool before the page is sent. The stream ends when a page is not full. If
+/// fetching fails, the stream is aborted, such that the client does not mistake a partial export
+/// for a complete one.
+pub fn stream_body<M, F>(
+    pg_pool: PgPool,
+    lookup: Lookup,
+    format: ExportFormat,
+    fetch: F,
+) -> hyper::Body
+where
+    M: Exportable,
+    F: Fn(&PgConnection, Option<Cursor>) -> QueryResult<Vec<M>> + Send + Sync + 'static,
+{
+    let fetch = Arc::new(fetch);
+    let pages = stream::unfold(Some(None), move |state: Option<Option<Cursor>>| {
+        let fetch = fetch.clone();
+        let pg_pool = pg_pool.clone();
+        async move {
+            let after = state?;
+            let page = helpers::threadpool(move || {
+                let conn = pg_pool.get().map_err(BoxError::from)?;
+                fetch(&conn, after).map_err(BoxError::from)
+            })
+            .await;
+
+            match page {
+                Ok(page) if page.is_empty() => None,
+                Ok(page) => {
+                    let next = if (page.len() as i64) < EXPORT_PAGE_SIZE {
+                        None
+                    } else {
+                        Some(page.last().map(Exportable::cursor))
+                    };
+                    Some((Ok(page), next))
+                }
+                Err(err) => {
+                    error!("Error while exporting measurements: {:?}", err);
+                    Some((Err(err), None))
+                }
+            }
+        }
+    });
+
+    let mut header = true;
+    let chunks = pages.map(move |page: Result<Vec<M>, BoxError>| {
+        let rows: Vec<M::Row> = page?
+            .into_iter()
+            .map(|measurement| measurement.into_row(&lookup))
+            .collect();
+        let chunk = encode(format, &rows, header)?;
+        header = false;
+        Ok::<_, BoxError>(chunk)
+    });
+
+    hyper::Body::wrap_stream(chunks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, ExportFormat};
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Row {
+        peripheral_name: &'static str,
+        value: f64,
+    }
+
+    #[test]
+    fn encode_csv() {
+        let rows = [
+            Row {
+                peripheral_name: "Sensor, left",
+                value: 1.5,
+            },
+            Row {
+                peripheral_name: "Sensor \"B\"",
+                value: 2.0,
+            },
+        ];
+
+        let encoded = encode(ExportFormat::Csv, &rows, true).unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "peripheralName,value\n\"Sensor, left\",1.5\n\"Sensor \"\"B\"\"\",2.0\n"
+        );
+
+        let encoded = encode(ExportFormat::Csv, &rows[..1], false).unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "\"Sensor, left\",1.5\n"
+        );
+    }
+
+    #[test]
+    fn encode_ndjson() {
+        let rows = [
+            Row {
+                peripheral_name: "a",
+                value: 1.5,
+            },
+            Row {
+                peripheral_name: "b",
+                value: 2.0,
+            },
+        ];
+
+        let encoded = encode(ExportFormat::Ndjson, &rows, true).unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "{\"peripheralName\":\"a\",\"value\":1.5}\n{\"peripheralName\":\"b\",\"value\":2.0}\n"
+        );
+    }
+}
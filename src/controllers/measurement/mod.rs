@@ -1,17 +1,77 @@
+mod cursor;
+mod export;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use warp::{filters::BoxedFilter, Filter, Rejection};
 
 use crate::response::{Response, ResponseBuilder};
 use crate::PgPooled;
-use crate::{helpers, models, views};
+use crate::{helpers, models, problem, views};
+
+use cursor::Cursor;
+
+/// The maximum number of measurements returned per page.
+const PAGE_SIZE: i64 = 1000;
 
-pub fn router(pg: BoxedFilter<(crate::PgPooled,)>) -> BoxedFilter<(Response,)> {
+/// The maximum number of buckets a measurement series may span.
+const MAX_BUCKETS: i64 = 10_000;
+
+pub fn router(
+    pg_pool: crate::PgPool,
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> BoxedFilter<(Response,)> {
     //impl Filter<Extract = (Response,), Error = Rejection> + Clone {
     trace!("Setting up measurements router.");
 
-    aggregate_measurements(pg.clone()).boxed()
+    aggregate_measurements(pg.clone())
+        .or(raw_measurements(pg.clone()))
+        .unify()
+        .or(series(pg.clone()))
+        .unify()
+        .or(export_measurements(pg_pool, pg.clone()))
+        .unify()
+        .boxed()
 }
 
-/// Handles the `GET /measurements/aggregate-measurements?kitSerial={kitSerial}` route.
+/// Parse the cursor query parameter, if any. Rejects the request if it is malformed.
+fn parse_cursor(cursor: &Option<String>) -> Result<Option<Cursor>, Rejection> {
+    match cursor {
+        Some(cursor) => cursor.parse().map(Some).map_err(|_| {
+            warp::reject::custom(
+                problem::InvalidParameterReason::Other
+                    .singleton("cursor")
+                    .into_problem(),
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AggregateMeasurementsQuery {
+    kit_serial: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peripheral: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quantity_type: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggregate_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// Handles the `GET
+/// /measurements/aggregate-measurements?kitSerial={kitSerial}&from={from}&to={to}&peripheral={peripheralId}&quantityType={quantityTypeId}&aggregateType={aggregateType}&cursor={cursor}`
+/// route.
+///
+/// All parameters except `kitSerial` are optional. If `from` is not given, it defaults to five
+/// days ago.
 fn aggregate_measurements(
     pg: BoxedFilter<(crate::PgPooled,)>,
 ) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
@@ -24,17 +84,407 @@ fn aggregate_measurements(
             )
             .map(|_, _, kit| kit),
         )
+        .and(warp::query::query::<AggregateMeasurementsQuery>())
         .and(pg)
-        .and_then(|kit: models::Kit, conn: PgPooled| {
-            helpers::threadpool_diesel_ok(move || {
-                let aggregate_measurements: Vec<_> =
-                    models::AggregateMeasurement::recent_measurements(&conn, kit.get_id())?
+        .and_then(
+            |kit: models::Kit, mut query: AggregateMeasurementsQuery, conn: PgPooled| {
+                async move {
+                    let cursor = parse_cursor(&query.cursor)?;
+                    let from = query
+                        .from
+                        .unwrap_or_else(|| Utc::now() - chrono::Duration::days(5));
+                    query.from = Some(from);
+
+                    let filter = models::MeasurementFilter {
+                        from: Some(from),
+                        to: query.to,
+                        peripheral_id: query.peripheral.map(models::PeripheralId),
+                        quantity_type_id: query.quantity_type.map(models::QuantityTypeId),
+                    };
+                    let aggregate_type = query.aggregate_type.clone();
+
+                    let aggregate_measurements = helpers::threadpool_diesel_ok(move || {
+                        models::AggregateMeasurement::page(
+                            &conn,
+                            kit.get_id(),
+                            &filter,
+                            aggregate_type,
+                            cursor.map(Cursor::into_tuple),
+                            PAGE_SIZE,
+                        )
+                    })
+                    .await?;
+
+                    let mut response_builder = ResponseBuilder::ok();
+                    if let Some(last) = aggregate_measurements.last() {
+                        query.cursor = Some(Cursor::new(last.datetime_start, last.id).to_string());
+                        response_builder = response_builder.next_page_uri(format!(
+                            "/measurements/aggregate-measurements?{}",
+                            serde_urlencoded::to_string(&query).unwrap()
+                        ));
+                    }
+
+                    let aggregate_measurements: Vec<_> = aggregate_measurements
                         .into_iter()
                         .map(|aggregate_measurement| {
                             views::AggregateMeasurement::from(aggregate_measurement)
                         })
                         .collect();
-                Ok(ResponseBuilder::ok().body(aggregate_measurements))
-            })
+                    Ok::<_, Rejection>(response_builder.body(aggregate_measurements))
+                }
+            },
+        )
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RawMeasurementsQuery {
+    kit_serial: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peripheral: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quantity_type: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// Handles the `GET
+/// /measurements/raw-measurements?kitSerial={kitSerial}&from={from}&to={to}&peripheral={peripheralId}&quantityType={quantityTypeId}&cursor={cursor}`
+/// route.
+///
+/// All parameters except `kitSerial` are optional. If `from` is not given, it defaults to one day
+/// ago.
+fn raw_measurements(
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("raw-measurements"))
+        .and(
+            helpers::authorization_user_kit_from_query(
+                pg.clone(),
+                crate::authorization::KitAction::View,
+            )
+            .map(|_, _, kit| kit),
+        )
+        .and(warp::query::query::<RawMeasurementsQuery>())
+        .and(pg)
+        .and_then(
+            |kit: models::Kit, mut query: RawMeasurementsQuery, conn: PgPooled| {
+                async move {
+                    let cursor = parse_cursor(&query.cursor)?;
+                    let from = query
+                        .from
+                        .unwrap_or_else(|| Utc::now() - chrono::Duration::days(1));
+                    query.from = Some(from);
+
+                    let filter = models::MeasurementFilter {
+                        from: Some(from),
+                        to: query.to,
+                        peripheral_id: query.peripheral.map(models::PeripheralId),
+                        quantity_type_id: query.quantity_type.map(models::QuantityTypeId),
+                    };
+
+                    let raw_measurements = helpers::threadpool_diesel_ok(move || {
+                        models::RawMeasurement::page(
+                            &conn,
+                            kit.get_id(),
+                            &filter,
+                            cursor.map(Cursor::into_tuple),
+                            PAGE_SIZE,
+                        )
+                    })
+                    .await?;
+
+                    let mut response_builder = ResponseBuilder::ok();
+                    if let Some(last) = raw_measurements.last() {
+                        query.cursor = Some(Cursor::new(last.datetime, last.id).to_string());
+                        response_builder = response_builder.next_page_uri(format!(
+                            "/measurements/raw-measurements?{}",
+                            serde_urlencoded::to_string(&query).unwrap()
+                        ));
+                    }
+
+                    let raw_measurements: Vec<_> = raw_measurements
+                        .into_iter()
+                        .map(|raw_measurement| views::RawMeasurement::from(raw_measurement))
+                        .collect();
+                    Ok::<_, Rejection>(response_builder.body(raw_measurements))
+                }
+            },
+        )
+}
+
+/// Parse a bucket width such as `30s`, `5m`, `1h`, `1d` or `1w`.
+fn parse_bucket_width(width: &str) -> Option<chrono::Duration> {
+    let (unit_start, _) = width.char_indices().last()?;
+    let (amount, unit) = width.split_at(unit_start);
+    let amount: i64 = amount.parse().ok()?;
+    if amount <= 0 || amount > 1_000_000 {
+        return None;
+    }
+
+    match unit {
+        "s" => Some(chrono::Duration::seconds(amount)),
+        "m" => Some(chrono::Duration::minutes(amount)),
+        "h" => Some(chrono::Duration::hours(amount)),
+        "d" => Some(chrono::Duration::days(amount)),
+        "w" => Some(chrono::Duration::weeks(amount)),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SeriesQuery {
+    source: models::MeasurementSource,
+    bucket: String,
+    reducer: models::BucketReducer,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    peripheral: Option<i32>,
+    quantity_type: Option<i32>,
+    aggregate_type: Option<String>,
+}
+
+/// Handles the `GET
+/// /measurements/series?kitSerial={kitSerial}&source={source}&bucket={bucketWidth}&reducer={reducer}&from={from}&to={to}&peripheral={peripheralId}&quantityType={quantityTypeId}&aggregateType={aggregateType}`
+/// route.
+///
+/// Buckets the kit's raw or aggregate measurements and reduces each bucket to a single value,
+/// producing one series per peripheral and quantity type. `to` defaults to now, and `from`
+/// defaults to one day before `to`. Aggregate measurements must be selected by `aggregateType`.
+fn series(
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    use itertools::Itertools;
+    use std::collections::HashMap;
+
+    warp::get()
+        .and(warp::path!("series"))
+        .and(
+            helpers::authorization_user_kit_from_query(
+                pg.clone(),
+                crate::authorization::KitAction::View,
+            )
+            .map(|_, _, kit| kit),
+        )
+        .and(warp::query::query::<SeriesQuery>())
+        .and(pg)
+        .and_then(|kit: models::Kit, query: SeriesQuery, conn: PgPooled| {
+            async move {
+                let to = query.to.unwrap_or_else(Utc::now);
+                let from = query
+                    .from
+                    .unwrap_or_else(|| to - chrono::Duration::days(1));
+
+                let mut invalid_parameters = problem::InvalidParameters::new();
+                let bucket_width = match parse_bucket_width(&query.bucket) {
+                    Some(bucket_width) => bucket_width,
+                    None => {
+                        return Err(warp::reject::custom(
+                            problem::InvalidParameterReason::Other
+                                .singleton("bucket")
+                                .into_problem(),
+                        ))
+                    }
+                };
+                if from >= to {
+                    invalid_parameters.add("from", problem::InvalidParameterReason::Other);
+                } else if (to - from).num_seconds() / bucket_width.num_seconds() > MAX_BUCKETS {
+                    invalid_parameters.add("bucket", problem::InvalidParameterReason::Other);
+                }
+                if query.source == models::MeasurementSource::Aggregate
+                    && query.aggregate_type.is_none()
+                {
+                    invalid_parameters.add("aggregateType", problem::InvalidParameterReason::Other);
+                }
+                if !invalid_parameters.is_empty() {
+                    return Err(warp::reject::custom(invalid_parameters.into_problem()));
+                }
+
+                let filter = models::MeasurementFilter {
+                    from: Some(from),
+                    to: Some(to),
+                    peripheral_id: query.peripheral.map(models::PeripheralId),
+                    quantity_type_id: query.quantity_type.map(models::QuantityTypeId),
+                };
+
+                let (buckets, quantity_types) = helpers::threadpool_diesel_ok(move || {
+                    let buckets = models::MeasurementBucket::bucketed(
+                        &conn,
+                        kit.get_id(),
+                        query.source,
+                        &filter,
+                        query.aggregate_type,
+                        bucket_width,
+                        query.reducer,
+                    )?;
+                    let quantity_type_ids: Vec<i32> = buckets
+                        .iter()
+                        .map(|bucket| bucket.quantity_type_id)
+                        .unique()
+                        .collect();
+                    let quantity_types = models::QuantityType::by_ids(&conn, quantity_type_ids)?;
+                    Ok((buckets, quantity_types))
+                })
+                .await?;
+
+                let quantity_types: HashMap<i32, models::QuantityType> = quantity_types
+                    .into_iter()
+                    .map(|quantity_type| (quantity_type.id, quantity_type))
+                    .collect();
+
+                let mut series: Vec<views::MeasurementSeries> = vec![];
+                for ((peripheral_id, quantity_type_id), buckets) in &buckets
+                    .into_iter()
+                    .group_by(|bucket| (bucket.peripheral_id, bucket.quantity_type_id))
+                {
+                    let quantity_type = match quantity_types.get(&quantity_type_id) {
+                        Some(quantity_type) => quantity_type.clone(),
+                        None => continue,
+                    };
+                    series.push(views::MeasurementSeries {
+                        peripheral_id,
+                        quantity_type: views::QuantityType::from(quantity_type),
+                        values: buckets.map(|bucket| (bucket.bucket, bucket.value)).collect(),
+                    });
+                }
+
+                Ok(ResponseBuilder::ok().body(series))
+            }
         })
 }
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ExportQuery {
+    source: models::MeasurementSource,
+    format: export::ExportFormat,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    peripheral: Option<i32>,
+    quantity_type: Option<i32>,
+    aggregate_type: Option<String>,
+}
+
+/// Handles the `GET
+/// /measurements/export?kitSerial={kitSerial}&source={source}&format={format}&from={from}&to={to}&peripheral={peripheralId}&quantityType={quantityTypeId}&aggregateType={aggregateType}`
+/// route.
+///
+/// Streams all of the kit's raw or aggregate measurements matching the filters as CSV or
+/// newline-delimited JSON, joined with their peripheral names and quantity type units.
+fn export_measurements(
+    pg_pool: crate::PgPool,
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("export"))
+        .and(
+            helpers::authorization_user_kit_from_query(
+                pg.clone(),
+                crate::authorization::KitAction::View,
+            )
+            .map(|_, _, kit| kit),
+        )
+        .and(warp::query::query::<ExportQuery>())
+        .and(pg)
+        .and_then(
+            move |kit: models::Kit, query: ExportQuery, conn: PgPooled| {
+                let pg_pool = pg_pool.clone();
+                async move {
+                    let kit_id = kit.get_id();
+                    let filter = models::MeasurementFilter {
+                        from: query.from,
+                        to: query.to,
+                        peripheral_id: query.peripheral.map(models::PeripheralId),
+                        quantity_type_id: query.quantity_type.map(models::QuantityTypeId),
+                    };
+
+                    let lookup =
+                        helpers::threadpool_diesel_ok(move || export::Lookup::fetch(&conn, kit_id))
+                            .await?;
+
+                    let (source_name, body) = match query.source {
+                        models::MeasurementSource::Raw => (
+                            "raw",
+                            export::stream_body(
+                                pg_pool,
+                                lookup,
+                                query.format,
+                                move |conn, after| {
+                                    models::RawMeasurement::page(
+                                        conn,
+                                        kit_id,
+                                        &filter,
+                                        after.map(Cursor::into_tuple),
+                                        export::EXPORT_PAGE_SIZE,
+                                    )
+                                },
+                            ),
+                        ),
+                        models::MeasurementSource::Aggregate => {
+                            let aggregate_type = query.aggregate_type;
+                            (
+                                "aggregate",
+                                export::stream_body(
+                                    pg_pool,
+                                    lookup,
+                                    query.format,
+                                    move |conn, after| {
+                                        models::AggregateMeasurement::page(
+                                            conn,
+                                            kit_id,
+                                            &filter,
+                                            aggregate_type.clone(),
+                                            after.map(Cursor::into_tuple),
+                                            export::EXPORT_PAGE_SIZE,
+                                        )
+                                    },
+                                ),
+                            )
+                        }
+                    };
+
+                    let file_name = format!(
+                        "{}-{}-measurements.{}",
+                        kit.serial,
+                        source_name,
+                        query.format.extension()
+                    );
+                    Ok::<_, Rejection>(
+                        ResponseBuilder::ok()
+                            .header(
+                                "Content-Disposition".to_owned(),
+                                format!("attachment; filename=\"{}\"", file_name),
+                            )
+                            .stream(query.format.content_type().to_owned(), body),
+                    )
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn parse_bucket_width() {
+        use super::parse_bucket_width;
+        use chrono::Duration;
+
+        assert_eq!(parse_bucket_width("30s"), Some(Duration::seconds(30)));
+        assert_eq!(parse_bucket_width("5m"), Some(Duration::minutes(5)));
+        assert_eq!(parse_bucket_width("1h"), Some(Duration::hours(1)));
+        assert_eq!(parse_bucket_width("2d"), Some(Duration::days(2)));
+        assert_eq!(parse_bucket_width("1w"), Some(Duration::weeks(1)));
+        assert_eq!(parse_bucket_width(""), None);
+        assert_eq!(parse_bucket_width("m"), None);
+        assert_eq!(parse_bucket_width("0m"), None);
+        assert_eq!(parse_bucket_width("-5m"), None);
+        assert_eq!(parse_bucket_width("5y"), None);
+        assert_eq!(parse_bucket_width("5µ"), None);
+    }
+}
@@ -2,6 +2,7 @@ pub mod kit;
 pub mod kit_configuration;
 pub mod kit_rpc;
 pub mod me;
+pub mod mqtt_auth;
 pub mod peripheral_definition;
 pub mod permission;
 pub mod quantity_type;
@@ -0,0 +1,173 @@
+//! Authentication and access control of kits on the MQTT broker.
+//!
+//! These routes implement the HTTP backend of the mosquitto-go-auth plugin, with JSON parameters
+//! and the status response mode: a 200 response grants access, any other response denies it.
+//! Kits authenticate with their serial as username. The account of this server's own MQTT
+//! connection is not handled here, and should be configured in another backend of the plugin.
+
+use serde::Deserialize;
+use warp::{filters::BoxedFilter, Filter, Rejection};
+
+use crate::response::{Response, ResponseBuilder};
+use crate::PgPooled;
+use crate::{helpers, models, problem};
+
+pub fn router(pg: BoxedFilter<(crate::PgPooled,)>) -> BoxedFilter<(Response,)> {
+    //impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    trace!("Setting up MQTT auth router.");
+
+    user(pg.clone())
+        .or(superuser())
+        .unify()
+        .or(acl())
+        .unify()
+        .boxed()
+}
+
+/// The bit of the plugin's `acc` parameter signifying write access.
+const ACC_WRITE: i32 = 2;
+
+/// Whether the kit with the given serial may access a topic or topic filter. Kits may only
+/// access topics within `kit/{kitSerial}/#`.
+fn kit_may_access(kit_serial: &str, topic: &str) -> bool {
+    if kit_serial.is_empty() || kit_serial.contains(|c| c == '/' || c == '+' || c == '#') {
+        return false;
+    }
+
+    let mut levels = topic.splitn(3, '/');
+    levels.next() == Some("kit") && levels.next() == Some(kit_serial)
+}
+
+/// Whether the kit with the given serial may publish on a topic it may access. Kits may not
+/// publish notifications from the server, such as `kit/{kitSerial}/configuration/changed`.
+fn kit_may_write(kit_serial: &str, topic: &str) -> bool {
+    kit_may_access(kit_serial, topic)
+        && topic != format!("kit/{}/configuration/changed", kit_serial)
+}
+
+/// Handles the `POST /mqtt-auth/user` route.
+///
+/// Checks the kit serial and password.
+fn user(
+    pg: BoxedFilter<(crate::PgPooled,)>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    #[derive(Deserialize, Debug)]
+    struct Credentials {
+        username: String,
+        password: String,
+    }
+
+    warp::post()
+        .and(warp::path!("user"))
+        .and(helpers::deserialize())
+        .and(pg)
+        .and_then(|credentials: Credentials, conn: PgPooled| {
+            async move {
+                use astroplant_auth::hash;
+
+                let kit_serial = credentials.username.clone();
+                let authenticated = helpers::threadpool_diesel_ok(move || {
+                    let kit = models::Kit::by_serial(&conn, credentials.username)?;
+                    match kit {
+                        Some(kit) => Ok(hash::check_kit_password(
+                            &credentials.password,
+                            &kit.password_hash,
+                        )),
+                        None => {
+                            // Hash the provided password to help defeat timing attacks.
+                            hash::hash_kit_password(&credentials.password);
+                            Ok(false)
+                        }
+                    }
+                })
+                .await?;
+
+                if authenticated {
+                    trace!("Authenticated kit {} on the MQTT broker.", kit_serial);
+                    Ok(ResponseBuilder::ok().empty())
+                } else {
+                    debug!("Denied MQTT authentication of kit {}.", kit_serial);
+                    Err(warp::reject::custom(problem::FORBIDDEN))
+                }
+            }
+        })
+}
+
+/// Handles the `POST /mqtt-auth/superuser` route.
+///
+/// Kits are never superusers.
+fn superuser() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("superuser"))
+        .and_then(|| async { Err::<Response, _>(warp::reject::custom(problem::FORBIDDEN)) })
+}
+
+/// Handles the `POST /mqtt-auth/acl` route.
+///
+/// Checks whether a kit may publish or subscribe to a topic. The broker only performs this check
+/// for authenticated clients, so the username is the serial of an existing kit. `acc` is the
+/// requested access: 1 to read, 2 to write, 3 to read and write, and 4 to subscribe.
+fn acl() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    #[derive(Deserialize, Debug)]
+    struct AclCheck {
+        username: String,
+        topic: String,
+        acc: i32,
+    }
+
+    warp::post()
+        .and(warp::path!("acl"))
+        .and(helpers::deserialize())
+        .and_then(|acl_check: AclCheck| {
+            async move {
+                let allowed = if acl_check.acc & ACC_WRITE != 0 {
+                    kit_may_write(&acl_check.username, &acl_check.topic)
+                } else {
+                    kit_may_access(&acl_check.username, &acl_check.topic)
+                };
+                if allowed {
+                    Ok(ResponseBuilder::ok().empty())
+                } else {
+                    debug!(
+                        "Denied MQTT access of kit {} to topic {}.",
+                        acl_check.username, acl_check.topic
+                    );
+                    Err(warp::reject::custom(problem::FORBIDDEN))
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{kit_may_access, kit_may_write};
+
+    #[test]
+    fn kit_topics() {
+        assert!(kit_may_access("k_abc", "kit/k_abc/measurement/raw"));
+        assert!(kit_may_access("k_abc", "kit/k_abc/server-rpc/request"));
+        assert!(kit_may_access("k_abc", "kit/k_abc/#"));
+        assert!(kit_may_access("k_abc", "kit/k_abc"));
+    }
+
+    #[test]
+    fn other_topics() {
+        assert!(!kit_may_access("k_abc", "kit/k_def/measurement/raw"));
+        assert!(!kit_may_access("k_abc", "kit/k_abcd/measurement/raw"));
+        assert!(!kit_may_access("k_abc", "kit/+/measurement/raw"));
+        assert!(!kit_may_access("k_abc", "kit/#"));
+        assert!(!kit_may_access("k_abc", "#"));
+        assert!(!kit_may_access("k_abc", "server/k_abc"));
+        assert!(!kit_may_access("", "kit//measurement/raw"));
+        assert!(!kit_may_access("+", "kit/+/measurement/raw"));
+        assert!(!kit_may_access("#", "kit/#"));
+    }
+
+    #[test]
+    fn server_notification_topics() {
+        assert!(kit_may_access("k_abc", "kit/k_abc/configuration/changed"));
+        assert!(!kit_may_write("k_abc", "kit/k_abc/configuration/changed"));
+        assert!(kit_may_write("k_abc", "kit/k_abc/measurement/raw"));
+        assert!(!kit_may_write("k_abc", "kit/k_def/measurement/raw"));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::schema::dead_letters;
+
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{Identifiable, QueryResult, Queryable};
+
+use super::{Kit, KitId};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Identifiable)]
+#[table_name = "dead_letters"]
+pub struct DeadLetterId(#[column_name = "id"] pub i64);
+
+/// An MQTT message that could not be handled.
+#[derive(Clone, Debug, PartialEq, Queryable, Identifiable, Associations)]
+#[belongs_to(parent = "Kit", foreign_key = "kit_id")]
+#[belongs_to(parent = "KitId", foreign_key = "kit_id")]
+pub struct DeadLetter {
+    pub id: i64,
+    pub kit_serial: Option<String>,
+    pub kit_id: Option<i32>,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub error_kind: String,
+    pub error_message: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    /// Get a page of the kit's dead letters, ordered from newest to oldest. If `before` is given,
+    /// only dead letters older than that dead letter are returned.
+    pub fn page_of_kit(
+        conn: &PgConnection,
+        kit: &Kit,
+        before: Option<i64>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        let q = DeadLetter::belonging_to(kit)
+            .order(dead_letters::columns::id.desc())
+            .limit(limit);
+        if let Some(before) = before {
+            q.filter(dead_letters::columns::id.lt(before)).load(conn)
+        } else {
+            q.load(conn)
+        }
+    }
+
+    /// Delete dead letters received before `received_before`, and delete all but the newest
+    /// `keep_per_kit` dead letters of each kit.
+    /// Returns the amount of deleted dead letters.
+    pub fn prune(
+        conn: &PgConnection,
+        received_before: DateTime<Utc>,
+        keep_per_kit: i64,
+    ) -> QueryResult<usize> {
+        use dead_letters::dsl;
+        use diesel::sql_types::Int8;
+
+        let mut deleted =
+            diesel::delete(dsl::dead_letters.filter(dsl::received_at.lt(received_before)))
+                .execute(conn)?;
+
+        deleted += diesel::sql_query(
+            "DELETE FROM dead_letters WHERE id IN ( \
+             SELECT id FROM ( \
+             SELECT id, row_number() OVER (PARTITION BY kit_id ORDER BY id DESC) AS kit_rank \
+             FROM dead_letters \
+             WHERE kit_id IS NOT NULL \
+             ) AS ranked \
+             WHERE kit_rank > $1 \
+             )",
+        )
+        .bind::<Int8, _>(keep_per_kit)
+        .execute(conn)?;
+
+        Ok(deleted)
+    }
+
+    pub fn get_id(&self) -> DeadLetterId {
+        DeadLetterId(self.id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Insertable)]
+#[table_name = "dead_letters"]
+pub struct NewDeadLetter {
+    pub kit_serial: Option<String>,
+    pub kit_id: Option<i32>,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub error_kind: String,
+    pub error_message: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl NewDeadLetter {
+    pub fn create(&self, conn: &PgConnection) -> QueryResult<DeadLetter> {
+        use crate::schema::dead_letters::dsl::*;
+
+        diesel::insert_into(dead_letters)
+            .values(self)
+            .get_result::<DeadLetter>(conn)
+    }
+}
@@ -1,6 +1,7 @@
 use crate::schema::kits;
 
 use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::{Identifiable, QueryResult, Queryable};
@@ -22,6 +23,10 @@ pub struct Kit {
     pub longitude: Option<BigDecimal>,
     pub privacy_public_dashboard: bool,
     pub privacy_show_on_map: bool,
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_measurement_at: Option<DateTime<Utc>>,
+    pub clock_skew_millis: Option<i64>,
 }
 
 impl Kit {
@@ -100,6 +105,45 @@ impl UpdateKit {
     }
 }
 
+/// An update of a kit's presence. Fields that are `None` are not updated.
+#[derive(Clone, Debug, PartialEq, AsChangeset)]
+#[table_name = "kits"]
+pub struct UpdateKitPresence {
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_measurement_at: Option<DateTime<Utc>>,
+    pub clock_skew_millis: Option<i64>,
+}
+
+impl UpdateKitPresence {
+    /// Update the presence of the kit with the given serial, unless the kit was last seen later
+    /// than this update's `last_seen`, e.g. by another server instance. The latest sighting wins.
+    /// Returns the amount of updated kits.
+    pub fn update_by_serial(&self, conn: &PgConnection, serial: &str) -> QueryResult<usize> {
+        let kit = kits::table.filter(kits::columns::serial.eq(serial));
+        match self.last_seen {
+            Some(last_seen) => diesel::update(
+                kit.filter(
+                    kits::columns::last_seen
+                        .is_null()
+                        .or(kits::columns::last_seen.le(last_seen)),
+                ),
+            )
+            .set(self)
+            .execute(conn),
+            None => diesel::update(kit).set(self).execute(conn),
+        }
+    }
+
+    /// Mark all kits as offline.
+    /// Returns the amount of updated kits.
+    pub fn all_offline(conn: &PgConnection) -> QueryResult<usize> {
+        diesel::update(kits::table.filter(kits::columns::online.eq(true)))
+            .set(kits::columns::online.eq(false))
+            .execute(conn)
+    }
+}
+
 #[derive(Insertable, Debug, Default, Validate)]
 #[table_name = "kits"]
 pub struct NewKit {
@@ -1,9 +1,10 @@
-use crate::schema::aggregate_measurements;
+use crate::schema::{aggregate_measurements, raw_measurements};
 
 use chrono::{DateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::{Identifiable, QueryResult, Queryable};
+use serde::Deserialize;
 use uuid::Uuid;
 use validator::Validate;
 
@@ -15,6 +16,139 @@ use super::{
     QuantityType, QuantityTypeId,
 };
 
+/// Filters to select measurements by. Unset filters match all measurements.
+#[derive(Clone, Debug, Default)]
+pub struct MeasurementFilter {
+    /// Only match measurements at or after this datetime.
+    pub from: Option<DateTime<Utc>>,
+    /// Only match measurements before this datetime.
+    pub to: Option<DateTime<Utc>>,
+    pub peripheral_id: Option<PeripheralId>,
+    pub quantity_type_id: Option<QuantityTypeId>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Identifiable)]
+#[table_name = "raw_measurements"]
+pub struct RawMeasurementId(#[column_name = "id"] pub Uuid);
+
+#[derive(Clone, Debug, PartialEq, Queryable, Identifiable, Associations)]
+#[belongs_to(parent = "Kit", foreign_key = "kit_id")]
+#[belongs_to(parent = "KitId", foreign_key = "kit_id")]
+#[belongs_to(parent = "KitConfiguration", foreign_key = "kit_configuration_id")]
+#[belongs_to(parent = "KitConfigurationId", foreign_key = "kit_configuration_id")]
+#[belongs_to(parent = "Peripheral", foreign_key = "peripheral_id")]
+#[belongs_to(parent = "PeripheralId", foreign_key = "peripheral_id")]
+#[belongs_to(parent = "QuantityType", foreign_key = "quantity_type_id")]
+#[belongs_to(parent = "QuantityTypeId", foreign_key = "quantity_type_id")]
+pub struct RawMeasurement {
+    pub id: Uuid,
+    pub peripheral_id: i32,
+    pub kit_id: i32,
+    pub kit_configuration_id: i32,
+    pub quantity_type_id: i32,
+    pub value: f64,
+    pub datetime: DateTime<Utc>,
+}
+
+impl RawMeasurement {
+    pub fn by_id(
+        conn: &PgConnection,
+        raw_measurement_id: RawMeasurementId,
+    ) -> QueryResult<Option<Self>> {
+        raw_measurements::table
+            .find(&raw_measurement_id.0)
+            .first(conn)
+            .optional()
+    }
+
+    /// Get a page of the kit's raw measurements matching the filter, ordered by datetime and id.
+    /// If `after` is given, only measurements after that datetime and id are returned.
+    pub fn page(
+        conn: &PgConnection,
+        kit_id: KitId,
+        filter: &MeasurementFilter,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        use raw_measurements::dsl;
+
+        let mut q = raw_measurements::table
+            .filter(dsl::kit_id.eq(kit_id.0))
+            .into_boxed();
+        if let Some(from) = filter.from {
+            q = q.filter(dsl::datetime.ge(from));
+        }
+        if let Some(to) = filter.to {
+            q = q.filter(dsl::datetime.lt(to));
+        }
+        if let Some(peripheral_id) = filter.peripheral_id {
+            q = q.filter(dsl::peripheral_id.eq(peripheral_id.0));
+        }
+        if let Some(quantity_type_id) = filter.quantity_type_id {
+            q = q.filter(dsl::quantity_type_id.eq(quantity_type_id.0));
+        }
+        if let Some((datetime, id)) = after {
+            q = q.filter(
+                dsl::datetime
+                    .gt(datetime)
+                    .or(dsl::datetime.eq(datetime).and(dsl::id.gt(id))),
+            );
+        }
+
+        q.order((dsl::datetime.asc(), dsl::id.asc()))
+            .limit(limit)
+            .load(conn)
+    }
+
+    pub fn get_id(&self) -> RawMeasurementId {
+        RawMeasurementId(self.id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Insertable)]
+#[table_name = "raw_measurements"]
+pub struct NewRawMeasurement {
+    pub id: Uuid,
+    pub peripheral_id: i32,
+    pub kit_id: i32,
+    pub kit_configuration_id: i32,
+    pub quantity_type_id: i32,
+    pub value: f64,
+    pub datetime: DateTime<Utc>,
+}
+
+impl NewRawMeasurement {
+    pub fn new(
+        kit_id: KitId,
+        kit_configuration_id: KitConfigurationId,
+        peripheral_id: PeripheralId,
+        quantity_type_id: QuantityTypeId,
+        value: f64,
+        datetime: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            peripheral_id: peripheral_id.0,
+            kit_id: kit_id.0,
+            kit_configuration_id: kit_configuration_id.0,
+            quantity_type_id: quantity_type_id.0,
+            value,
+            datetime,
+        }
+    }
+
+    /// Insert a batch of raw measurements.
+    /// Returns the amount of inserted measurements.
+    pub fn create_batch(conn: &PgConnection, batch: &[Self]) -> QueryResult<usize> {
+        use crate::schema::raw_measurements::dsl::*;
+
+        diesel::insert_into(raw_measurements)
+            .values(batch)
+            .on_conflict_do_nothing()
+            .execute(conn)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Identifiable)]
 #[table_name = "aggregate_measurements"]
 pub struct AggregateMeasurementId(#[column_name = "id"] pub Uuid);
@@ -51,14 +185,47 @@ impl AggregateMeasurement {
             .optional()
     }
 
-    pub fn recent_measurements(conn: &PgConnection, kit_id: KitId) -> QueryResult<Vec<Self>> {
-        aggregate_measurements::table
-            .filter(aggregate_measurements::columns::kit_id.eq(kit_id.0))
-            .filter(
-                aggregate_measurements::columns::datetime_start
-                    .gt(chrono::Utc::now() - chrono::Duration::days(5)),
-            )
-            .order(aggregate_measurements::dsl::datetime_start)
+    /// Get a page of the kit's aggregate measurements matching the filter, ordered by start
+    /// datetime and id. If `after` is given, only measurements after that start datetime and id
+    /// are returned.
+    pub fn page(
+        conn: &PgConnection,
+        kit_id: KitId,
+        filter: &MeasurementFilter,
+        aggregate_type: Option<String>,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        use aggregate_measurements::dsl;
+
+        let mut q = aggregate_measurements::table
+            .filter(dsl::kit_id.eq(kit_id.0))
+            .into_boxed();
+        if let Some(from) = filter.from {
+            q = q.filter(dsl::datetime_start.ge(from));
+        }
+        if let Some(to) = filter.to {
+            q = q.filter(dsl::datetime_start.lt(to));
+        }
+        if let Some(peripheral_id) = filter.peripheral_id {
+            q = q.filter(dsl::peripheral_id.eq(peripheral_id.0));
+        }
+        if let Some(quantity_type_id) = filter.quantity_type_id {
+            q = q.filter(dsl::quantity_type_id.eq(quantity_type_id.0));
+        }
+        if let Some(aggregate_type) = aggregate_type {
+            q = q.filter(dsl::aggregate_type.eq(aggregate_type));
+        }
+        if let Some((datetime_start, id)) = after {
+            q = q.filter(
+                dsl::datetime_start
+                    .gt(datetime_start)
+                    .or(dsl::datetime_start.eq(datetime_start).and(dsl::id.gt(id))),
+            );
+        }
+
+        q.order((dsl::datetime_start.asc(), dsl::id.asc()))
+            .limit(limit)
             .load(conn)
     }
 
@@ -66,3 +233,165 @@ impl AggregateMeasurement {
         AggregateMeasurementId(self.id)
     }
 }
+
+#[derive(Clone, Debug, PartialEq, Insertable)]
+#[table_name = "aggregate_measurements"]
+pub struct NewAggregateMeasurement {
+    pub id: Uuid,
+    pub peripheral_id: i32,
+    pub kit_id: i32,
+    pub kit_configuration_id: i32,
+    pub quantity_type_id: i32,
+    pub aggregate_type: String,
+    pub value: f64,
+    pub datetime_start: DateTime<Utc>,
+    pub datetime_end: DateTime<Utc>,
+}
+
+impl NewAggregateMeasurement {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        kit_id: KitId,
+        kit_configuration_id: KitConfigurationId,
+        peripheral_id: PeripheralId,
+        quantity_type_id: QuantityTypeId,
+        aggregate_type: String,
+        value: f64,
+        datetime_start: DateTime<Utc>,
+        datetime_end: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            peripheral_id: peripheral_id.0,
+            kit_id: kit_id.0,
+            kit_configuration_id: kit_configuration_id.0,
+            quantity_type_id: quantity_type_id.0,
+            aggregate_type,
+            value,
+            datetime_start,
+            datetime_end,
+        }
+    }
+
+    /// Insert a batch of aggregate measurements.
+    /// Returns the amount of inserted measurements.
+    pub fn create_batch(conn: &PgConnection, batch: &[Self]) -> QueryResult<usize> {
+        use crate::schema::aggregate_measurements::dsl::*;
+
+        diesel::insert_into(aggregate_measurements)
+            .values(batch)
+            .on_conflict_do_nothing()
+            .execute(conn)
+    }
+}
+
+/// The measurements to compute a series over.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MeasurementSource {
+    Raw,
+    Aggregate,
+}
+
+/// How the measurements in a bucket are reduced to a single value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BucketReducer {
+    Mean,
+    Min,
+    Max,
+    Last,
+    Count,
+}
+
+impl BucketReducer {
+    fn sql(self, datetime_column: &str) -> String {
+        use BucketReducer::*;
+
+        match self {
+            Mean => "avg(value)".to_owned(),
+            Min => "min(value)".to_owned(),
+            Max => "max(value)".to_owned(),
+            Last => format!("(array_agg(value ORDER BY {} DESC))[1]", datetime_column),
+            Count => "count(*)::float8".to_owned(),
+        }
+    }
+}
+
+/// The reduced value of the measurements of one peripheral and quantity type within a time
+/// bucket.
+#[derive(Clone, Debug, PartialEq, QueryableByName)]
+pub struct MeasurementBucket {
+    #[sql_type = "diesel::sql_types::Int4"]
+    pub peripheral_id: i32,
+    #[sql_type = "diesel::sql_types::Int4"]
+    pub quantity_type_id: i32,
+    /// The start of the bucket.
+    #[sql_type = "diesel::sql_types::Timestamptz"]
+    pub bucket: DateTime<Utc>,
+    #[sql_type = "diesel::sql_types::Float8"]
+    pub value: f64,
+}
+
+impl MeasurementBucket {
+    /// Divide the kit's measurements matching the filter into buckets of the given width, and
+    /// reduce each bucket per peripheral and quantity type. Buckets are aligned to the UNIX epoch.
+    ///
+    /// Aggregate measurements are bucketed by their start datetime, and are only matched by
+    /// `aggregate_type` if it is given. Results are ordered by peripheral, quantity type and
+    /// bucket.
+    pub fn bucketed(
+        conn: &PgConnection,
+        kit_id: KitId,
+        source: MeasurementSource,
+        filter: &MeasurementFilter,
+        aggregate_type: Option<String>,
+        bucket_width: chrono::Duration,
+        reducer: BucketReducer,
+    ) -> QueryResult<Vec<Self>> {
+        use diesel::sql_types::{Float8, Int4, Nullable, Text, Timestamptz};
+
+        let (table, datetime_column) = match source {
+            MeasurementSource::Raw => ("raw_measurements", "datetime"),
+            MeasurementSource::Aggregate => ("aggregate_measurements", "datetime_start"),
+        };
+
+        let mut sql = format!(
+            "SELECT peripheral_id, quantity_type_id, \
+             to_timestamp(floor(extract(epoch FROM {datetime}) / $1) * $1) AS bucket, \
+             {reducer} AS value \
+             FROM {table} \
+             WHERE kit_id = $2 \
+             AND ($3::timestamptz IS NULL OR {datetime} >= $3) \
+             AND ($4::timestamptz IS NULL OR {datetime} < $4) \
+             AND ($5::int4 IS NULL OR peripheral_id = $5) \
+             AND ($6::int4 IS NULL OR quantity_type_id = $6)",
+            datetime = datetime_column,
+            reducer = reducer.sql(datetime_column),
+            table = table,
+        );
+        if source == MeasurementSource::Aggregate {
+            sql.push_str(" AND ($7::text IS NULL OR aggregate_type = $7)");
+        }
+        sql.push_str(
+            " GROUP BY peripheral_id, quantity_type_id, bucket \
+             ORDER BY peripheral_id, quantity_type_id, bucket",
+        );
+
+        let bucket_width_secs = bucket_width.num_milliseconds() as f64 / 1000.0;
+        let query = diesel::sql_query(sql)
+            .bind::<Float8, _>(bucket_width_secs)
+            .bind::<Int4, _>(kit_id.0)
+            .bind::<Nullable<Timestamptz>, _>(filter.from)
+            .bind::<Nullable<Timestamptz>, _>(filter.to)
+            .bind::<Nullable<Int4>, _>(filter.peripheral_id.map(|id| id.0))
+            .bind::<Nullable<Int4>, _>(filter.quantity_type_id.map(|id| id.0));
+
+        match source {
+            MeasurementSource::Raw => query.load(conn),
+            MeasurementSource::Aggregate => {
+                query.bind::<Nullable<Text>, _>(aggregate_type).load(conn)
+            }
+        }
+    }
+}
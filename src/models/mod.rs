@@ -1,5 +1,5 @@
 mod kit;
-pub use kit::{Kit, KitId, NewKit, UpdateKit};
+pub use kit::{Kit, KitId, NewKit, UpdateKit, UpdateKitPresence};
 
 mod user;
 pub use user::{NewUser, User, UserId};
@@ -25,4 +25,22 @@ mod peripheral_definition_expected_quantity_type;
 pub use peripheral_definition_expected_quantity_type::PeripheralDefinitionExpectedQuantityType;
 
 mod measurement;
-pub use measurement::{AggregateMeasurement, AggregateMeasurementId};
+pub use measurement::{
+    AggregateMeasurement, AggregateMeasurementId, BucketReducer, MeasurementBucket,
+    MeasurementFilter, MeasurementSource, NewAggregateMeasurement, NewRawMeasurement,
+    RawMeasurement, RawMeasurementId,
+};
+
+mod quarantined_measurement;
+pub use quarantined_measurement::{
+    NewQuarantinedMeasurement, QuarantineReason, QuarantinedMeasurement, QuarantinedMeasurementId,
+};
+
+mod dead_letter;
+pub use dead_letter::{DeadLetter, DeadLetterId, NewDeadLetter};
+
+mod queued_kit_rpc_request;
+pub use queued_kit_rpc_request::{
+    NewQueuedKitRpcRequest, QueuedKitRpcMethod, QueuedKitRpcRequest, QueuedKitRpcRequestId,
+    QueuedKitRpcRequestStatus,
+};
@@ -100,6 +100,7 @@ impl Peripheral {
     ) -> QueryResult<Vec<(Self, PeripheralDefinition)>> {
         Peripheral::belonging_to(&kit_configuration_id)
             .inner_join(peripheral_definitions::table)
+            .order(peripherals::columns::id.asc())
             .load(conn)
     }
 
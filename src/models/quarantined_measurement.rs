@@ -0,0 +1,136 @@
+use crate::schema::quarantined_measurements;
+
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{Identifiable, QueryResult, Queryable};
+use uuid::Uuid;
+
+use super::{Kit, KitId};
+
+/// The reason a measurement was quarantined.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum QuarantineReason {
+    /// There is no kit with the measurement's kit serial.
+    UnknownKit,
+    /// The kit has no active configuration.
+    NoActiveConfiguration,
+    /// The peripheral is not part of the kit's active configuration.
+    UnknownPeripheral,
+    /// The peripheral's definition does not declare the quantity type.
+    UnexpectedQuantityType,
+    /// The measurement's datetime is out of range, or the aggregate measurement ends before it
+    /// starts.
+    InvalidDatetime,
+    /// The database rejected the measurement, e.g. because its kit was deleted.
+    Rejected,
+}
+
+impl QuarantineReason {
+    pub fn as_str(self) -> &'static str {
+        use QuarantineReason::*;
+
+        match self {
+            UnknownKit => "unknownKit",
+            NoActiveConfiguration => "noActiveConfiguration",
+            UnknownPeripheral => "unknownPeripheral",
+            UnexpectedQuantityType => "unexpectedQuantityType",
+            InvalidDatetime => "invalidDatetime",
+            Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Identifiable)]
+#[table_name = "quarantined_measurements"]
+pub struct QuarantinedMeasurementId(#[column_name = "id"] pub Uuid);
+
+#[derive(Clone, Debug, PartialEq, Queryable, Identifiable, Associations)]
+#[belongs_to(parent = "Kit", foreign_key = "kit_id")]
+#[belongs_to(parent = "KitId", foreign_key = "kit_id")]
+pub struct QuarantinedMeasurement {
+    pub id: Uuid,
+    pub kit_serial: String,
+    pub kit_id: Option<i32>,
+    /// Either "raw" or "aggregate".
+    pub measurement_type: String,
+    pub peripheral_id: i32,
+    pub quantity_type_id: i32,
+    pub aggregate_type: Option<String>,
+    pub value: f64,
+    /// The datetime of a raw measurement, or the start datetime of an aggregate measurement.
+    pub datetime: Option<DateTime<Utc>>,
+    pub datetime_end: Option<DateTime<Utc>>,
+    pub reason: String,
+    pub received_at: DateTime<Utc>,
+}
+
+impl QuarantinedMeasurement {
+    /// Delete quarantined measurements received before `received_before`, and delete all but the
+    /// newest `keep_per_kit` quarantined measurements of each kit serial.
+    /// Returns the amount of deleted quarantined measurements.
+    pub fn prune(
+        conn: &PgConnection,
+        received_before: DateTime<Utc>,
+        keep_per_kit: i64,
+    ) -> QueryResult<usize> {
+        use diesel::sql_types::Int8;
+        use quarantined_measurements::dsl;
+
+        let mut deleted = diesel::delete(
+            dsl::quarantined_measurements.filter(dsl::received_at.lt(received_before)),
+        )
+        .execute(conn)?;
+
+        // Measurements of unknown kits have no kit id; they are counted by kit serial.
+        deleted += diesel::sql_query(
+            "DELETE FROM quarantined_measurements WHERE id IN ( \
+             SELECT id FROM ( \
+             SELECT id, row_number() OVER ( \
+             PARTITION BY kit_serial ORDER BY received_at DESC \
+             ) AS kit_rank \
+             FROM quarantined_measurements \
+             ) AS ranked \
+             WHERE kit_rank > $1 \
+             )",
+        )
+        .bind::<Int8, _>(keep_per_kit)
+        .execute(conn)?;
+
+        Ok(deleted)
+    }
+
+    pub fn get_id(&self) -> QuarantinedMeasurementId {
+        QuarantinedMeasurementId(self.id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Insertable)]
+#[table_name = "quarantined_measurements"]
+pub struct NewQuarantinedMeasurement {
+    pub id: Uuid,
+    pub kit_serial: String,
+    pub kit_id: Option<i32>,
+    pub measurement_type: String,
+    pub peripheral_id: i32,
+    pub quantity_type_id: i32,
+    pub aggregate_type: Option<String>,
+    pub value: f64,
+    pub datetime: Option<DateTime<Utc>>,
+    pub datetime_end: Option<DateTime<Utc>>,
+    pub reason: String,
+    pub received_at: DateTime<Utc>,
+}
+
+impl NewQuarantinedMeasurement {
+    /// Insert a batch of quarantined measurements.
+    /// Returns the amount of inserted measurements.
+    pub fn create_batch(conn: &PgConnection, batch: &[Self]) -> QueryResult<usize> {
+        use crate::schema::quarantined_measurements::dsl::*;
+
+        diesel::insert_into(quarantined_measurements)
+            .values(batch)
+            .on_conflict_do_nothing()
+            .execute(conn)
+    }
+}
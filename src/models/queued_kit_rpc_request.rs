@@ -0,0 +1,275 @@
+use crate::schema::queued_kit_rpc_requests;
+
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{Identifiable, QueryResult, Queryable};
+
+use super::{Kit, KitId};
+
+/// The kit RPC methods that can be queued.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum QueuedKitRpcMethod {
+    Version,
+    Uptime,
+    PeripheralCommand,
+}
+
+impl QueuedKitRpcMethod {
+    pub fn as_str(self) -> &'static str {
+        use QueuedKitRpcMethod::*;
+
+        match self {
+            Version => "version",
+            Uptime => "uptime",
+            PeripheralCommand => "peripheralCommand",
+        }
+    }
+
+    pub fn parse(method: &str) -> Option<Self> {
+        use QueuedKitRpcMethod::*;
+
+        match method {
+            "version" => Some(Version),
+            "uptime" => Some(Uptime),
+            "peripheralCommand" => Some(PeripheralCommand),
+            _ => None,
+        }
+    }
+}
+
+/// The status of a queued kit RPC request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum QueuedKitRpcRequestStatus {
+    /// The request waits for the kit to show activity.
+    Pending,
+    /// The request has been sent to the kit, and awaits the kit's response.
+    Delivering,
+    /// The kit responded successfully.
+    Succeeded,
+    /// The kit responded with an error, or did not respond after several attempts.
+    Failed,
+}
+
+impl QueuedKitRpcRequestStatus {
+    pub fn as_str(self) -> &'static str {
+        use QueuedKitRpcRequestStatus::*;
+
+        match self {
+            Pending => "pending",
+            Delivering => "delivering",
+            Succeeded => "succeeded",
+            Failed => "failed",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Identifiable)]
+#[table_name = "queued_kit_rpc_requests"]
+pub struct QueuedKitRpcRequestId(#[column_name = "id"] pub i64);
+
+/// A kit RPC request that is delivered when the kit next shows activity.
+#[derive(Clone, Debug, PartialEq, Queryable, Identifiable, Associations)]
+#[belongs_to(parent = "Kit", foreign_key = "kit_id")]
+#[belongs_to(parent = "KitId", foreign_key = "kit_id")]
+pub struct QueuedKitRpcRequest {
+    pub id: i64,
+    pub kit_id: i32,
+    pub user_id: Option<i32>,
+    pub method: String,
+    pub peripheral_id: Option<i32>,
+    pub command: Option<serde_json::Value>,
+    pub status: String,
+    pub attempts: i32,
+    pub response: Option<serde_json::Value>,
+    pub error: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// The id of the server instance that last claimed the request for delivery.
+    pub claimed_by: Option<String>,
+}
+
+impl QueuedKitRpcRequest {
+    pub fn by_id_of_kit(conn: &PgConnection, kit: &Kit, id: i64) -> QueryResult<Option<Self>> {
+        QueuedKitRpcRequest::belonging_to(kit)
+            .filter(queued_kit_rpc_requests::columns::id.eq(id))
+            .first(conn)
+            .optional()
+    }
+
+    /// Get a page of the kit's queued requests, ordered from newest to oldest. If `before` is
+    /// given, only requests queued before that request are returned.
+    pub fn page_of_kit(
+        conn: &PgConnection,
+        kit: &Kit,
+        before: Option<i64>,
+        limit: i64,
+    ) -> QueryResult<Vec<Self>> {
+        let q = QueuedKitRpcRequest::belonging_to(kit)
+            .order(queued_kit_rpc_requests::columns::id.desc())
+            .limit(limit);
+        if let Some(before) = before {
+            q.filter(queued_kit_rpc_requests::columns::id.lt(before))
+                .load(conn)
+        } else {
+            q.load(conn)
+        }
+    }
+
+    /// Get the kit's pending requests, ordered from oldest to newest.
+    pub fn pending_of_kit_id(conn: &PgConnection, kit_id: KitId) -> QueryResult<Vec<Self>> {
+        use queued_kit_rpc_requests::dsl;
+
+        dsl::queued_kit_rpc_requests
+            .filter(dsl::kit_id.eq(kit_id.0))
+            .filter(dsl::status.eq(QueuedKitRpcRequestStatus::Pending.as_str()))
+            .order(dsl::id.asc())
+            .load(conn)
+    }
+
+    /// Claim the request for delivery by the server instance with id `instance_id`, marking it as
+    /// delivering. Returns `None` if the request is no longer pending.
+    pub fn claim(
+        conn: &PgConnection,
+        id: QueuedKitRpcRequestId,
+        instance_id: &str,
+        now: DateTime<Utc>,
+    ) -> QueryResult<Option<Self>> {
+        use queued_kit_rpc_requests::dsl;
+
+        diesel::update(
+            dsl::queued_kit_rpc_requests
+                .filter(dsl::id.eq(id.0))
+                .filter(dsl::status.eq(QueuedKitRpcRequestStatus::Pending.as_str())),
+        )
+        .set((
+            dsl::status.eq(QueuedKitRpcRequestStatus::Delivering.as_str()),
+            dsl::attempts.eq(dsl::attempts + 1),
+            dsl::delivered_at.eq(now),
+            dsl::claimed_by.eq(instance_id),
+        ))
+        .get_result(conn)
+        .optional()
+    }
+
+    /// Return the request to the queue, to be delivered again.
+    pub fn release(conn: &PgConnection, id: QueuedKitRpcRequestId) -> QueryResult<usize> {
+        use queued_kit_rpc_requests::dsl;
+
+        diesel::update(dsl::queued_kit_rpc_requests.filter(dsl::id.eq(id.0)))
+            .set(dsl::status.eq(QueuedKitRpcRequestStatus::Pending.as_str()))
+            .execute(conn)
+    }
+
+    /// Record the outcome of the request.
+    pub fn complete(
+        conn: &PgConnection,
+        id: QueuedKitRpcRequestId,
+        outcome: Result<serde_json::Value, serde_json::Value>,
+        now: DateTime<Utc>,
+    ) -> QueryResult<usize> {
+        use queued_kit_rpc_requests::dsl;
+
+        let (status, response, error) = match outcome {
+            Ok(response) => (QueuedKitRpcRequestStatus::Succeeded, Some(response), None),
+            Err(error) => (QueuedKitRpcRequestStatus::Failed, None, Some(error)),
+        };
+
+        diesel::update(dsl::queued_kit_rpc_requests.filter(dsl::id.eq(id.0)))
+            .set((
+                dsl::status.eq(status.as_str()),
+                dsl::response.eq(response),
+                dsl::error.eq(error),
+                dsl::completed_at.eq(now),
+            ))
+            .execute(conn)
+    }
+
+    /// Return the requests being delivered by the server instance with id `instance_id`, and the
+    /// requests of any instance delivered before `delivered_before`, to the queue. Used on
+    /// startup, as the responses to these requests can no longer be received.
+    /// Returns the amount of released requests.
+    pub fn release_delivering(
+        conn: &PgConnection,
+        instance_id: &str,
+        delivered_before: DateTime<Utc>,
+    ) -> QueryResult<usize> {
+        use queued_kit_rpc_requests::dsl;
+
+        diesel::update(
+            dsl::queued_kit_rpc_requests
+                .filter(dsl::status.eq(QueuedKitRpcRequestStatus::Delivering.as_str()))
+                .filter(
+                    dsl::claimed_by
+                        .eq(instance_id)
+                        .or(dsl::delivered_at.lt(delivered_before)),
+                ),
+        )
+        .set(dsl::status.eq(QueuedKitRpcRequestStatus::Pending.as_str()))
+        .execute(conn)
+    }
+
+    /// Return the requests of any server instance delivered before `delivered_before` to the
+    /// queue, e.g. because the instance delivering them stopped.
+    /// Returns the amount of released requests.
+    pub fn release_abandoned(
+        conn: &PgConnection,
+        delivered_before: DateTime<Utc>,
+    ) -> QueryResult<usize> {
+        use queued_kit_rpc_requests::dsl;
+
+        diesel::update(
+            dsl::queued_kit_rpc_requests
+                .filter(dsl::status.eq(QueuedKitRpcRequestStatus::Delivering.as_str()))
+                .filter(dsl::delivered_at.lt(delivered_before)),
+        )
+        .set(dsl::status.eq(QueuedKitRpcRequestStatus::Pending.as_str()))
+        .execute(conn)
+    }
+
+    pub fn get_id(&self) -> QueuedKitRpcRequestId {
+        QueuedKitRpcRequestId(self.id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Insertable)]
+#[table_name = "queued_kit_rpc_requests"]
+pub struct NewQueuedKitRpcRequest {
+    pub kit_id: i32,
+    pub user_id: Option<i32>,
+    pub method: String,
+    pub peripheral_id: Option<i32>,
+    pub command: Option<serde_json::Value>,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl NewQueuedKitRpcRequest {
+    pub fn new(
+        kit_id: KitId,
+        user_id: Option<i32>,
+        method: QueuedKitRpcMethod,
+        peripheral_id: Option<i32>,
+        command: Option<serde_json::Value>,
+    ) -> Self {
+        Self {
+            kit_id: kit_id.0,
+            user_id,
+            method: method.as_str().to_owned(),
+            peripheral_id,
+            command,
+            status: QueuedKitRpcRequestStatus::Pending.as_str().to_owned(),
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn create(&self, conn: &PgConnection) -> QueryResult<QueuedKitRpcRequest> {
+        use crate::schema::queued_kit_rpc_requests::dsl::*;
+
+        diesel::insert_into(queued_kit_rpc_requests)
+            .values(self)
+            .on_conflict_do_nothing()
+            .get_result::<QueuedKitRpcRequest>(conn)
+    }
+}
@@ -0,0 +1,103 @@
+//! The active configuration of kits, as sent to kits over MQTT.
+
+use super::{models, views};
+
+use astroplant_mqtt::ConfigurationHash;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use diesel::pg::PgConnection;
+use diesel::QueryResult;
+use std::collections::HashMap;
+
+/// A kit's active configuration, including its peripherals and their definitions with the
+/// quantity types they are expected to produce.
+#[derive(Debug)]
+pub struct ActiveConfiguration {
+    pub configuration: serde_json::Value,
+    pub hash: ConfigurationHash,
+}
+
+/// Hash a JSON-encoded configuration.
+fn hash(encoded: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(encoded);
+    hasher.result_str()
+}
+
+impl ActiveConfiguration {
+    pub fn of_kit(conn: &PgConnection, kit: &models::Kit) -> QueryResult<Option<Self>> {
+        let configuration = match models::KitConfiguration::active_configuration_of_kit(conn, kit)?
+        {
+            Some(configuration) => configuration,
+            None => return Ok(None),
+        };
+        let peripherals_with_definitions =
+            models::Peripheral::peripherals_with_definitions_of_kit_configuration(
+                conn,
+                &configuration,
+            )?;
+
+        // Multiple peripherals can share a definition.
+        let mut definitions: Vec<models::PeripheralDefinition> = vec![];
+        for (_, definition) in &peripherals_with_definitions {
+            if !definitions.iter().any(|known| known.id == definition.id) {
+                definitions.push(definition.clone());
+            }
+        }
+        let expected_quantity_types: HashMap<i32, Vec<i32>> =
+            models::PeripheralDefinitionExpectedQuantityType::of_peripheral_definitions(
+                conn,
+                &definitions,
+            )?
+            .into_iter()
+            .zip(&definitions)
+            .map(|(expected_quantity_types, definition)| {
+                let mut quantity_types: Vec<i32> = expected_quantity_types
+                    .into_iter()
+                    .map(|expected_quantity_type| expected_quantity_type.quantity_type_id)
+                    .collect();
+                quantity_types.sort();
+                (definition.id, quantity_types)
+            })
+            .collect();
+
+        let id = configuration.id;
+        let configuration = views::KitConfiguration::from(configuration);
+        let peripherals_with_definitions: Vec<_> = peripherals_with_definitions
+            .into_iter()
+            .map(|(peripheral, definition)| {
+                let quantity_types = expected_quantity_types
+                    .get(&definition.id)
+                    .cloned()
+                    .unwrap_or_default();
+                let definition = views::PeripheralDefinition::from(definition)
+                    .with_expected_quantity_types(quantity_types);
+                views::Peripheral::from(peripheral).with_definition(definition)
+            })
+            .collect();
+        let configuration =
+            serde_json::to_value(configuration.with_peripherals(peripherals_with_definitions))
+                .unwrap();
+
+        // The hash is of the configuration exactly as it is sent to the kit.
+        let hash = hash(&configuration.to_string());
+
+        Ok(Some(Self {
+            configuration,
+            hash: ConfigurationHash { id, hash },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_is_hex_encoded_sha256() {
+        assert_eq!(
+            hash("{}"),
+            "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+    }
+}
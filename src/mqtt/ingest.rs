@@ -0,0 +1,637 @@
+//! Persists measurements received over MQTT.
+//!
+//! Measurements are validated against their kit's active configuration, buffered, and inserted in
+//! batches. Valid raw measurements are forwarded to WebSocket subscribers. Measurements failing
+//! validation are counted and quarantined instead, as are measurements the database rejects.
+//! Quarantined measurements and dead letters are pruned periodically.
+
+use super::kits_cache::KitsCache;
+use super::Error;
+use crate::{models, PgPool};
+
+use chrono::{DateTime, TimeZone, Utc};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use diesel::pg::PgConnection;
+use diesel::{Connection, QueryResult};
+use futures::channel::mpsc;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The maximum number of measurements buffered before they are inserted.
+const BATCH_SIZE: usize = 256;
+
+/// The maximum duration measurements are buffered before they are inserted.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The interval at which the number of quarantined measurements is logged.
+const QUARANTINE_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The interval at which old quarantined measurements and dead letters are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The duration quarantined measurements are kept for.
+const QUARANTINE_RETENTION_DAYS: i64 = 14;
+
+/// The maximum number of quarantined measurements kept per kit serial.
+const QUARANTINED_MEASUREMENTS_PER_KIT: i64 = 10_000;
+
+/// The duration dead letters are kept for.
+const DEAD_LETTER_RETENTION_DAYS: i64 = 14;
+
+/// The maximum number of dead letters kept per kit.
+const DEAD_LETTERS_PER_KIT: i64 = 1000;
+
+#[derive(Debug)]
+pub enum Measurement {
+    Raw(astroplant_mqtt::RawMeasurement),
+    Aggregate(astroplant_mqtt::AggregateMeasurement),
+    Batch(astroplant_mqtt::MeasurementBatch),
+}
+
+/// Convert a kit's millisecond UNIX timestamp to a datetime.
+/// Returns None if the timestamp is out of range.
+pub(super) fn datetime_from_millis(millis: u64) -> Option<DateTime<Utc>> {
+    if millis > i64::max_value() as u64 {
+        return None;
+    }
+    Utc.timestamp_millis_opt(millis as i64).single()
+}
+
+/// A measurement that failed validation.
+struct Quarantined {
+    kit_id: Option<models::KitId>,
+    reason: models::QuarantineReason,
+}
+
+impl Quarantined {
+    fn new(kit_id: Option<models::KitId>, reason: models::QuarantineReason) -> Self {
+        Self { kit_id, reason }
+    }
+}
+
+pub struct Ingester {
+    pg_pool: PgPool,
+    kits_cache: KitsCache,
+    raw_measurement_sender: mpsc::Sender<astroplant_mqtt::RawMeasurement>,
+    /// Buffered measurements, with the serials of their kits.
+    raw_measurements: Vec<(String, models::NewRawMeasurement)>,
+    aggregate_measurements: Vec<(String, models::NewAggregateMeasurement)>,
+    quarantined_measurements: Vec<models::NewQuarantinedMeasurement>,
+    /// The number of measurements quarantined since the counts were last logged.
+    quarantine_counts: HashMap<models::QuarantineReason, u64>,
+}
+
+impl Ingester {
+    pub fn new(
+        pg_pool: PgPool,
+        raw_measurement_sender: mpsc::Sender<astroplant_mqtt::RawMeasurement>,
+    ) -> Self {
+        Self {
+            pg_pool,
+            kits_cache: KitsCache::new(),
+            raw_measurement_sender,
+            raw_measurements: Vec::with_capacity(BATCH_SIZE),
+            aggregate_measurements: Vec::with_capacity(BATCH_SIZE),
+            quarantined_measurements: vec![],
+            quarantine_counts: HashMap::new(),
+        }
+    }
+
+    /// Resolve the kit and its active configuration a measurement belongs to, and validate the
+    /// measurement against that configuration.
+    fn resolve(
+        &mut self,
+        kit_serial: &str,
+        peripheral: i32,
+        quantity_type: i32,
+    ) -> Result<Result<(models::KitId, models::KitConfigurationId), Quarantined>, Error> {
+        use models::QuarantineReason::*;
+
+        let kit = match self.kits_cache.get(&self.pg_pool, kit_serial)? {
+            Some(kit) => kit,
+            None => return Ok(Err(Quarantined::new(None, UnknownKit))),
+        };
+        let configuration = match &kit.active_configuration {
+            Some(configuration) => configuration,
+            None => return Ok(Err(Quarantined::new(Some(kit.id), NoActiveConfiguration))),
+        };
+        let expected_quantity_types = match configuration.peripherals.get(&peripheral) {
+            Some(expected_quantity_types) => expected_quantity_types,
+            None => return Ok(Err(Quarantined::new(Some(kit.id), UnknownPeripheral))),
+        };
+        if !expected_quantity_types.contains(&quantity_type) {
+            return Ok(Err(Quarantined::new(Some(kit.id), UnexpectedQuantityType)));
+        }
+
+        Ok(Ok((kit.id, configuration.id)))
+    }
+
+    /// Quarantine a measurement that failed validation.
+    fn quarantine(
+        &mut self,
+        reason: models::QuarantineReason,
+        measurement: models::NewQuarantinedMeasurement,
+    ) {
+        debug!(
+            "quarantining {} measurement of kit {}, peripheral {}, quantity type {}: {}",
+            measurement.measurement_type,
+            measurement.kit_serial,
+            measurement.peripheral_id,
+            measurement.quantity_type_id,
+            measurement.reason
+        );
+
+        *self.quarantine_counts.entry(reason).or_insert(0) += 1;
+        self.quarantined_measurements.push(measurement);
+    }
+
+    fn ingest_raw_measurement(
+        &mut self,
+        measurement: astroplant_mqtt::RawMeasurement,
+    ) -> Result<(), Error> {
+        let datetime = datetime_from_millis(measurement.datetime);
+        let validated = match self.resolve(
+            &measurement.kit_serial,
+            measurement.peripheral,
+            measurement.quantity_type,
+        )? {
+            Ok(ids) => match datetime {
+                Some(datetime) => Ok((ids, datetime)),
+                None => Err(Quarantined::new(
+                    Some(ids.0),
+                    models::QuarantineReason::InvalidDatetime,
+                )),
+            },
+            Err(quarantined) => Err(quarantined),
+        };
+
+        let ((kit_id, kit_configuration_id), datetime) = match validated {
+            Ok(validated) => validated,
+            Err(quarantined) => {
+                let quarantined_measurement = models::NewQuarantinedMeasurement {
+                    id: Uuid::new_v4(),
+                    kit_serial: measurement.kit_serial,
+                    kit_id: quarantined.kit_id.map(|kit_id| kit_id.0),
+                    measurement_type: "raw".to_owned(),
+                    peripheral_id: measurement.peripheral,
+                    quantity_type_id: measurement.quantity_type,
+                    aggregate_type: None,
+                    value: measurement.value,
+                    datetime,
+                    datetime_end: None,
+                    reason: quarantined.reason.as_str().to_owned(),
+                    received_at: Utc::now(),
+                };
+                self.quarantine(quarantined.reason, quarantined_measurement);
+                return Ok(());
+            }
+        };
+
+        self.raw_measurements.push((
+            measurement.kit_serial.clone(),
+            models::NewRawMeasurement::new(
+                kit_id,
+                kit_configuration_id,
+                models::PeripheralId(measurement.peripheral),
+                models::QuantityTypeId(measurement.quantity_type),
+                measurement.value,
+                datetime,
+            ),
+        ));
+
+        // Buffered measurements are not real-time; they are not published to WebSocket
+        // subscribers.
+        if !measurement.buffered {
+            if let Err(err) = self.raw_measurement_sender.try_send(measurement) {
+                debug!("could not publish raw measurement: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ingest_aggregate_measurement(
+        &mut self,
+        measurement: astroplant_mqtt::AggregateMeasurement,
+    ) -> Result<(), Error> {
+        let datetime_start = datetime_from_millis(measurement.datetime_start);
+        let datetime_end = datetime_from_millis(measurement.datetime_end);
+        let validated = match self.resolve(
+            &measurement.kit_serial,
+            measurement.peripheral,
+            measurement.quantity_type,
+        )? {
+            Ok(ids) => match (datetime_start, datetime_end) {
+                (Some(datetime_start), Some(datetime_end)) if datetime_start <= datetime_end => {
+                    Ok((ids, datetime_start, datetime_end))
+                }
+                _ => Err(Quarantined::new(
+                    Some(ids.0),
+                    models::QuarantineReason::InvalidDatetime,
+                )),
+            },
+            Err(quarantined) => Err(quarantined),
+        };
+
+        let ((kit_id, kit_configuration_id), datetime_start, datetime_end) = match validated {
+            Ok(validated) => validated,
+            Err(quarantined) => {
+                let quarantined_measurement = models::NewQuarantinedMeasurement {
+                    id: Uuid::new_v4(),
+                    kit_serial: measurement.kit_serial,
+                    kit_id: quarantined.kit_id.map(|kit_id| kit_id.0),
+                    measurement_type: "aggregate".to_owned(),
+                    peripheral_id: measurement.peripheral,
+                    quantity_type_id: measurement.quantity_type,
+                    aggregate_type: Some(measurement.aggregate_type),
+                    value: measurement.value,
+                    datetime: datetime_start,
+                    datetime_end,
+                    reason: quarantined.reason.as_str().to_owned(),
+                    received_at: Utc::now(),
+                };
+                self.quarantine(quarantined.reason, quarantined_measurement);
+                return Ok(());
+            }
+        };
+
+        self.aggregate_measurements.push((
+            measurement.kit_serial,
+            models::NewAggregateMeasurement::new(
+                kit_id,
+                kit_configuration_id,
+                models::PeripheralId(measurement.peripheral),
+                models::QuantityTypeId(measurement.quantity_type),
+                measurement.aggregate_type,
+                measurement.value,
+                datetime_start,
+                datetime_end,
+            ),
+        ));
+
+        Ok(())
+    }
+
+    /// Ingest the measurements of a batch. As batches can be large, the buffered measurements are
+    /// inserted whenever the buffer fills up.
+    ///
+    /// If inserting fails, the rest of the batch is buffered as well: insertion is retried, and
+    /// the buffer is capped, by `run`.
+    fn ingest_batch(&mut self, batch: astroplant_mqtt::MeasurementBatch) -> Result<(), Error> {
+        let mut flush_failed = false;
+        for measurement in batch.raw_measurements {
+            self.ingest_raw_measurement(measurement)?;
+            self.flush_full_buffer(&mut flush_failed);
+        }
+        for measurement in batch.aggregate_measurements {
+            self.ingest_aggregate_measurement(measurement)?;
+            self.flush_full_buffer(&mut flush_failed);
+        }
+        Ok(())
+    }
+
+    /// Insert the buffered measurements if the buffer is full, unless inserting failed before.
+    fn flush_full_buffer(&mut self, flush_failed: &mut bool) {
+        if *flush_failed || self.buffered() < BATCH_SIZE {
+            return;
+        }
+        if let Err(err) = self.flush() {
+            warn!("error flushing measurements of batch: {:?}", err);
+            *flush_failed = true;
+        }
+    }
+
+    fn ingest(&mut self, measurement: Measurement) -> Result<(), Error> {
+        match measurement {
+            Measurement::Raw(measurement) => self.ingest_raw_measurement(measurement),
+            Measurement::Aggregate(measurement) => self.ingest_aggregate_measurement(measurement),
+            Measurement::Batch(batch) => self.ingest_batch(batch),
+        }
+    }
+
+    fn buffered(&self) -> usize {
+        self.raw_measurements.len()
+            + self.aggregate_measurements.len()
+            + self.quarantined_measurements.len()
+    }
+
+    fn clear(&mut self) {
+        self.raw_measurements.clear();
+        self.aggregate_measurements.clear();
+        self.quarantined_measurements.clear();
+    }
+
+    /// Log and reset the number of quarantined measurements.
+    fn log_quarantine_counts(&mut self) {
+        for (reason, count) in self.quarantine_counts.drain() {
+            info!(
+                "quarantined {} measurements in the last {} seconds: {}",
+                count,
+                QUARANTINE_LOG_INTERVAL.as_secs(),
+                reason.as_str()
+            );
+        }
+    }
+
+    /// Insert all buffered measurements at once.
+    fn insert_batches(&self, conn: &PgConnection) -> QueryResult<()> {
+        let raw_measurements: Vec<_> = self
+            .raw_measurements
+            .iter()
+            .map(|(_, measurement)| measurement.clone())
+            .collect();
+        let inserted = models::NewRawMeasurement::create_batch(conn, &raw_measurements)?;
+        trace!("inserted {} raw measurements", inserted);
+
+        let aggregate_measurements: Vec<_> = self
+            .aggregate_measurements
+            .iter()
+            .map(|(_, measurement)| measurement.clone())
+            .collect();
+        let inserted =
+            models::NewAggregateMeasurement::create_batch(conn, &aggregate_measurements)?;
+        trace!("inserted {} aggregate measurements", inserted);
+
+        let inserted =
+            models::NewQuarantinedMeasurement::create_batch(conn, &self.quarantined_measurements)?;
+        trace!("inserted {} quarantined measurements", inserted);
+
+        Ok(())
+    }
+
+    /// Insert the buffered measurements one by one, each in its own savepoint, and quarantine
+    /// the measurements the database rejects. Returns the quarantined rejected measurements.
+    fn insert_rows(
+        &self,
+        conn: &PgConnection,
+    ) -> QueryResult<Vec<models::NewQuarantinedMeasurement>> {
+        let mut rejected = vec![];
+
+        for (kit_serial, measurement) in &self.raw_measurements {
+            let inserted = conn.transaction(|| {
+                models::NewRawMeasurement::create_batch(conn, std::slice::from_ref(measurement))
+            });
+            if let Err(err) = inserted {
+                debug!("raw measurement of kit {} rejected: {:?}", kit_serial, err);
+                rejected.push(rejected_raw_measurement(kit_serial, measurement));
+            }
+        }
+
+        for (kit_serial, measurement) in &self.aggregate_measurements {
+            let inserted = conn.transaction(|| {
+                models::NewAggregateMeasurement::create_batch(
+                    conn,
+                    std::slice::from_ref(measurement),
+                )
+            });
+            if let Err(err) = inserted {
+                debug!(
+                    "aggregate measurement of kit {} rejected: {:?}",
+                    kit_serial, err
+                );
+                rejected.push(rejected_aggregate_measurement(kit_serial, measurement));
+            }
+        }
+
+        for quarantined in self.quarantined_measurements.iter().chain(&rejected) {
+            let inserted = conn.transaction(|| {
+                models::NewQuarantinedMeasurement::create_batch(
+                    conn,
+                    std::slice::from_ref(quarantined),
+                )
+            });
+            if let Err(err) = inserted {
+                error!(
+                    "dropping quarantined measurement of kit {}: {:?}",
+                    quarantined.kit_serial, err
+                );
+            }
+        }
+
+        Ok(rejected)
+    }
+
+    /// Insert all buffered measurements. If the database rejects any of them, the others are
+    /// still inserted, and the rejected measurements are quarantined. The buffer is kept if the
+    /// measurements could not be inserted at all, e.g. because the database is unavailable.
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.buffered() == 0 {
+            return Ok(());
+        }
+
+        let conn = self.pg_pool.get().map_err(|_| Error::PgPool)?;
+
+        let rejected = conn
+            .transaction(|| {
+                if conn.transaction(|| self.insert_batches(&conn)).is_ok() {
+                    return Ok(vec![]);
+                }
+                self.insert_rows(&conn)
+            })
+            .map_err(|err| {
+                error!("error inserting measurements: {:?}", err);
+                Error::Internal
+            })?;
+
+        for quarantined in rejected {
+            *self
+                .quarantine_counts
+                .entry(models::QuarantineReason::Rejected)
+                .or_insert(0) += 1;
+            // The kit may have been deleted or changed while it was cached.
+            self.kits_cache.invalidate(&quarantined.kit_serial);
+        }
+        self.clear();
+
+        Ok(())
+    }
+
+    /// Delete old quarantined measurements and dead letters.
+    fn prune(&self) -> Result<(), Error> {
+        let conn = self.pg_pool.get().map_err(|_| Error::PgPool)?;
+
+        let deleted = models::QuarantinedMeasurement::prune(
+            &conn,
+            Utc::now() - chrono::Duration::days(QUARANTINE_RETENTION_DAYS),
+            QUARANTINED_MEASUREMENTS_PER_KIT,
+        )
+        .map_err(|_| Error::Internal)?;
+        debug!("pruned {} quarantined measurements", deleted);
+
+        let deleted = models::DeadLetter::prune(
+            &conn,
+            Utc::now() - chrono::Duration::days(DEAD_LETTER_RETENTION_DAYS),
+            DEAD_LETTERS_PER_KIT,
+        )
+        .map_err(|_| Error::Internal)?;
+        debug!("pruned {} dead letters", deleted);
+
+        Ok(())
+    }
+
+    /// Ingest measurements until the sending side of the channel disconnects. The kits of which
+    /// the serials are received on `configuration_changes` are evicted from the cache.
+    pub fn run(mut self, receiver: Receiver<Measurement>, configuration_changes: Receiver<String>) {
+        let mut last_flush = Instant::now();
+        let mut last_quarantine_log = Instant::now();
+        let mut last_prune = Instant::now();
+
+        loop {
+            for kit_serial in configuration_changes.try_iter() {
+                self.kits_cache.invalidate(&kit_serial);
+            }
+
+            if last_quarantine_log.elapsed() >= QUARANTINE_LOG_INTERVAL {
+                self.log_quarantine_counts();
+                last_quarantine_log = Instant::now();
+            }
+
+            if last_prune.elapsed() >= PRUNE_INTERVAL {
+                if let Err(err) = self.prune() {
+                    warn!(
+                        "error pruning quarantined measurements and dead letters: {:?}",
+                        err
+                    );
+                }
+                last_prune = Instant::now();
+            }
+
+            let timeout = FLUSH_INTERVAL
+                .checked_sub(last_flush.elapsed())
+                .unwrap_or_default();
+
+            match receiver.recv_timeout(timeout) {
+                Ok(measurement) => {
+                    if let Err(err) = self.ingest(measurement) {
+                        warn!("error ingesting measurement: {:?}", err);
+                    }
+                    if self.buffered() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Err(err) = self.flush() {
+                        warn!("error flushing measurements: {:?}", err);
+                    }
+                    break;
+                }
+            }
+
+            if let Err(err) = self.flush() {
+                // The buffer is kept, and insertion is retried on next flush. Prevent the buffer
+                // from growing unboundedly when the database is unavailable for a long time.
+                warn!("error flushing measurements: {:?}", err);
+                if self.buffered() >= BATCH_SIZE * 16 {
+                    warn!("dropping {} buffered measurements", self.buffered());
+                    self.clear();
+                }
+            }
+            last_flush = Instant::now();
+        }
+    }
+}
+
+fn rejected_raw_measurement(
+    kit_serial: &str,
+    measurement: &models::NewRawMeasurement,
+) -> models::NewQuarantinedMeasurement {
+    models::NewQuarantinedMeasurement {
+        id: Uuid::new_v4(),
+        kit_serial: kit_serial.to_owned(),
+        // The kit may no longer exist.
+        kit_id: None,
+        measurement_type: "raw".to_owned(),
+        peripheral_id: measurement.peripheral_id,
+        quantity_type_id: measurement.quantity_type_id,
+        aggregate_type: None,
+        value: measurement.value,
+        datetime: Some(measurement.datetime),
+        datetime_end: None,
+        reason: models::QuarantineReason::Rejected.as_str().to_owned(),
+        received_at: Utc::now(),
+    }
+}
+
+fn rejected_aggregate_measurement(
+    kit_serial: &str,
+    measurement: &models::NewAggregateMeasurement,
+) -> models::NewQuarantinedMeasurement {
+    models::NewQuarantinedMeasurement {
+        id: Uuid::new_v4(),
+        kit_serial: kit_serial.to_owned(),
+        // The kit may no longer exist.
+        kit_id: None,
+        measurement_type: "aggregate".to_owned(),
+        peripheral_id: measurement.peripheral_id,
+        quantity_type_id: measurement.quantity_type_id,
+        aggregate_type: Some(measurement.aggregate_type.clone()),
+        value: measurement.value,
+        datetime: Some(measurement.datetime_start),
+        datetime_end: Some(measurement.datetime_end),
+        reason: models::QuarantineReason::Rejected.as_str().to_owned(),
+        received_at: Utc::now(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mqtt::kits_cache::{ActiveConfiguration, CachedKit};
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use std::collections::HashSet;
+
+    #[test]
+    fn measurement_batches_are_kept_when_flushing_fails() {
+        // The database is unreachable.
+        let pg_pool = Pool::builder()
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://127.0.0.1:1/astroplant"));
+        let (raw_measurement_sender, _raw_measurement_receiver) = mpsc::channel(128);
+        let mut ingester = Ingester::new(pg_pool, raw_measurement_sender);
+
+        let mut peripherals = HashMap::new();
+        peripherals.insert(1, [2].iter().cloned().collect::<HashSet<_>>());
+        ingester.kits_cache.insert(
+            "k_test",
+            Some(CachedKit {
+                id: models::KitId(1),
+                active_configuration: Some(ActiveConfiguration {
+                    id: models::KitConfigurationId(1),
+                    peripherals,
+                }),
+            }),
+        );
+
+        let raw_measurements = (0..BATCH_SIZE as u64 * 3)
+            .map(|n| astroplant_mqtt::RawMeasurement {
+                kit_serial: "k_test".to_owned(),
+                datetime: 1_588_680_000_000 + n,
+                peripheral: 1,
+                quantity_type: 2,
+                value: 21.5,
+                buffered: true,
+            })
+            .collect();
+        let batch = astroplant_mqtt::MeasurementBatch {
+            kit_serial: "k_test".to_owned(),
+            raw_measurements,
+            aggregate_measurements: vec![],
+        };
+
+        assert!(ingester.ingest(Measurement::Batch(batch)).is_ok());
+        assert_eq!(ingester.buffered(), BATCH_SIZE * 3);
+    }
+
+    #[test]
+    fn datetime_from_millis() {
+        use chrono::{TimeZone, Utc};
+
+        assert_eq!(
+            super::datetime_from_millis(1_431_648_000_123),
+            Some(Utc.ymd(2015, 5, 15).and_hms_milli(0, 0, 0, 123))
+        );
+        assert_eq!(super::datetime_from_millis(u64::max_value()), None);
+    }
+}
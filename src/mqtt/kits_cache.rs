@@ -0,0 +1,143 @@
+//! A cache mapping kit serials to the kits' database identifiers and active configurations.
+//!
+//! Measurements arrive over MQTT tagged only with a kit serial. Resolving that serial requires
+//! several queries, which would otherwise be performed once per message. Kits are evicted when
+//! their active configuration changes, and expire after a while regardless.
+
+use super::Error;
+use crate::{models, PgPool};
+
+use diesel::pg::PgConnection;
+use diesel::QueryResult;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// The duration after which a cached kit is fetched from the database again.
+const TIME_TO_LIVE: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct ActiveConfiguration {
+    pub id: models::KitConfigurationId,
+    /// The ids of the peripherals in this configuration, mapped to the ids of the quantity types
+    /// their definitions declare.
+    pub peripherals: HashMap<i32, HashSet<i32>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedKit {
+    pub id: models::KitId,
+    pub active_configuration: Option<ActiveConfiguration>,
+}
+
+pub struct KitsCache {
+    kits: HashMap<String, (Instant, Option<CachedKit>)>,
+}
+
+impl KitsCache {
+    pub fn new() -> Self {
+        Self {
+            kits: HashMap::new(),
+        }
+    }
+
+    /// Get the kit with the given serial. The kit is fetched from the database if it is not
+    /// cached or if its cache entry has expired.
+    ///
+    /// Returns None if there is no kit with the given serial. This is cached as well.
+    pub fn get(&mut self, pg_pool: &PgPool, kit_serial: &str) -> Result<Option<&CachedKit>, Error> {
+        let now = Instant::now();
+        let fresh = match self.kits.get(kit_serial) {
+            Some((fetched, _)) => now.duration_since(*fetched) < TIME_TO_LIVE,
+            None => false,
+        };
+
+        if !fresh {
+            trace!("fetching kit {} for the kits cache", kit_serial);
+            let conn = pg_pool.get().map_err(|_| Error::PgPool)?;
+            let kit = Self::fetch(&conn, kit_serial).map_err(|_| Error::Internal)?;
+            self.kits.insert(kit_serial.to_owned(), (now, kit));
+        }
+
+        Ok(self.kits.get(kit_serial).and_then(|(_, kit)| kit.as_ref()))
+    }
+
+    /// Cache a kit without fetching it.
+    #[cfg(test)]
+    pub fn insert(&mut self, kit_serial: &str, kit: Option<CachedKit>) {
+        self.kits
+            .insert(kit_serial.to_owned(), (Instant::now(), kit));
+    }
+
+    /// Remove a kit from the cache, such that it is fetched again on next use.
+    pub fn invalidate(&mut self, kit_serial: &str) {
+        self.kits.remove(kit_serial);
+    }
+
+    fn fetch(conn: &PgConnection, kit_serial: &str) -> QueryResult<Option<CachedKit>> {
+        let kit = match models::Kit::by_serial(conn, kit_serial.to_owned())? {
+            Some(kit) => kit,
+            None => return Ok(None),
+        };
+
+        let active_configuration =
+            match models::KitConfiguration::active_configuration_of_kit(conn, &kit)? {
+                Some(configuration) => Some(ActiveConfiguration {
+                    id: configuration.get_id(),
+                    peripherals: Self::fetch_peripherals(conn, &configuration)?,
+                }),
+                None => None,
+            };
+
+        Ok(Some(CachedKit {
+            id: kit.get_id(),
+            active_configuration,
+        }))
+    }
+
+    /// Fetch the peripherals of a configuration, with the quantity types they are expected to
+    /// measure.
+    fn fetch_peripherals(
+        conn: &PgConnection,
+        configuration: &models::KitConfiguration,
+    ) -> QueryResult<HashMap<i32, HashSet<i32>>> {
+        let peripherals_with_definitions =
+            models::Peripheral::peripherals_with_definitions_of_kit_configuration(
+                conn,
+                configuration,
+            )?;
+
+        // Multiple peripherals can share a definition.
+        let mut definitions: Vec<models::PeripheralDefinition> = vec![];
+        for (_, definition) in &peripherals_with_definitions {
+            if !definitions.iter().any(|known| known.id == definition.id) {
+                definitions.push(definition.clone());
+            }
+        }
+        let expected_quantity_types: HashMap<i32, HashSet<i32>> =
+            models::PeripheralDefinitionExpectedQuantityType::of_peripheral_definitions(
+                conn,
+                &definitions,
+            )?
+            .into_iter()
+            .zip(&definitions)
+            .map(|(expected_quantity_types, definition)| {
+                let quantity_types = expected_quantity_types
+                    .into_iter()
+                    .map(|expected_quantity_type| expected_quantity_type.quantity_type_id)
+                    .collect();
+                (definition.id, quantity_types)
+            })
+            .collect();
+
+        Ok(peripherals_with_definitions
+            .into_iter()
+            .map(|(peripheral, definition)| {
+                let quantity_types = expected_quantity_types
+                    .get(&definition.id)
+                    .cloned()
+                    .unwrap_or_default();
+                (peripheral.id, quantity_types)
+            })
+            .collect())
+    }
+}
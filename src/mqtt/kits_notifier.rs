@@ -0,0 +1,46 @@
+//! Notifies kits of changes over MQTT.
+//!
+//! Changes to a kit's active configuration also evict the kit from this instance's kits cache.
+//! Other instances sharing the handling of kit messages pick up the change when their cached kit
+//! expires.
+
+use astroplant_mqtt::ConfigurationHash;
+
+/// A handle to send notifications to kits.
+#[derive(Clone)]
+pub struct KitsNotifier {
+    kits_notifier: astroplant_mqtt::KitsNotifier,
+    configuration_change_sender: crossbeam::channel::Sender<String>,
+}
+
+impl KitsNotifier {
+    pub(super) fn new(
+        kits_notifier: astroplant_mqtt::KitsNotifier,
+        configuration_change_sender: crossbeam::channel::Sender<String>,
+    ) -> Self {
+        Self {
+            kits_notifier,
+            configuration_change_sender,
+        }
+    }
+
+    /// Notify the kit its active configuration has changed. `configuration_hash` is `None` if the
+    /// kit no longer has an active configuration.
+    pub fn configuration_changed(
+        &mut self,
+        kit_serial: &str,
+        configuration_hash: Option<&ConfigurationHash>,
+    ) {
+        // The ingester caches the kit's active configuration.
+        if self
+            .configuration_change_sender
+            .send(kit_serial.to_owned())
+            .is_err()
+        {
+            error!("measurement ingester has gone away");
+        }
+
+        self.kits_notifier
+            .configuration_changed(kit_serial, configuration_hash);
+    }
+}
@@ -1,11 +1,38 @@
+mod active_configuration;
+mod ingest;
+mod kits_cache;
+mod kits_notifier;
+mod presence;
+mod rpc_queue;
+
+pub use active_configuration::ActiveConfiguration;
+pub use kits_notifier::KitsNotifier;
+pub use presence::Presence;
+
 use super::{helpers, models, views, PgPool, PgPooled};
 
-use astroplant_mqtt::{MqttApiMessage, ServerRpcRequest};
+use astroplant_mqtt::{
+    ConnectionOptions, MqttApiMessage, OverflowPolicies, OverflowPolicy, ServerRpcRequest,
+    SharedSubscription, TlsOptions,
+};
 use futures::channel::{mpsc, oneshot};
 use futures::future::FutureExt;
-use futures::sink::SinkExt;
+use std::time::Duration;
 use tokio::runtime::{Runtime, Handle};
 
+/// The number of measurements that can be queued for ingestion. Measurements arriving while the
+/// queue is full are dropped.
+const INGEST_BUFFER: usize = 1024;
+
+/// The instance id of a server not sharing the handling of kit messages with other instances.
+const STANDALONE_INSTANCE_ID: &str = "standalone";
+
+/// The interval at which kits that have gone silent are marked offline.
+const PRESENCE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The interval at which queued kit RPC requests abandoned by stopped instances are released.
+const RPC_QUEUE_RELEASE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 enum Error {
     PgPool,
@@ -15,69 +42,80 @@ enum Error {
 struct Handler {
     pg_pool: PgPool,
     runtime_handle: Handle,
-    raw_measurement_sender: mpsc::Sender<astroplant_mqtt::RawMeasurement>,
+    ingest_sender: crossbeam::channel::Sender<ingest::Measurement>,
+    /// The number of measurements dropped because the ingester was not keeping up.
+    ingest_dropped: usize,
+    presence_tracker: presence::PresenceTracker,
+    presence_sender: mpsc::Sender<Presence>,
+    rpc_queue_deliverer: rpc_queue::Deliverer,
 }
 
 impl Handler {
     pub fn new(
         pg_pool: PgPool,
         runtime_handle: Handle,
-        raw_measurement_sender: mpsc::Sender<astroplant_mqtt::RawMeasurement>,
+        ingest_sender: crossbeam::channel::Sender<ingest::Measurement>,
+        presence_sender: mpsc::Sender<Presence>,
+        kits_rpc: astroplant_mqtt::KitsRpc,
+        instance_id: String,
     ) -> Self {
         Self {
+            rpc_queue_deliverer: rpc_queue::Deliverer::new(pg_pool.clone(), kits_rpc, instance_id),
             pg_pool,
             runtime_handle,
-            raw_measurement_sender,
+            ingest_sender,
+            ingest_dropped: 0,
+            presence_tracker: presence::PresenceTracker::new(),
+            presence_sender,
         }
     }
 
-    async fn get_active_configuration(
+    async fn active_configuration_of_kit_serial(
         pg_pool: PgPool,
         kit_serial: String,
-        response: oneshot::Sender<Option<serde_json::Value>>,
-    ) -> Result<(), Error> {
-        trace!("handling getActiveConfiguration request for {}", kit_serial);
-
+    ) -> Result<Option<ActiveConfiguration>, Error> {
         let conn: PgPooled =
             helpers::threadpool(move || pg_pool.get().map_err(|_| Error::PgPool)).await?;
-        let configuration: Option<_> = helpers::threadpool(move || {
+        helpers::threadpool(move || {
             println!("getting for kit: {}", kit_serial);
             let kit =
                 match models::Kit::by_serial(&conn, kit_serial).map_err(|_| Error::Internal)? {
                     Some(kit) => kit,
                     None => return Ok(None),
                 };
-            let configuration =
-                match models::KitConfiguration::active_configuration_of_kit(&conn, &kit)
-                    .map_err(|_| Error::Internal)?
-                {
-                    Some(configuration) => configuration,
-                    None => return Ok(None),
-                };
-            let peripherals_with_definitions =
-                models::Peripheral::peripherals_with_definitions_of_kit_configuration(
-                    &conn,
-                    &configuration,
-                )
-                .map_err(|_| Error::Internal)?;
-
-            let configuration = views::KitConfiguration::from(configuration);
-            let peripherals_with_definitions: Vec<_> = peripherals_with_definitions
-                .into_iter()
-                .map(|(peripheral, definition)| {
-                    let definition = views::PeripheralDefinition::from(definition);
-                    views::Peripheral::from(peripheral).with_definition(definition)
-                })
-                .collect();
-
-            Ok(Some(
-                configuration.with_peripherals(peripherals_with_definitions),
-            ))
+            ActiveConfiguration::of_kit(&conn, &kit).map_err(|_| Error::Internal)
         })
-        .await?;
+        .await
+    }
 
-        let _ = response
-            .send(configuration.map(|configuration| serde_json::to_value(configuration).unwrap()));
+    async fn get_active_configuration(
+        pg_pool: PgPool,
+        kit_serial: String,
+        response: oneshot::Sender<Option<(serde_json::Value, astroplant_mqtt::ConfigurationHash)>>,
+    ) -> Result<(), Error> {
+        trace!("handling getActiveConfiguration request for {}", kit_serial);
+
+        let configuration = Self::active_configuration_of_kit_serial(pg_pool, kit_serial).await?;
+
+        let _ = response.send(
+            configuration.map(|configuration| (configuration.configuration, configuration.hash)),
+        );
+        Ok(())
+    }
+
+    async fn get_active_configuration_hash(
+        pg_pool: PgPool,
+        kit_serial: String,
+        response: oneshot::Sender<Option<astroplant_mqtt::ConfigurationHash>>,
+    ) -> Result<(), Error> {
+        trace!(
+            "handling getActiveConfigurationHash request for {}",
+            kit_serial
+        );
+
+        let configuration = Self::active_configuration_of_kit_serial(pg_pool, kit_serial).await?;
+
+        let _ = response.send(configuration.map(|configuration| configuration.hash));
         Ok(())
     }
 
@@ -105,6 +143,134 @@ impl Handler {
         Ok(())
     }
 
+    /// Store a message that could not be handled, such that kit owners can inspect it.
+    async fn store_dead_letter(
+        pg_pool: PgPool,
+        message: astroplant_mqtt::MalformedMessage,
+    ) -> Result<(), Error> {
+        use astroplant_mqtt::Error as MqttError;
+
+        let (error_kind, error_message) = match message.error {
+            MqttError::InvalidTopic => ("invalidTopic", None),
+            MqttError::InvalidPayload => ("invalidPayload", None),
+            MqttError::Capnp(err) => ("capnp", Some(err.to_string())),
+            MqttError::ServerRpcError(_) => ("serverRpc", None),
+        };
+
+        let conn: PgPooled =
+            helpers::threadpool(move || pg_pool.get().map_err(|_| Error::PgPool)).await?;
+        helpers::threadpool(move || {
+            let kit_id = match &message.kit_serial {
+                Some(kit_serial) => models::Kit::by_serial(&conn, kit_serial.clone())
+                    .map_err(|_| Error::Internal)?
+                    .map(|kit| kit.get_id()),
+                None => None,
+            };
+
+            models::NewDeadLetter {
+                kit_serial: message.kit_serial,
+                kit_id: kit_id.map(|kit_id| kit_id.0),
+                topic: message.topic,
+                payload: message.payload,
+                error_kind: error_kind.to_owned(),
+                error_message,
+                received_at: chrono::Utc::now(),
+            }
+            .create(&conn)
+            .map_err(|_| Error::Internal)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Persist a kit's presence. If a `presence_sender` is given, the presence is published to it
+    /// once persisted; a presence that is not persisted, because another instance saw the kit
+    /// later, is not published.
+    async fn persist_presence(
+        pg_pool: PgPool,
+        presence: Presence,
+        presence_sender: Option<mpsc::Sender<Presence>>,
+    ) -> Result<(), Error> {
+        let conn: PgPooled =
+            helpers::threadpool(move || pg_pool.get().map_err(|_| Error::PgPool)).await?;
+        helpers::threadpool(move || {
+            let updated = models::UpdateKitPresence {
+                online: presence.online,
+                last_seen: presence.last_seen,
+                last_measurement_at: presence.last_measurement_at,
+                clock_skew_millis: presence.clock_skew_millis,
+            }
+            .update_by_serial(&conn, &presence.kit_serial)
+            .map_err(|_| Error::Internal)?;
+
+            if updated == 0 {
+                return Ok(());
+            }
+            if let Some(mut presence_sender) = presence_sender {
+                debug!(
+                    "kit {} is {}",
+                    presence.kit_serial,
+                    if presence.online { "online" } else { "offline" }
+                );
+                if presence_sender.try_send(presence).is_err() {
+                    warn!("presence receiver not keeping up; dropping presence change");
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    fn presence_update(&mut self, update: Option<presence::Update>) {
+        let update = match update {
+            Some(update) => update,
+            None => return,
+        };
+
+        if update.presence.online {
+            // The kit shows activity: deliver its queued RPC requests.
+            self.runtime_handle.spawn(
+                self.rpc_queue_deliverer
+                    .clone()
+                    .deliver(update.presence.kit_serial.clone()),
+            );
+        }
+
+        // Changes are only published once persisted.
+        let presence_sender = if update.changed {
+            Some(self.presence_sender.clone())
+        } else {
+            None
+        };
+        self.runtime_handle.spawn(
+            Self::persist_presence(self.pg_pool.clone(), update.presence, presence_sender)
+                .map(|_| ()),
+        );
+    }
+
+    /// Queue a measurement for ingestion. The handler does not wait for the ingester, which may be
+    /// held up by the database: the measurement is dropped if the queue is full.
+    fn ingest(&mut self, measurement: ingest::Measurement) {
+        use crossbeam::channel::TrySendError;
+
+        match self.ingest_sender.try_send(measurement) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.ingest_dropped += 1;
+                // Keep the log readable during floods.
+                if self.ingest_dropped.is_power_of_two() {
+                    warn!(
+                        "measurement ingester not keeping up; dropped {} measurements in total",
+                        self.ingest_dropped
+                    );
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => error!("measurement ingester has gone away"),
+        }
+    }
+
     fn server_rpc_request(&mut self, request: ServerRpcRequest) {
         use ServerRpcRequest::*;
 
@@ -112,6 +278,9 @@ impl Handler {
             Version { response } => {
                 let _ = response.send(super::VERSION.to_owned());
             }
+            GetServerTime { response } => {
+                let _ = response.send(chrono::Utc::now().timestamp_millis() as u64);
+            }
             GetActiveConfiguration {
                 kit_serial,
                 response,
@@ -121,6 +290,15 @@ impl Handler {
                         .map(|_| ()),
                 );
             }
+            GetActiveConfigurationHash {
+                kit_serial,
+                response,
+            } => {
+                self.runtime_handle.spawn(
+                    Self::get_active_configuration_hash(self.pg_pool.clone(), kit_serial, response)
+                        .map(|_| ()),
+                );
+            }
             GetQuantityTypes { response } => {
                 self.runtime_handle
                     .spawn(Self::get_quantity_types(self.pg_pool.clone(), response).map(|_| ()));
@@ -128,47 +306,210 @@ impl Handler {
         }
     }
 
-    async fn send<T>(mut sender: mpsc::Sender<T>, val: T) {
-        // TODO: handle errors.
-        let _ = sender.send(val).await;
-    }
+    pub fn run(&mut self, message_receiver: astroplant_mqtt::MqttApiReceiver) {
+        let mut next_sweep = std::time::Instant::now() + PRESENCE_SWEEP_INTERVAL;
+        let mut next_release = std::time::Instant::now() + RPC_QUEUE_RELEASE_INTERVAL;
+        loop {
+            let now = std::time::Instant::now();
+            if now >= next_sweep {
+                for update in self.presence_tracker.sweep(chrono::Utc::now()) {
+                    self.presence_update(Some(update));
+                }
+                next_sweep = now + PRESENCE_SWEEP_INTERVAL;
+            }
+            if now >= next_release {
+                self.runtime_handle
+                    .spawn(self.rpc_queue_deliverer.clone().release_abandoned());
+                next_release = now + RPC_QUEUE_RELEASE_INTERVAL;
+            }
+
+            let timeout = next_sweep.min(next_release) - now;
+            let message = match message_receiver.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(astroplant_mqtt::RecvTimeoutError::Timeout) => continue,
+                Err(astroplant_mqtt::RecvTimeoutError::Disconnected) => break,
+            };
 
-    pub fn run(
-        &mut self,
-        message_receiver: crossbeam::channel::Receiver<astroplant_mqtt::MqttApiMessage>,
-    ) {
-        for message in message_receiver {
             match message {
                 MqttApiMessage::ServerRpcRequest(request) => self.server_rpc_request(request),
                 MqttApiMessage::RawMeasurement(measurement) => {
                     println!("Received measurement: {:?}", measurement);
+                    let update = self.presence_tracker.measured(
+                        &measurement.kit_serial,
+                        chrono::Utc::now(),
+                        ingest::datetime_from_millis(measurement.datetime),
+                    );
+                    self.presence_update(update);
+                    self.ingest(ingest::Measurement::Raw(measurement));
+                }
+                MqttApiMessage::MalformedMessage(message) => {
                     self.runtime_handle
-                        .spawn(Self::send(self.raw_measurement_sender.clone(), measurement));
+                        .spawn(Self::store_dead_letter(self.pg_pool.clone(), message).map(|_| ()));
+                }
+                MqttApiMessage::AggregateMeasurement(measurement) => {
+                    // Aggregate measurements are published after their window has passed; their
+                    // datetimes are not used to estimate the kit's clock skew.
+                    let update = self.presence_tracker.measured(
+                        &measurement.kit_serial,
+                        chrono::Utc::now(),
+                        None,
+                    );
+                    self.presence_update(update);
+                    self.ingest(ingest::Measurement::Aggregate(measurement));
+                }
+                MqttApiMessage::MeasurementBatch(batch) => {
+                    // Buffered measurements were not measured right before they were published.
+                    let update =
+                        self.presence_tracker
+                            .measured(&batch.kit_serial, chrono::Utc::now(), None);
+                    self.presence_update(update);
+                    self.ingest(ingest::Measurement::Batch(batch));
+                }
+                MqttApiMessage::KitStatus(status) => {
+                    let update = self.presence_tracker.status(
+                        &status.kit_serial,
+                        status.online,
+                        chrono::Utc::now(),
+                    );
+                    self.presence_update(update);
+                }
+                MqttApiMessage::KitSeen(kit_serial) => {
+                    let update = self.presence_tracker.seen(&kit_serial, chrono::Utc::now());
+                    self.presence_update(update);
                 }
-                _ => {}
             }
         }
     }
 }
 
+/// Read a file named by an environment variable.
+fn read_file_of_var(name: &str, path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| panic!("could not read {} {}: {}", name, path, err))
+}
+
+/// Read from the environment whether the handling of kit messages is shared with other instances.
+fn shared_subscription() -> Option<SharedSubscription> {
+    let group = std::env::var("MQTT_SHARED_SUBSCRIPTION_GROUP").ok()?;
+    let instance_id = std::env::var("MQTT_INSTANCE_ID").unwrap_or_else(|_| {
+        // Queued kit RPC requests claimed before a restart are then only released once abandoned.
+        warn!("MQTT_INSTANCE_ID is not set; using a random instance id, which changes on restart");
+        random_string::unambiguous_lowercase_string(8)
+    });
+    info!(
+        "sharing MQTT subscriptions in group {} as instance {}",
+        group, instance_id
+    );
+    Some(SharedSubscription { group, instance_id })
+}
+
+/// Read the configuration of the connection to the MQTT broker from the environment. Instances
+/// sharing subscriptions connect with the client id suffixed by their instance id, as client ids
+/// must be unique.
+fn connection_options(shared_subscription: Option<&SharedSubscription>) -> ConnectionOptions {
+    let tls = std::env::var("MQTT_TLS_CA").ok().map(|ca| {
+        let client_auth = match (
+            std::env::var("MQTT_TLS_CLIENT_CERT"),
+            std::env::var("MQTT_TLS_CLIENT_KEY"),
+        ) {
+            (Ok(certificate), Ok(key)) => Some((
+                read_file_of_var("MQTT_TLS_CLIENT_CERT", &certificate),
+                read_file_of_var("MQTT_TLS_CLIENT_KEY", &key),
+            )),
+            (Err(_), Err(_)) => None,
+            _ => panic!("MQTT_TLS_CLIENT_CERT and MQTT_TLS_CLIENT_KEY must be set together."),
+        };
+        TlsOptions {
+            ca: read_file_of_var("MQTT_TLS_CA", &ca),
+            client_auth,
+        }
+    });
+
+    // The connection cannot be checked more often than every five seconds.
+    let keep_alive = std::env::var("MQTT_KEEP_ALIVE")
+        .map_err(|_| ())
+        .and_then(|keep_alive| keep_alive.parse().map_err(|_| ()))
+        .unwrap_or(crate::DEFAULT_MQTT_KEEP_ALIVE)
+        .max(5);
+
+    let mut client_id =
+        std::env::var("MQTT_CLIENT_ID").unwrap_or(crate::DEFAULT_MQTT_CLIENT_ID.to_owned());
+    if let Some(shared_subscription) = shared_subscription {
+        client_id = format!("{}-{}", client_id, shared_subscription.instance_id);
+    }
+
+    ConnectionOptions {
+        host: std::env::var("MQTT_HOST").unwrap_or(crate::DEFAULT_MQTT_HOST.to_owned()),
+        port: std::env::var("MQTT_PORT")
+            .map_err(|_| ())
+            .and_then(|port| port.parse().map_err(|_| ()))
+            .unwrap_or(crate::DEFAULT_MQTT_PORT),
+        client_id,
+        username: std::env::var("MQTT_USERNAME").unwrap_or(crate::DEFAULT_MQTT_USERNAME.to_owned()),
+        password: std::env::var("MQTT_PASSWORD").unwrap_or(crate::DEFAULT_MQTT_PASSWORD.to_owned()),
+        keep_alive: Duration::from_secs(keep_alive),
+        tls,
+    }
+}
+
+/// Read an overflow policy from the environment.
+fn overflow_policy(name: &str, default: OverflowPolicy) -> OverflowPolicy {
+    match std::env::var(name) {
+        Ok(policy) => policy.parse().unwrap_or_else(|_| {
+            warn!("invalid {}: {}, using {:?}", name, policy, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn overflow_policies() -> OverflowPolicies {
+    let default = OverflowPolicies::default();
+    OverflowPolicies {
+        measurements: overflow_policy("MQTT_OVERFLOW_MEASUREMENTS", default.measurements),
+        server_rpc_requests: overflow_policy(
+            "MQTT_OVERFLOW_SERVER_RPC_REQUESTS",
+            default.server_rpc_requests,
+        ),
+        kit_events: overflow_policy("MQTT_OVERFLOW_KIT_EVENTS", default.kit_events),
+        kit_rpc_responses: overflow_policy(
+            "MQTT_OVERFLOW_KIT_RPC_RESPONSES",
+            default.kit_rpc_responses,
+        ),
+    }
+}
+
 pub fn run(
     pg_pool: PgPool,
 ) -> (
     mpsc::Receiver<astroplant_mqtt::RawMeasurement>,
+    mpsc::Receiver<Presence>,
     astroplant_mqtt::KitsRpc,
+    KitsNotifier,
 ) {
     let (raw_measurement_sender, raw_measurement_receiver) = mpsc::channel(128);
+    let (presence_sender, presence_receiver) = mpsc::channel(128);
+    let (ingest_sender, ingest_receiver) = crossbeam::channel::bounded(INGEST_BUFFER);
+    let (configuration_change_sender, configuration_change_receiver) =
+        crossbeam::channel::unbounded();
 
-    let (message_receiver, kits_rpc) = astroplant_mqtt::run(
-        std::env::var("MQTT_HOST").unwrap_or(crate::DEFAULT_MQTT_HOST.to_owned()),
-        std::env::var("MQTT_PORT")
-            .map_err(|_| ())
-            .and_then(|port| port.parse().map_err(|_| ()))
-            .unwrap_or(crate::DEFAULT_MQTT_PORT),
-        std::env::var("MQTT_USERNAME").unwrap_or(crate::DEFAULT_MQTT_USERNAME.to_owned()),
-        std::env::var("MQTT_PASSWORD").unwrap_or(crate::DEFAULT_MQTT_PASSWORD.to_owned()),
+    let shared_subscription = shared_subscription();
+    let shared = shared_subscription.is_some();
+    let instance_id = shared_subscription
+        .as_ref()
+        .map(|shared_subscription| shared_subscription.instance_id.clone())
+        .unwrap_or_else(|| STANDALONE_INSTANCE_ID.to_owned());
+    let (message_receiver, kits_rpc, kits_notifier) = astroplant_mqtt::run(
+        connection_options(shared_subscription.as_ref()),
+        shared_subscription,
+        overflow_policies(),
     );
 
+    {
+        let ingester = ingest::Ingester::new(pg_pool.clone(), raw_measurement_sender);
+        std::thread::spawn(move || ingester.run(ingest_receiver, configuration_change_receiver));
+    }
+
+    let handler_kits_rpc = kits_rpc.clone();
     std::thread::spawn(move || {
         let (thread_pool_handle_sender, thread_pool_handle_receiver) = oneshot::channel::<()>();
         let mut runtime = Runtime::new().unwrap();
@@ -176,11 +517,144 @@ pub fn run(
 
         std::thread::spawn(move || runtime.block_on(thread_pool_handle_receiver));
 
-        let mut handler = Handler::new(pg_pool, runtime_handle, raw_measurement_sender);
+        // The presence of kits is not known after a restart: kits are marked online again as soon
+        // as they are seen. Instances sharing the handling of kit messages leave presence to the
+        // kits' statuses and to the instances that see them, as other instances may be running.
+        // Responses to kit RPC requests this instance sent before the restart can no longer be
+        // received: such queued requests are delivered again.
+        match pg_pool.get() {
+            Ok(conn) => {
+                if !shared {
+                    if let Err(err) = models::UpdateKitPresence::all_offline(&conn) {
+                        error!("could not reset kit presence: {:?}", err);
+                    }
+                }
+                if let Err(err) = rpc_queue::release_delivering(&conn, &instance_id) {
+                    error!("could not reset queued kit RPC requests: {:?}", err);
+                }
+            }
+            Err(err) => error!("could not reset kit state: {:?}", err),
+        }
+
+        let mut handler = Handler::new(
+            pg_pool,
+            runtime_handle,
+            ingest_sender,
+            presence_sender,
+            handler_kits_rpc,
+            instance_id,
+        );
         handler.run(message_receiver);
 
         thread_pool_handle_sender.send(()).unwrap();
     });
 
-    (raw_measurement_receiver, kits_rpc)
+    (
+        raw_measurement_receiver,
+        presence_receiver,
+        kits_rpc,
+        KitsNotifier::new(kits_notifier, configuration_change_sender),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use astroplant_mqtt::astroplant_capnp;
+    use astroplant_mqtt::transport::{Loopback, Notification, Transport};
+    use capnp::serialize_packed;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use std::sync::Arc;
+
+    fn raw_measurement_payload() -> Vec<u8> {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut measurement_builder =
+            message_builder.init_root::<astroplant_capnp::raw_measurement::Builder>();
+        measurement_builder.set_datetime(1_588_680_000_000);
+        measurement_builder.set_peripheral(1);
+        measurement_builder.set_quantity_type(2);
+        measurement_builder.set_value(21.5);
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        bytes
+    }
+
+    fn version_request_payload() -> Vec<u8> {
+        let mut message_builder = capnp::message::Builder::new_default();
+        let mut request_builder =
+            message_builder.init_root::<astroplant_capnp::server_rpc_request::Builder>();
+        request_builder.set_id(7);
+        request_builder.set_version(());
+
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, &message_builder).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn server_rpc_is_answered_while_ingest_stalls() {
+        let loopback = Loopback::new();
+        let (transport, notifications) = loopback.connect();
+        let (message_receiver, kits_rpc, _kits_notifier) = astroplant_mqtt::run_with_transport(
+            Arc::new(transport),
+            notifications,
+            None,
+            OverflowPolicies::default(),
+        );
+
+        // The database is unreachable, and the ingester never drains its queue.
+        let pg_pool = Pool::builder()
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://127.0.0.1:1/astroplant"));
+        let (ingest_sender, ingest_receiver) = crossbeam::channel::bounded(1);
+        let (presence_sender, _presence_receiver) = mpsc::channel(128);
+        let mut runtime = Runtime::new().unwrap();
+        let mut handler = Handler::new(
+            pg_pool,
+            runtime.handle().clone(),
+            ingest_sender,
+            presence_sender,
+            kits_rpc,
+            STANDALONE_INSTANCE_ID.to_owned(),
+        );
+        std::thread::spawn(move || handler.run(message_receiver));
+        std::thread::spawn(move || runtime.block_on(futures::future::pending::<()>()));
+
+        let (kit, kit_notifications) = loopback.connect();
+        kit.subscribe("kit/k_test/server-rpc/response").unwrap();
+        for _ in 0..4 {
+            kit.publish(
+                "kit/k_test/measurement/raw".to_owned(),
+                false,
+                raw_measurement_payload(),
+            )
+            .unwrap();
+        }
+
+        // Let the handler take up the measurements the ingest queue has no room for.
+        while ingest_receiver.is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+
+        kit.publish(
+            "kit/k_test/server-rpc/request".to_owned(),
+            false,
+            version_request_payload(),
+        )
+        .unwrap();
+        loop {
+            match kit_notifications.recv_timeout(Duration::from_secs(5)) {
+                Ok(Notification::Publish(publish))
+                    if publish.topic == "kit/k_test/server-rpc/response" =>
+                {
+                    break
+                }
+                Ok(_) => {}
+                Err(err) => panic!("the server RPC request was not answered: {:?}", err),
+            }
+        }
+        assert_eq!(ingest_receiver.len(), 1);
+    }
 }
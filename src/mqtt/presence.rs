@@ -0,0 +1,262 @@
+//! Tracks whether kits are online.
+//!
+//! A kit is online after it published a message or an `online` status, and goes offline when it
+//! publishes an `offline` status (usually its last will), or when it has not been seen for
+//! `OFFLINE_AFTER_SECONDS`. Changes are persisted immediately; last-seen times of kits that stay
+//! online are persisted at most once per `PERSIST_INTERVAL_SECONDS`. When multiple server instances
+//! track the same kit, a persisted presence never overrides one that saw the kit later.
+//!
+//! The skew of kits' clocks is tracked as well, from the datetimes of their raw measurements.
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+/// The number of seconds after which a kit that has not been seen is considered offline.
+pub const OFFLINE_AFTER_SECONDS: i64 = 300;
+
+/// The minimum number of seconds between persisting a kit's presence when its status has not
+/// changed.
+pub const PERSIST_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presence {
+    pub kit_serial: String,
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_measurement_at: Option<DateTime<Utc>>,
+    /// The kit's clock minus the server's clock, in milliseconds.
+    pub clock_skew_millis: Option<i64>,
+}
+
+/// A presence to persist.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Update {
+    pub presence: Presence,
+    /// Whether the kit went online or offline.
+    pub changed: bool,
+}
+
+#[derive(Default)]
+struct KitState {
+    online: bool,
+    last_seen: Option<DateTime<Utc>>,
+    last_measurement_at: Option<DateTime<Utc>>,
+    clock_skew_millis: Option<i64>,
+    last_persisted: Option<DateTime<Utc>>,
+    dirty: bool,
+}
+
+#[derive(Default)]
+pub struct PresenceTracker {
+    kits: HashMap<String, KitState>,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The kit published a message.
+    pub fn seen(&mut self, kit_serial: &str, now: DateTime<Utc>) -> Option<Update> {
+        let state = self.kits.entry(kit_serial.to_owned()).or_default();
+        state.last_seen = Some(now);
+        Self::set_online(kit_serial, state, true, now)
+    }
+
+    /// The kit published a measurement. `measured_at` is the datetime of the measurement
+    /// according to the kit's clock, if it was measured right before it was published.
+    pub fn measured(
+        &mut self,
+        kit_serial: &str,
+        now: DateTime<Utc>,
+        measured_at: Option<DateTime<Utc>>,
+    ) -> Option<Update> {
+        let state = self.kits.entry(kit_serial.to_owned()).or_default();
+        state.last_seen = Some(now);
+        state.last_measurement_at = Some(now);
+        if let Some(measured_at) = measured_at {
+            // This includes the time the measurement took to arrive, which is assumed to be
+            // negligible compared to the skew of kits without time synchronization.
+            state.clock_skew_millis = Some((measured_at - now).num_milliseconds());
+        }
+        Self::set_online(kit_serial, state, true, now)
+    }
+
+    /// The kit (or the broker, on behalf of the kit) published its status.
+    pub fn status(&mut self, kit_serial: &str, online: bool, now: DateTime<Utc>) -> Option<Update> {
+        let state = self.kits.entry(kit_serial.to_owned()).or_default();
+        if online {
+            state.last_seen = Some(now);
+        }
+        Self::set_online(kit_serial, state, online, now)
+    }
+
+    /// Mark kits that have not been seen for `OFFLINE_AFTER_SECONDS` as offline, and get the
+    /// presences of kits that have not been persisted for `PERSIST_INTERVAL_SECONDS`.
+    pub fn sweep(&mut self, now: DateTime<Utc>) -> Vec<Update> {
+        let mut updates = vec![];
+
+        for (kit_serial, state) in self.kits.iter_mut() {
+            let expired = match state.last_seen {
+                Some(last_seen) => now - last_seen >= Duration::seconds(OFFLINE_AFTER_SECONDS),
+                None => true,
+            };
+
+            if state.online && expired {
+                state.online = false;
+                updates.push(Self::persist(kit_serial, state, true, now));
+            } else if state.dirty && Self::persist_due(state, now) {
+                updates.push(Self::persist(kit_serial, state, false, now));
+            }
+        }
+
+        // Offline kits are forgotten once their presence is persisted.
+        self.kits.retain(|_, state| state.online || state.dirty);
+
+        updates
+    }
+
+    fn set_online(
+        kit_serial: &str,
+        state: &mut KitState,
+        online: bool,
+        now: DateTime<Utc>,
+    ) -> Option<Update> {
+        state.dirty = true;
+        if state.online != online {
+            state.online = online;
+            Some(Self::persist(kit_serial, state, true, now))
+        } else if Self::persist_due(state, now) {
+            Some(Self::persist(kit_serial, state, false, now))
+        } else {
+            None
+        }
+    }
+
+    fn persist_due(state: &KitState, now: DateTime<Utc>) -> bool {
+        match state.last_persisted {
+            Some(last_persisted) => {
+                now - last_persisted >= Duration::seconds(PERSIST_INTERVAL_SECONDS)
+            }
+            None => true,
+        }
+    }
+
+    fn persist(
+        kit_serial: &str,
+        state: &mut KitState,
+        changed: bool,
+        now: DateTime<Utc>,
+    ) -> Update {
+        state.last_persisted = Some(now);
+        state.dirty = false;
+
+        Update {
+            presence: Presence {
+                kit_serial: kit_serial.to_owned(),
+                online: state.online,
+                last_seen: state.last_seen,
+                last_measurement_at: state.last_measurement_at,
+                clock_skew_millis: state.clock_skew_millis,
+            },
+            changed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_588_000_000 + seconds, 0)
+    }
+
+    #[test]
+    fn goes_online_when_seen() {
+        let mut tracker = PresenceTracker::new();
+
+        let update = tracker.seen("k", at(0)).unwrap();
+        assert!(update.changed);
+        assert!(update.presence.online);
+        assert_eq!(update.presence.last_seen, Some(at(0)));
+        assert_eq!(update.presence.last_measurement_at, None);
+
+        // Not persisted again within the persist interval.
+        assert_eq!(tracker.seen("k", at(10)), None);
+        assert_eq!(tracker.measured("k", at(20), None), None);
+
+        let update = tracker.seen("k", at(60)).unwrap();
+        assert!(!update.changed);
+        assert_eq!(update.presence.last_seen, Some(at(60)));
+        assert_eq!(update.presence.last_measurement_at, Some(at(20)));
+    }
+
+    #[test]
+    fn clock_skew() {
+        let mut tracker = PresenceTracker::new();
+
+        let update = tracker.measured("k", at(0), None).unwrap();
+        assert_eq!(update.presence.clock_skew_millis, None);
+
+        tracker.measured("k", at(10), Some(at(130)));
+        let update = tracker.measured("k", at(60), Some(at(175))).unwrap();
+        assert_eq!(update.presence.clock_skew_millis, Some(115_000));
+
+        // Aggregate measurements do not affect the skew.
+        tracker.measured("k", at(70), None);
+        let update = tracker.sweep(at(120)).pop().unwrap();
+        assert_eq!(update.presence.clock_skew_millis, Some(115_000));
+    }
+
+    #[test]
+    fn status() {
+        let mut tracker = PresenceTracker::new();
+
+        let update = tracker.status("k", true, at(0)).unwrap();
+        assert!(update.changed);
+        assert!(update.presence.online);
+
+        let update = tracker.status("k", false, at(5)).unwrap();
+        assert!(update.changed);
+        assert!(!update.presence.online);
+        assert_eq!(update.presence.last_seen, Some(at(0)));
+
+        assert!(tracker.seen("k", at(10)).unwrap().changed);
+    }
+
+    #[test]
+    fn sweep() {
+        let mut tracker = PresenceTracker::new();
+
+        tracker.seen("a", at(0));
+        tracker.seen("b", at(0));
+        tracker.measured("b", at(30), None);
+        assert_eq!(tracker.sweep(at(45)), vec![]);
+
+        // The measurement of "b" is persisted once the persist interval has passed.
+        let updates = tracker.sweep(at(60));
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].presence.kit_serial, "b");
+        assert!(!updates[0].changed);
+        assert_eq!(updates[0].presence.last_measurement_at, Some(at(30)));
+
+        // "a" expires.
+        let updates = tracker.sweep(at(300));
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].presence.kit_serial, "a");
+        assert!(updates[0].changed);
+        assert!(!updates[0].presence.online);
+        assert_eq!(updates[0].presence.last_seen, Some(at(0)));
+
+        // "b" expires.
+        let updates = tracker.sweep(at(330));
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].presence.kit_serial, "b");
+        assert!(updates[0].changed);
+
+        assert_eq!(tracker.sweep(at(1000)), vec![]);
+        assert!(tracker.kits.is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+//! Delivers queued kit RPC requests when kits show activity.
+//!
+//! The requests of a kit are delivered one at a time, oldest first. When the kit does not respond
+//! to a request, delivery stops and the request is retried on the kit's next activity, until it
+//! has been attempted `MAX_ATTEMPTS` times.
+//!
+//! Requests are claimed for delivery by server instance. An instance starting up releases the
+//! requests it was delivering, without affecting the deliveries of other instances. Requests
+//! abandoned by an instance that stopped are released periodically.
+
+use super::{helpers, models, Error, PgPool};
+
+use astroplant_mqtt::{KitRpcResponseError, KitsRpc};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The number of times a request is sent to a kit before it is marked as failed.
+const MAX_ATTEMPTS: i32 = 3;
+
+/// The time kits are given to respond to queued requests.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The time after which a request still being delivered is considered abandoned, e.g. because the
+/// instance delivering it stopped and started with another id.
+const ABANDONED_AFTER: Duration = Duration::from_secs(2 * DELIVERY_TIMEOUT.as_secs());
+
+/// Why a queued request failed. Stored with the request.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum DeliveryError {
+    KitRpcResponseError(KitRpcResponseError),
+    /// The peripheral is no longer part of the kit's active configuration.
+    PeripheralNotActive,
+    UnknownMethod,
+}
+
+/// A queued request, ready to be sent to the kit.
+enum Prepared {
+    Version,
+    Uptime,
+    PeripheralCommand {
+        peripheral: String,
+        command: serde_json::Value,
+    },
+}
+
+impl Prepared {
+    fn prepare(
+        conn: &diesel::pg::PgConnection,
+        kit: &models::Kit,
+        request: &models::QueuedKitRpcRequest,
+    ) -> diesel::QueryResult<Result<Self, DeliveryError>> {
+        use models::QueuedKitRpcMethod;
+
+        let method = match QueuedKitRpcMethod::parse(&request.method) {
+            Some(method) => method,
+            None => return Ok(Err(DeliveryError::UnknownMethod)),
+        };
+
+        Ok(Ok(match method {
+            QueuedKitRpcMethod::Version => Prepared::Version,
+            QueuedKitRpcMethod::Uptime => Prepared::Uptime,
+            QueuedKitRpcMethod::PeripheralCommand => {
+                let peripheral = match request.peripheral_id {
+                    Some(peripheral_id) => {
+                        models::Peripheral::by_id(conn, models::PeripheralId(peripheral_id))?
+                    }
+                    None => None,
+                };
+                let active_configuration =
+                    models::KitConfiguration::active_configuration_of_kit(conn, kit)?;
+                match (peripheral, active_configuration) {
+                    (Some(peripheral), Some(configuration))
+                        if peripheral.kit_configuration_id == configuration.id =>
+                    {
+                        Prepared::PeripheralCommand {
+                            peripheral: peripheral.name,
+                            command: request.command.clone().unwrap_or(serde_json::Value::Null),
+                        }
+                    }
+                    _ => return Ok(Err(DeliveryError::PeripheralNotActive)),
+                }
+            }
+        }))
+    }
+}
+
+/// Return the requests the instance with id `instance_id` was delivering, and abandoned requests,
+/// to the queue. The responses to these requests can no longer be received.
+/// Returns the amount of released requests.
+pub fn release_delivering(
+    conn: &diesel::pg::PgConnection,
+    instance_id: &str,
+) -> diesel::QueryResult<usize> {
+    models::QueuedKitRpcRequest::release_delivering(conn, instance_id, abandoned_before())
+}
+
+/// Requests delivered before this time are considered abandoned.
+fn abandoned_before() -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now() - chrono::Duration::seconds(ABANDONED_AFTER.as_secs() as i64)
+}
+
+#[derive(Clone)]
+pub struct Deliverer {
+    pg_pool: PgPool,
+    kits_rpc: KitsRpc,
+    /// The id of this server instance, recorded with the requests it claims.
+    instance_id: Arc<str>,
+    /// The serials of the kits requests are currently being delivered to.
+    delivering: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Deliverer {
+    pub fn new(pg_pool: PgPool, kits_rpc: KitsRpc, instance_id: String) -> Self {
+        Self {
+            pg_pool,
+            kits_rpc,
+            instance_id: instance_id.into(),
+            delivering: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Return requests abandoned by any instance to the queue. They are delivered on their kits'
+    /// next activity.
+    pub async fn release_abandoned(self) {
+        let pg_pool = self.pg_pool.clone();
+        let released = helpers::threadpool(move || {
+            let conn = pg_pool.get().map_err(|_| Error::PgPool)?;
+            models::QueuedKitRpcRequest::release_abandoned(&conn, abandoned_before())
+                .map_err(|_| Error::Internal)
+        })
+        .await;
+
+        match released {
+            Ok(0) => {}
+            Ok(released) => info!("released {} abandoned queued kit RPC requests", released),
+            Err(err) => warn!(
+                "error releasing abandoned queued kit RPC requests: {:?}",
+                err
+            ),
+        }
+    }
+
+    /// Deliver the kit's pending requests, unless they are already being delivered.
+    pub async fn deliver(self, kit_serial: String) {
+        if !self.delivering.lock().unwrap().insert(kit_serial.clone()) {
+            return;
+        }
+
+        if let Err(err) = self.deliver_pending(&kit_serial).await {
+            warn!(
+                "error delivering queued kit RPC requests to {}: {:?}",
+                kit_serial, err
+            );
+        }
+
+        self.delivering.lock().unwrap().remove(&kit_serial);
+    }
+
+    async fn deliver_pending(&self, kit_serial: &str) -> Result<(), Error> {
+        let pg_pool = self.pg_pool.clone();
+        let serial = kit_serial.to_owned();
+        let pending = helpers::threadpool(move || {
+            let conn = pg_pool.get().map_err(|_| Error::PgPool)?;
+            let kit = match models::Kit::by_serial(&conn, serial).map_err(|_| Error::Internal)? {
+                Some(kit) => kit,
+                None => return Ok(None),
+            };
+            let pending = models::QueuedKitRpcRequest::pending_of_kit_id(&conn, kit.get_id())
+                .map_err(|_| Error::Internal)?;
+            Ok(Some((kit, pending)))
+        })
+        .await?;
+
+        let (kit, pending) = match pending {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        for request in pending {
+            let pg_pool = self.pg_pool.clone();
+            let kit_ = kit.clone();
+            let instance_id = self.instance_id.clone();
+            let claimed = helpers::threadpool(move || {
+                let conn = pg_pool.get().map_err(|_| Error::PgPool)?;
+                let request = match models::QueuedKitRpcRequest::claim(
+                    &conn,
+                    request.get_id(),
+                    &instance_id,
+                    chrono::Utc::now(),
+                )
+                .map_err(|_| Error::Internal)?
+                {
+                    Some(request) => request,
+                    None => return Ok(None),
+                };
+                let prepared =
+                    Prepared::prepare(&conn, &kit_, &request).map_err(|_| Error::Internal)?;
+                Ok(Some((request, prepared)))
+            })
+            .await?;
+
+            let (request, prepared) = match claimed {
+                Some(claimed) => claimed,
+                None => continue,
+            };
+
+            trace!(
+                "delivering queued kit RPC request {} to {}",
+                request.id,
+                kit_serial
+            );
+            let outcome = match prepared {
+                Ok(prepared) => self.send(kit_serial, prepared).await,
+                Err(err) => Err(err),
+            };
+
+            let unresponsive = match outcome {
+                Err(DeliveryError::KitRpcResponseError(KitRpcResponseError::TimedOut))
+                | Err(DeliveryError::KitRpcResponseError(KitRpcResponseError::Canceled)) => true,
+                _ => false,
+            };
+
+            let pg_pool = self.pg_pool.clone();
+            helpers::threadpool(move || {
+                let conn = pg_pool.get().map_err(|_| Error::PgPool)?;
+                if unresponsive && request.attempts < MAX_ATTEMPTS {
+                    models::QueuedKitRpcRequest::release(&conn, request.get_id())
+                } else {
+                    models::QueuedKitRpcRequest::complete(
+                        &conn,
+                        request.get_id(),
+                        outcome.map_err(|err| serde_json::to_value(err).unwrap()),
+                        chrono::Utc::now(),
+                    )
+                }
+                .map_err(|_| Error::Internal)
+            })
+            .await?;
+
+            if unresponsive {
+                // Try again when the kit next shows activity.
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        kit_serial: &str,
+        prepared: Prepared,
+    ) -> Result<serde_json::Value, DeliveryError> {
+        let rpc = self.kits_rpc.kit_rpc(kit_serial.to_owned());
+        let response = match prepared {
+            Prepared::Version => rpc
+                .version(DELIVERY_TIMEOUT)
+                .await
+                .map(serde_json::Value::from),
+            Prepared::Uptime => rpc
+                .uptime(DELIVERY_TIMEOUT)
+                .await
+                .map(|uptime| serde_json::Value::from(uptime.as_secs())),
+            Prepared::PeripheralCommand {
+                peripheral,
+                command,
+            } => rpc
+                .peripheral_command(peripheral, command, DELIVERY_TIMEOUT)
+                .await
+                .map(|()| serde_json::Value::Null),
+        };
+
+        response.map_err(DeliveryError::KitRpcResponseError)
+    }
+}
@@ -3,6 +3,7 @@
 //!
 //! TODO: ensure each status code has exactly one problem variant
 
+use astroplant_mqtt::{KitRpcResponseError, RpcError};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error as StdError;
@@ -62,7 +63,30 @@ impl Problem {
             PayloadTooLarge { .. } => warp::http::StatusCode::PAYLOAD_TOO_LARGE,
             InvalidJson { .. } => warp::http::StatusCode::BAD_REQUEST,
             InvalidParameters { .. } => warp::http::StatusCode::BAD_REQUEST,
-            KitRpc(_) => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            KitRpc(KitRpcProblem {
+                kit_rpc_response_error: KitRpcResponseError::TimedOut,
+                ..
+            }) => warp::http::StatusCode::GATEWAY_TIMEOUT,
+            KitRpc(KitRpcProblem {
+                kit_rpc_response_error: KitRpcResponseError::RpcError(RpcError::RateLimit { .. }),
+                ..
+            }) => warp::http::StatusCode::TOO_MANY_REQUESTS,
+            KitRpc(_) => warp::http::StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The time in milliseconds the client should wait before retrying a request rate limited by
+    /// the kit, if known.
+    pub fn retry_after_millis(&self) -> Option<u64> {
+        use Problem::*;
+
+        match self {
+            KitRpc(KitRpcProblem {
+                kit_rpc_response_error:
+                    KitRpcResponseError::RpcError(RpcError::RateLimit { wait_time_millis }),
+                ..
+            }) => Some(*wait_time_millis),
+            _ => None,
         }
     }
 }
@@ -174,6 +198,35 @@ impl<'a> From<&'a Problem> for DescriptiveProblem<'a> {
                 )
             }
 
+            KitRpc(KitRpcProblem {
+                kit_rpc_response_error: KitRpcResponseError::TimedOut,
+                timeout_millis,
+                ..
+            }) => {
+                (
+                    Some("The kit did not respond in time".to_owned()),
+                    Some(format!("The kit did not respond within {} ms.", timeout_millis)),
+                )
+            }
+
+            KitRpc(KitRpcProblem {
+                kit_rpc_response_error: KitRpcResponseError::RpcError(rpc_error),
+                ..
+            }) => {
+                let detail = match rpc_error {
+                    RpcError::Other => "The kit could not handle the request.".to_owned(),
+                    RpcError::MethodNotFound => "The kit does not support the request.".to_owned(),
+                    RpcError::RateLimit { wait_time_millis } => format!(
+                        "The kit rate-limited the request. Retry after {} ms.",
+                        wait_time_millis
+                    ),
+                };
+                (
+                    Some("The kit responded with an error".to_owned()),
+                    Some(detail),
+                )
+            }
+
             KitRpc(_) => {
                 (
                     Some("There was an issue with the kit RPC response".to_owned()),
@@ -374,14 +427,86 @@ impl From<&serde_json::Error> for JsonDeserializeErrorCategory {
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub enum KitRpcProblem {
-    KitRpcResponseError(String),
+pub struct KitRpcProblem {
+    pub kit_rpc_response_error: KitRpcResponseError,
+    /// The time in milliseconds spent waiting for the kit's response.
+    pub elapsed_millis: u64,
+    /// The time in milliseconds the kit was given to respond.
+    pub timeout_millis: u64,
 }
 
 impl KitRpcProblem {
     pub fn kit_rpc_response_error_into_problem(
-        error: astroplant_mqtt::KitRpcResponseError,
+        error: KitRpcResponseError,
+        elapsed: std::time::Duration,
+        timeout: std::time::Duration,
     ) -> Problem {
-        Problem::KitRpc(KitRpcProblem::KitRpcResponseError(format!("{:?}", error)))
+        Problem::KitRpc(KitRpcProblem {
+            kit_rpc_response_error: error,
+            elapsed_millis: elapsed.as_millis() as u64,
+            timeout_millis: timeout.as_millis() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn kit_rpc_rate_limit() {
+        let problem = KitRpcProblem::kit_rpc_response_error_into_problem(
+            KitRpcResponseError::RpcError(RpcError::RateLimit {
+                wait_time_millis: 1500,
+            }),
+            Duration::from_millis(20),
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(
+            problem.to_status_code(),
+            warp::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(problem.retry_after_millis(), Some(1500));
+        assert_eq!(
+            serde_json::to_value(&problem).unwrap(),
+            serde_json::json!({
+                "type": "/probs/kit-rpc-problem",
+                "kitRpcResponseError": {
+                    "rpcError": { "rateLimit": { "waitTimeMillis": 1500 } }
+                },
+                "elapsedMillis": 20,
+                "timeoutMillis": 5000,
+            })
+        );
+    }
+
+    #[test]
+    fn kit_rpc_timed_out() {
+        let problem = KitRpcProblem::kit_rpc_response_error_into_problem(
+            KitRpcResponseError::TimedOut,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(
+            problem.to_status_code(),
+            warp::http::StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(problem.retry_after_millis(), None);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let problem = Problem::RateLimit(RateLimitError {
+            wait_time_millis: 1500,
+        });
+
+        assert_eq!(
+            problem.to_status_code(),
+            warp::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(problem.retry_after_millis(), None);
     }
 }
@@ -2,8 +2,15 @@ use erased_serde::Serialize as ErasedSerialize;
 use std::collections::HashMap;
 use warp::http::StatusCode;
 
+/// A response body that is streamed to the client rather than serialized in one go.
+pub struct StreamedBody {
+    pub content_type: String,
+    pub body: hyper::Body,
+}
+
 pub struct Response {
     value: Option<Box<dyn ErasedSerialize + Send>>,
+    stream: Option<StreamedBody>,
     status_code: StatusCode,
     headers: HashMap<String, String>,
 }
@@ -14,6 +21,11 @@ impl Response {
         &self.value
     }
 
+    /// Take the streamed response body, if any. A response with a streamed body has no value.
+    pub fn take_stream(&mut self) -> Option<StreamedBody> {
+        self.stream.take()
+    }
+
     /// The response status code.
     pub fn status_code(&self) -> StatusCode {
         self.status_code
@@ -77,6 +89,7 @@ impl ResponseBuilder {
     pub fn empty(self) -> Response {
         Response {
             value: None,
+            stream: None,
             status_code: self.status_code,
             headers: self.headers,
         }
@@ -90,6 +103,17 @@ impl ResponseBuilder {
     {
         Response {
             value: Some(Box::new(value) as Box<dyn ErasedSerialize + Send>),
+            stream: None,
+            status_code: self.status_code,
+            headers: self.headers,
+        }
+    }
+
+    /// Build the response with a body that is streamed to the client.
+    pub fn stream(self, content_type: String, body: hyper::Body) -> Response {
+        Response {
+            value: None,
+            stream: Some(StreamedBody { content_type, body }),
             status_code: self.status_code,
             headers: self.headers,
         }
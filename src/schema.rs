@@ -60,6 +60,62 @@ table! {
     }
 }
 
+table! {
+    /// Representation of the `dead_letters` table.
+    ///
+    /// (Automatically generated by Diesel.)
+    dead_letters (id) {
+        /// The `id` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Int8`.
+        ///
+        /// (Automatically generated by Diesel.)
+        id -> Int8,
+        /// The `kit_serial` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Nullable<Varchar>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        kit_serial -> Nullable<Varchar>,
+        /// The `kit_id` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Nullable<Int4>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        kit_id -> Nullable<Int4>,
+        /// The `topic` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Varchar`.
+        ///
+        /// (Automatically generated by Diesel.)
+        topic -> Varchar,
+        /// The `payload` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Bytea`.
+        ///
+        /// (Automatically generated by Diesel.)
+        payload -> Bytea,
+        /// The `error_kind` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Varchar`.
+        ///
+        /// (Automatically generated by Diesel.)
+        error_kind -> Varchar,
+        /// The `error_message` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Nullable<Varchar>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        error_message -> Nullable<Varchar>,
+        /// The `received_at` column of the `dead_letters` table.
+        ///
+        /// Its SQL type is `Timestamptz`.
+        ///
+        /// (Automatically generated by Diesel.)
+        received_at -> Timestamptz,
+    }
+}
+
 table! {
     /// Representation of the `kit_configurations` table.
     ///
@@ -219,6 +275,30 @@ table! {
         ///
         /// (Automatically generated by Diesel.)
         privacy_show_on_map -> Bool,
+        /// The `online` column of the `kits` table.
+        ///
+        /// Its SQL type is `Bool`.
+        ///
+        /// (Automatically generated by Diesel.)
+        online -> Bool,
+        /// The `last_seen` column of the `kits` table.
+        ///
+        /// Its SQL type is `Nullable<Timestamptz>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        last_seen -> Nullable<Timestamptz>,
+        /// The `last_measurement_at` column of the `kits` table.
+        ///
+        /// Its SQL type is `Nullable<Timestamptz>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        last_measurement_at -> Nullable<Timestamptz>,
+        /// The `clock_skew_millis` column of the `kits` table.
+        ///
+        /// Its SQL type is `Nullable<Int8>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        clock_skew_millis -> Nullable<Int8>,
     }
 }
 
@@ -386,6 +466,178 @@ table! {
     }
 }
 
+table! {
+    /// Representation of the `quarantined_measurements` table.
+    ///
+    /// (Automatically generated by Diesel.)
+    quarantined_measurements (id) {
+        /// The `id` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Uuid`.
+        ///
+        /// (Automatically generated by Diesel.)
+        id -> Uuid,
+        /// The `kit_serial` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Varchar`.
+        ///
+        /// (Automatically generated by Diesel.)
+        kit_serial -> Varchar,
+        /// The `kit_id` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Nullable<Int4>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        kit_id -> Nullable<Int4>,
+        /// The `measurement_type` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Varchar`.
+        ///
+        /// (Automatically generated by Diesel.)
+        measurement_type -> Varchar,
+        /// The `peripheral_id` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Int4`.
+        ///
+        /// (Automatically generated by Diesel.)
+        peripheral_id -> Int4,
+        /// The `quantity_type_id` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Int4`.
+        ///
+        /// (Automatically generated by Diesel.)
+        quantity_type_id -> Int4,
+        /// The `aggregate_type` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Nullable<Varchar>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        aggregate_type -> Nullable<Varchar>,
+        /// The `value` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Float8`.
+        ///
+        /// (Automatically generated by Diesel.)
+        value -> Float8,
+        /// The `datetime` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Nullable<Timestamptz>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        datetime -> Nullable<Timestamptz>,
+        /// The `datetime_end` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Nullable<Timestamptz>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        datetime_end -> Nullable<Timestamptz>,
+        /// The `reason` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Varchar`.
+        ///
+        /// (Automatically generated by Diesel.)
+        reason -> Varchar,
+        /// The `received_at` column of the `quarantined_measurements` table.
+        ///
+        /// Its SQL type is `Timestamptz`.
+        ///
+        /// (Automatically generated by Diesel.)
+        received_at -> Timestamptz,
+    }
+}
+
+table! {
+    /// Representation of the `queued_kit_rpc_requests` table.
+    ///
+    /// (Automatically generated by Diesel.)
+    queued_kit_rpc_requests (id) {
+        /// The `id` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Int8`.
+        ///
+        /// (Automatically generated by Diesel.)
+        id -> Int8,
+        /// The `kit_id` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Int4`.
+        ///
+        /// (Automatically generated by Diesel.)
+        kit_id -> Int4,
+        /// The `user_id` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Int4>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        user_id -> Nullable<Int4>,
+        /// The `method` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Varchar`.
+        ///
+        /// (Automatically generated by Diesel.)
+        method -> Varchar,
+        /// The `peripheral_id` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Int4>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        peripheral_id -> Nullable<Int4>,
+        /// The `command` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Json>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        command -> Nullable<Json>,
+        /// The `status` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Varchar`.
+        ///
+        /// (Automatically generated by Diesel.)
+        status -> Varchar,
+        /// The `attempts` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Int4`.
+        ///
+        /// (Automatically generated by Diesel.)
+        attempts -> Int4,
+        /// The `response` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Json>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        response -> Nullable<Json>,
+        /// The `error` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Json>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        error -> Nullable<Json>,
+        /// The `created_at` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Timestamptz`.
+        ///
+        /// (Automatically generated by Diesel.)
+        created_at -> Timestamptz,
+        /// The `delivered_at` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Timestamptz>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        delivered_at -> Nullable<Timestamptz>,
+        /// The `completed_at` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Timestamptz>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        completed_at -> Nullable<Timestamptz>,
+        /// The `claimed_by` column of the `queued_kit_rpc_requests` table.
+        ///
+        /// Its SQL type is `Nullable<Varchar>`.
+        ///
+        /// (Automatically generated by Diesel.)
+        claimed_by -> Nullable<Varchar>,
+    }
+}
+
 table! {
     /// Representation of the `raw_measurements` table.
     ///
@@ -490,6 +742,7 @@ joinable!(aggregate_measurements -> kit_configurations (kit_configuration_id));
 joinable!(aggregate_measurements -> kits (kit_id));
 joinable!(aggregate_measurements -> peripherals (peripheral_id));
 joinable!(aggregate_measurements -> quantity_types (quantity_type_id));
+joinable!(dead_letters -> kits (kit_id));
 joinable!(kit_configurations -> kits (kit_id));
 joinable!(kit_memberships -> kits (kit_id));
 joinable!(kit_memberships -> users (user_id));
@@ -498,6 +751,10 @@ joinable!(peripheral_definition_expected_quantity_types -> quantity_types (quant
 joinable!(peripherals -> kit_configurations (kit_configuration_id));
 joinable!(peripherals -> kits (kit_id));
 joinable!(peripherals -> peripheral_definitions (peripheral_definition_id));
+joinable!(quarantined_measurements -> kits (kit_id));
+joinable!(queued_kit_rpc_requests -> kits (kit_id));
+joinable!(queued_kit_rpc_requests -> peripherals (peripheral_id));
+joinable!(queued_kit_rpc_requests -> users (user_id));
 joinable!(raw_measurements -> kit_configurations (kit_configuration_id));
 joinable!(raw_measurements -> kits (kit_id));
 joinable!(raw_measurements -> peripherals (peripheral_id));
@@ -505,6 +762,7 @@ joinable!(raw_measurements -> quantity_types (quantity_type_id));
 
 allow_tables_to_appear_in_same_query!(
     aggregate_measurements,
+    dead_letters,
     kit_configurations,
     kit_memberships,
     kits,
@@ -512,6 +770,8 @@ allow_tables_to_appear_in_same_query!(
     peripheral_definitions,
     peripherals,
     quantity_types,
+    quarantined_measurements,
+    queued_kit_rpc_requests,
     raw_measurements,
     users,
 );
@@ -13,6 +13,12 @@ pub struct Kit {
     pub longitude: Option<f64>,
     pub privacy_public_dashboard: bool,
     pub privacy_show_on_map: bool,
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_measurement_at: Option<DateTime<Utc>>,
+    /// The difference in milliseconds between the kit's clock and the server's clock, as observed
+    /// from the kit's latest raw measurement. Positive if the kit's clock is ahead.
+    pub clock_skew_millis: Option<i64>,
 }
 
 impl From<models::Kit> for Kit {
@@ -28,6 +34,10 @@ impl From<models::Kit> for Kit {
             longitude,
             privacy_public_dashboard,
             privacy_show_on_map,
+            online,
+            last_seen,
+            last_measurement_at,
+            clock_skew_millis,
             ..
         } = kit;
         Self {
@@ -39,6 +49,10 @@ impl From<models::Kit> for Kit {
             longitude: longitude.and_then(|l| l.to_f64()),
             privacy_public_dashboard,
             privacy_show_on_map,
+            online,
+            last_seen,
+            last_measurement_at,
+            clock_skew_millis,
         }
     }
 }
@@ -345,7 +359,7 @@ pub struct Peripheral {
 }
 
 impl Peripheral {
-    pub fn with_definition(self, definition: PeripheralDefinition) -> PeripheralWithDefinition {
+    pub fn with_definition<D>(self, definition: D) -> PeripheralWithDefinition<D> {
         PeripheralWithDefinition {
             peripheral: self,
             definition,
@@ -378,9 +392,45 @@ impl From<models::Peripheral> for Peripheral {
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct PeripheralWithDefinition {
+pub struct PeripheralWithDefinition<D> {
     pub peripheral: Peripheral,
-    pub definition: PeripheralDefinition,
+    pub definition: D,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RawMeasurement {
+    pub id: uuid::Uuid,
+    pub peripheral_id: i32,
+    pub kit_id: i32,
+    pub kit_configuration_id: i32,
+    pub quantity_type_id: i32,
+    pub value: f64,
+    pub datetime: DateTime<Utc>,
+}
+
+impl From<models::RawMeasurement> for RawMeasurement {
+    fn from(
+        models::RawMeasurement {
+            id,
+            peripheral_id,
+            kit_id,
+            kit_configuration_id,
+            quantity_type_id,
+            value,
+            datetime,
+        }: models::RawMeasurement,
+    ) -> Self {
+        Self {
+            id,
+            peripheral_id,
+            kit_id,
+            kit_configuration_id,
+            quantity_type_id,
+            value,
+            datetime,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -425,3 +475,106 @@ impl From<models::AggregateMeasurement> for AggregateMeasurement {
         }
     }
 }
+
+/// The bucketed values of one peripheral's quantity type.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MeasurementSeries {
+    pub peripheral_id: i32,
+    pub quantity_type: QuantityType,
+    /// Pairs of bucket start datetimes and reduced values.
+    pub values: Vec<(DateTime<Utc>, f64)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    pub id: i64,
+    pub kit_serial: Option<String>,
+    pub topic: String,
+    /// The base64-encoded message payload.
+    pub payload: String,
+    pub error_kind: String,
+    pub error_message: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl From<models::DeadLetter> for DeadLetter {
+    fn from(
+        models::DeadLetter {
+            id,
+            kit_serial,
+            topic,
+            payload,
+            error_kind,
+            error_message,
+            received_at,
+            ..
+        }: models::DeadLetter,
+    ) -> Self {
+        Self {
+            id,
+            kit_serial,
+            topic,
+            payload: base64::encode(&payload),
+            error_kind,
+            error_message,
+            received_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedKitRpcRequest {
+    pub id: i64,
+    pub kit_id: i32,
+    pub user_id: Option<i32>,
+    pub method: String,
+    pub peripheral_id: Option<i32>,
+    pub command: Option<serde_json::Value>,
+    pub status: String,
+    pub attempts: i32,
+    pub response: Option<serde_json::Value>,
+    pub error: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl From<models::QueuedKitRpcRequest> for QueuedKitRpcRequest {
+    fn from(
+        models::QueuedKitRpcRequest {
+            id,
+            kit_id,
+            user_id,
+            method,
+            peripheral_id,
+            command,
+            status,
+            attempts,
+            response,
+            error,
+            created_at,
+            delivered_at,
+            completed_at,
+            ..
+        }: models::QueuedKitRpcRequest,
+    ) -> Self {
+        Self {
+            id,
+            kit_id,
+            user_id,
+            method,
+            peripheral_id,
+            command,
+            status,
+            attempts,
+            response,
+            error,
+            created_at,
+            delivered_at,
+            completed_at,
+        }
+    }
+}
@@ -1,31 +1,113 @@
 use log::info;
 
-use futures::channel::mpsc;
-use futures::stream::StreamExt;
+use futures::channel::{mpsc, oneshot};
+use futures::future::FutureExt;
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
+
+use crate::authorization::KitAction;
+use crate::mqtt::Presence;
+use crate::{helpers, models, PgPool};
+
+enum Event {
+    RawMeasurement(astroplant_mqtt::RawMeasurement),
+    Presence(Presence),
+}
+
+/// Whether the user authenticated by `access_token` may view the kit, or whether anyone may if
+/// there is no access token.
+async fn may_view_kit(pg_pool: PgPool, access_token: Option<String>, kit_serial: String) -> bool {
+    let user_id = match access_token {
+        Some(access_token) => match crate::TOKEN_SIGNER
+            .get()
+            .unwrap()
+            .decode_access_token(&access_token)
+        {
+            Ok(authentication_state) => Some(models::UserId(authentication_state.user_id)),
+            Err(_) => return false,
+        },
+        None => None,
+    };
+
+    let conn = match helpers::threadpool(move || pg_pool.get()).await {
+        Ok(conn) => conn,
+        Err(_) => return false,
+    };
+    helpers::fut_permission_or_forbidden(conn, user_id, kit_serial, KitAction::View)
+        .await
+        .is_ok()
+}
+
+/// Authorizes subscriptions to kit presence, which users may only watch of kits they may view.
+/// Authorization runs on the runtime of `runtime_handle`.
+pub fn kit_presence_authorizer(
+    pg_pool: PgPool,
+    runtime_handle: tokio::runtime::Handle,
+) -> astroplant_websocket::KitAuthorizer {
+    Arc::new(move |access_token, kit_serial| {
+        let (sender, receiver) = oneshot::channel();
+        runtime_handle.spawn(
+            may_view_kit(pg_pool.clone(), access_token, kit_serial)
+                .map(move |authorized| sender.send(authorized).unwrap_or(())),
+        );
+        receiver
+            .map(|authorized| authorized.unwrap_or(false))
+            .boxed()
+    })
+}
 
 pub async fn run(
     mut publisher: astroplant_websocket::WebSocketPublisher,
-    mut raw_measurement_receiver: mpsc::Receiver<astroplant_mqtt::RawMeasurement>,
+    raw_measurement_receiver: mpsc::Receiver<astroplant_mqtt::RawMeasurement>,
+    presence_receiver: mpsc::Receiver<Presence>,
 ) {
     info!("Starting WebSocket server.");
 
-    while let Some(raw_measurement) = raw_measurement_receiver.next().await {
-        let astroplant_mqtt::RawMeasurement {
-            kit_serial,
-            datetime,
-            peripheral,
-            quantity_type,
-            value,
-            ..
-        } = raw_measurement;
-        let raw_measurement = astroplant_websocket::RawMeasurement {
-            kit_serial,
-            datetime,
-            peripheral,
-            quantity_type,
-            value,
-        };
-
-        publisher.publish_raw_measurement(raw_measurement.kit_serial.clone(), raw_measurement)
+    let mut events = stream::select(
+        raw_measurement_receiver.map(Event::RawMeasurement),
+        presence_receiver.map(Event::Presence),
+    );
+
+    while let Some(event) = events.next().await {
+        match event {
+            Event::RawMeasurement(raw_measurement) => {
+                let astroplant_mqtt::RawMeasurement {
+                    kit_serial,
+                    datetime,
+                    peripheral,
+                    quantity_type,
+                    value,
+                    ..
+                } = raw_measurement;
+                let raw_measurement = astroplant_websocket::RawMeasurement {
+                    kit_serial,
+                    datetime,
+                    peripheral,
+                    quantity_type,
+                    value,
+                };
+
+                publisher
+                    .publish_raw_measurement(raw_measurement.kit_serial.clone(), raw_measurement)
+            }
+            Event::Presence(presence) => {
+                let Presence {
+                    kit_serial,
+                    online,
+                    last_seen,
+                    last_measurement_at,
+                    ..
+                } = presence;
+                let kit_presence = astroplant_websocket::KitPresence {
+                    kit_serial,
+                    online,
+                    last_seen: last_seen.map(|datetime| datetime.timestamp_millis() as u64),
+                    last_measurement_at: last_measurement_at
+                        .map(|datetime| datetime.timestamp_millis() as u64),
+                };
+
+                publisher.publish_kit_presence(kit_presence)
+            }
+        }
     }
 }